@@ -46,6 +46,14 @@ kubectl create secret generic webhook-relay-aws \
     --from-literal=AWS_SECRET_ACCESS_KEY="${AWS_WEBHOOK_SECRET_ACCESS_KEY}" \
     --dry-run=client -o yaml | kubectl apply -f -
 
+# Redis password for redis routes, shared with 05-redis
+if [ -n "${REDIS_PASSWORD:-}" ]; then
+    kubectl create secret generic webhook-relay-redis \
+        --namespace ${NAMESPACE} \
+        --from-literal=REDIS_PASSWORD="${REDIS_PASSWORD}" \
+        --dry-run=client -o yaml | kubectl apply -f -
+fi
+
 # Create registry pull secret
 echo "Creating registry pull secret..."
 kubectl create secret docker-registry harbor-pull-secret \
@@ -63,12 +71,18 @@ kubectl wait --for=condition=Ready certificate/webhook-relay-cert -n ${NAMESPACE
 echo "Creating routes ConfigMap..."
 kubectl apply -f "${SCRIPT_DIR}/configmap.yaml"
 
+# Service account allowed to read EndpointSlices for service routes
+echo "Creating service account..."
+kubectl apply -f "${SCRIPT_DIR}/rbac.yaml"
+
 # Apply substitutions to deployment
 TMP_DEPLOY=$(mktemp)
 trap "rm -f $TMP_DEPLOY" EXIT
 
 sed -e "s|IMAGE_PLACEHOLDER|${IMAGE}|g" \
     -e "s|QUEUE_URL_PLACEHOLDER|${AWS_WEBHOOK_QUEUE_URL}|g" \
+    -e "s|DLQ_URL_PLACEHOLDER|${AWS_WEBHOOK_DLQ_URL:-}|g" \
+    -e "s|REPLY_QUEUE_URL_PLACEHOLDER|${AWS_WEBHOOK_REPLY_QUEUE_URL:-}|g" \
     -e "s|AWS_REGION_PLACEHOLDER|${AWS_WEBHOOK_REGION:-us-east-1}|g" \
     "${SCRIPT_DIR}/deployment.yaml" > "$TMP_DEPLOY"
 
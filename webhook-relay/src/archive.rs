@@ -0,0 +1,607 @@
+use anyhow::{bail, Context, Result};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client;
+use chrono::{DateTime, Duration as ChronoDuration, DurationRound, Utc};
+use indexmap::IndexMap;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::object_store;
+use crate::router::RouteTarget;
+use crate::sqs::WebhookMessage;
+
+/// How often the background task looks for batches to write
+const FLUSH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Columns of Parquet archives, one row per webhook
+const PARQUET_SCHEMA: &str = "
+message webhook {
+    required binary route (STRING);
+    required binary message_id (STRING);
+    required int64 received_at (TIMESTAMP(MILLIS, true));
+    required binary method (STRING);
+    required binary path (STRING);
+    required binary headers (JSON);
+    required binary query (JSON);
+    required binary source_ip (STRING);
+    required binary body;
+}
+";
+
+/// Where and how a route's webhooks are archived, as configured in the
+/// routes YAML
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveConfig {
+    pub bucket: String,
+    /// Object key template; defaults to one suited to the format
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub format: ArchiveFormat,
+    /// Write an hourly batch early once it holds this many webhooks
+    #[serde(default = "default_max_records")]
+    pub max_records: usize,
+    /// Write an hourly batch early once its oldest webhook has waited this
+    /// long, as one of several parts of the hour
+    #[serde(default = "default_flush_seconds")]
+    pub flush_seconds: u64,
+}
+
+fn default_max_records() -> usize {
+    10000
+}
+
+fn default_flush_seconds() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    /// One JSON object per webhook, written before it is delivered
+    #[default]
+    Json,
+    /// Hourly batches of newline-delimited JSON
+    Ndjson,
+    /// Hourly batches as Parquet files
+    Parquet,
+}
+
+impl ArchiveFormat {
+    /// Whether webhooks are buffered into hourly batches once delivered,
+    /// rather than written on their own beforehand
+    pub fn is_batched(self) -> bool {
+        self != ArchiveFormat::Json
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Json => "application/json",
+            ArchiveFormat::Ndjson => "application/x-ndjson",
+            ArchiveFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    fn default_key(self) -> &'static str {
+        match self {
+            ArchiveFormat::Json => "{route}/{date}/{message_id}.json",
+            ArchiveFormat::Ndjson => "{route}/{date}/{hour}/{message_id}.ndjson",
+            ArchiveFormat::Parquet => "{route}/{date}/{hour}/{message_id}.parquet",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub bucket: String,
+    key: String,
+    pub format: ArchiveFormat,
+    max_records: usize,
+    flush_interval: Duration,
+}
+
+impl Archive {
+    pub fn from_config(config: ArchiveConfig) -> Result<Self> {
+        if config.bucket.is_empty() {
+            bail!("bucket must be set");
+        }
+        if config.max_records == 0 {
+            bail!("max_records must be at least 1");
+        }
+        if config.flush_seconds == 0 {
+            bail!("flush_seconds must be at least 1");
+        }
+
+        let key = config
+            .key
+            .unwrap_or_else(|| config.format.default_key().to_string());
+        // Every object needs a key of its own
+        if !key.contains("{message_id}") {
+            bail!("key must contain {{message_id}}");
+        }
+        let mut rest = key.as_str();
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else {
+                bail!("unclosed placeholder in key {}", key);
+            };
+            let name = &rest[start + 1..start + end];
+            if !matches!(name, "route" | "date" | "hour" | "message_id") {
+                bail!("unknown placeholder {{{}}} in key {}", name, key);
+            }
+            rest = &rest[start + end + 1..];
+        }
+
+        Ok(Archive {
+            bucket: config.bucket,
+            key,
+            format: config.format,
+            max_records: config.max_records,
+            flush_interval: Duration::from_secs(config.flush_seconds),
+        })
+    }
+
+    /// The object key for a webhook, or for a batch starting with it
+    fn object_key(&self, route: &str, received: DateTime<Utc>, message_id: &str) -> String {
+        self.key
+            .replace("{route}", route)
+            .replace("{date}", &received.format("%Y-%m-%d").to_string())
+            .replace("{hour}", &received.format("%H").to_string())
+            .replace("{message_id}", message_id)
+    }
+}
+
+/// A webhook as archived: the queued message with its route and SQS message
+/// ID alongside
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ArchiveRecord {
+    route: String,
+    message_id: String,
+    #[serde(flatten)]
+    message: WebhookMessage,
+}
+
+impl ArchiveRecord {
+    /// Webhooks without an SQS message ID get a random one, so their objects
+    /// never overwrite each other
+    fn new(target: &RouteTarget, message: &WebhookMessage, message_id: Option<&str>) -> Self {
+        ArchiveRecord {
+            route: target.name.clone(),
+            message_id: message_id
+                .map(str::to_string)
+                .unwrap_or_else(|| Uuid::new_v4().to_string()),
+            message: message.clone(),
+        }
+    }
+}
+
+struct PendingArchive {
+    archive: Archive,
+    route: String,
+    hour: DateTime<Utc>,
+    /// By message ID, so a redelivered webhook replaces its earlier record
+    records: IndexMap<String, ArchiveRecord>,
+    started: Instant,
+}
+
+impl PendingArchive {
+    fn insert(&mut self, record: ArchiveRecord) {
+        self.records.insert(record.message_id.clone(), record);
+    }
+
+    /// Whether the batch's hour is over or it has waited long enough
+    fn due(&self, now: DateTime<Utc>) -> bool {
+        self.hour + ChronoDuration::hours(1) <= now
+            || self.started.elapsed() >= self.archive.flush_interval
+    }
+}
+
+/// Writes webhooks for archived routes to the object store, either one
+/// object each or batched per route and hour.
+///
+/// Batches are best-effort: webhooks are added once delivered, after their
+/// message has been deleted, and are only held in memory until written. A
+/// restart loses whatever has not been written yet, at most `flush_seconds`
+/// worth.
+pub struct Archiver {
+    client: Client,
+    pending: Mutex<HashMap<(String, DateTime<Utc>), PendingArchive>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Archiver {
+    pub async fn new(config: &Config, metrics: Arc<Metrics>) -> Self {
+        Archiver {
+            client: object_store::client(&config.object_store).await,
+            pending: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    /// Write a webhook to an object of its own before it is delivered. A
+    /// failure is returned so the webhook is retried; the key holds the
+    /// message ID, so a retry overwrites any earlier copy.
+    pub async fn write(
+        &self,
+        target: &RouteTarget,
+        archive: &Archive,
+        message: &WebhookMessage,
+        message_id: Option<&str>,
+    ) -> Result<()> {
+        let record = ArchiveRecord::new(target, message, message_id);
+        let key = archive.object_key(&target.name, received_at(message), &record.message_id);
+        let body = serde_json::to_vec(&record).context("Failed to encode webhook")?;
+        self.put(&target.name, archive, &key, body, 1)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to archive webhook to s3://{}/{}",
+                    archive.bucket, key
+                )
+            })
+    }
+
+    /// Add a delivered webhook to its route's hourly batch
+    pub async fn add(
+        &self,
+        target: &RouteTarget,
+        archive: &Archive,
+        message: &WebhookMessage,
+        message_id: Option<&str>,
+    ) {
+        let received = received_at(message);
+        let hour = received
+            .duration_trunc(ChronoDuration::hours(1))
+            .unwrap_or(received);
+        let full = {
+            let mut pending = self.pending.lock().unwrap();
+            let key = (target.name.clone(), hour);
+            let batch = pending
+                .entry(key.clone())
+                .or_insert_with(|| PendingArchive {
+                    archive: archive.clone(),
+                    route: target.name.clone(),
+                    hour,
+                    records: IndexMap::new(),
+                    started: Instant::now(),
+                });
+            batch.insert(ArchiveRecord::new(target, message, message_id));
+
+            if batch.records.len() >= archive.max_records {
+                pending.remove(&key)
+            } else {
+                None
+            }
+        };
+
+        if let Some(batch) = full {
+            self.flush(batch).await;
+        }
+    }
+
+    /// Periodically write batches whose hour has passed or that have waited
+    /// `flush_seconds`
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(FLUSH_CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let due: Vec<PendingArchive> = {
+                let now = Utc::now();
+                let mut pending = self.pending.lock().unwrap();
+                let expired: Vec<(String, DateTime<Utc>)> = pending
+                    .iter()
+                    .filter(|(_, batch)| batch.due(now))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+
+                expired
+                    .iter()
+                    .filter_map(|key| pending.remove(key))
+                    .collect()
+            };
+
+            for batch in due {
+                self.flush(batch).await;
+            }
+        }
+    }
+
+    /// Write a batch, keeping it buffered to try again if that fails
+    async fn flush(&self, mut batch: PendingArchive) {
+        let archive = &batch.archive;
+        let records: Vec<&ArchiveRecord> = batch.records.values().collect();
+        let key = archive.object_key(&batch.route, batch.hour, &records[0].message_id);
+        let count = records.len();
+
+        let written = match encode_batch(&records, archive.format) {
+            Ok(body) => self.put(&batch.route, archive, &key, body, count).await,
+            Err(e) => Err(e),
+        };
+
+        match written {
+            Ok(()) => {
+                info!(
+                    "Archived {} webhooks to s3://{}/{}",
+                    count, archive.bucket, key
+                );
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to archive {} webhooks to s3://{}/{}: {:#}",
+                    count, archive.bucket, key, e
+                );
+
+                // Webhooks buffered since go after the failed ones
+                let mut pending = self.pending.lock().unwrap();
+                if let Some(newer) = pending.remove(&(batch.route.clone(), batch.hour)) {
+                    for record in newer.records.into_values() {
+                        batch.insert(record);
+                    }
+                }
+                batch.started = Instant::now();
+                pending.insert((batch.route.clone(), batch.hour), batch);
+            }
+        }
+    }
+
+    async fn put(
+        &self,
+        route: &str,
+        archive: &Archive,
+        key: &str,
+        body: Vec<u8>,
+        count: usize,
+    ) -> Result<()> {
+        let result = self
+            .client
+            .put_object()
+            .bucket(&archive.bucket)
+            .key(key)
+            .content_type(archive.format.content_type())
+            .body(ByteStream::from(body))
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => {
+                self.metrics
+                    .webhooks_archived
+                    .with_label_values(&[route])
+                    .inc_by(count as f64);
+                Ok(())
+            }
+            Err(e) => {
+                self.metrics
+                    .archive_failed
+                    .with_label_values(&[route, "write_error"])
+                    .inc();
+                Err(e).context("Failed to write to the object store")
+            }
+        }
+    }
+}
+
+/// When a webhook was received, or now if its timestamp is invalid
+fn received_at(message: &WebhookMessage) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(&message.timestamp)
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or_else(|_| Utc::now())
+}
+
+/// Encode an hourly batch in the archive's format
+fn encode_batch(records: &[&ArchiveRecord], format: ArchiveFormat) -> Result<Vec<u8>> {
+    match format {
+        ArchiveFormat::Json | ArchiveFormat::Ndjson => {
+            let mut body = Vec::new();
+            for record in records {
+                serde_json::to_writer(&mut body, record).context("Failed to encode webhook")?;
+                body.push(b'\n');
+            }
+            Ok(body)
+        }
+        ArchiveFormat::Parquet => encode_parquet(records),
+    }
+}
+
+fn encode_parquet(records: &[&ArchiveRecord]) -> Result<Vec<u8>> {
+    let text = |f: &dyn Fn(&ArchiveRecord) -> Vec<u8>| -> Vec<ByteArray> {
+        records.iter().map(|r| ByteArray::from(f(r))).collect()
+    };
+    let grouped = |values: Vec<(&str, &str)>| -> Vec<u8> {
+        let mut map: IndexMap<&str, Vec<&str>> = IndexMap::new();
+        for (name, value) in values {
+            map.entry(name).or_default().push(value);
+        }
+        serde_json::to_vec(&map).unwrap_or_default()
+    };
+
+    let columns: Vec<Vec<ByteArray>> = vec![
+        text(&|r| r.route.clone().into()),
+        text(&|r| r.message_id.clone().into()),
+        Vec::new(),
+        text(&|r| r.message.method.clone().into()),
+        text(&|r| r.message.path.clone().into()),
+        text(&|r| grouped(r.message.header_values())),
+        text(&|r| grouped(r.message.query_values())),
+        text(&|r| r.message.source_ip.clone().into()),
+        text(&|r| r.message.decoded_body().unwrap_or_default()),
+    ];
+    let received_at: Vec<i64> = records
+        .iter()
+        .map(|r| {
+            DateTime::parse_from_rfc3339(&r.message.timestamp)
+                .map(|t| t.timestamp_millis())
+                .unwrap_or_default()
+        })
+        .collect();
+
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA).context("Invalid Parquet schema")?);
+    let properties = Arc::new(
+        WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+            .build(),
+    );
+
+    let mut body = Vec::new();
+    let mut writer = SerializedFileWriter::new(&mut body, schema, properties)?;
+    let mut row_group = writer.next_row_group()?;
+    let mut index = 0;
+    while let Some(mut column) = row_group.next_column()? {
+        if index == 2 {
+            column
+                .typed::<Int64Type>()
+                .write_batch(&received_at, None, None)?;
+        } else {
+            column
+                .typed::<ByteArrayType>()
+                .write_batch(&columns[index], None, None)?;
+        }
+        column.close()?;
+        index += 1;
+    }
+    row_group.close()?;
+    writer.close()?;
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+
+    fn archive(yaml: &str) -> Result<Archive> {
+        Archive::from_config(serde_yaml::from_str(yaml)?)
+    }
+
+    fn record(message_id: &str, body: &str) -> ArchiveRecord {
+        ArchiveRecord {
+            route: "gitea".to_string(),
+            message_id: message_id.to_string(),
+            message: serde_json::from_value(serde_json::json!({
+                "path": "/webhook/gitea/push",
+                "method": "POST",
+                "headers": {"X-Gitea-Event": "push"},
+                "queryStringParameters": {"ref": "main"},
+                "body": body,
+                "timestamp": "2025-01-15T10:30:00Z",
+            }))
+            .unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_object_key() {
+        let received = DateTime::parse_from_rfc3339("2025-01-15T10:30:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let json = archive("bucket: webhooks").unwrap();
+        assert_eq!(
+            json.object_key("gitea", received, "msg-1"),
+            "gitea/2025-01-15/msg-1.json"
+        );
+
+        let parquet = archive("bucket: webhooks\nformat: parquet").unwrap();
+        assert_eq!(
+            parquet.object_key("gitea", received, "msg-1"),
+            "gitea/2025-01-15/10/msg-1.parquet"
+        );
+
+        for bad in [
+            "bucket: \"\"",
+            "bucket: webhooks\nkey: \"{route}/{date}.json\"",
+            "bucket: webhooks\nkey: \"{route}/{day}/{message_id}.json\"",
+            "bucket: webhooks\nmax_records: 0",
+            "bucket: webhooks\nflush_seconds: 0",
+        ] {
+            assert!(archive(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_batch_records() {
+        let now = Utc::now();
+        let mut batch = PendingArchive {
+            archive: archive("bucket: webhooks\nformat: ndjson\nflush_seconds: 1").unwrap(),
+            route: "gitea".to_string(),
+            hour: now.duration_trunc(ChronoDuration::hours(1)).unwrap(),
+            records: IndexMap::new(),
+            started: Instant::now(),
+        };
+        for (id, body) in [("msg-1", "a"), ("msg-2", "b"), ("msg-1", "c")] {
+            batch.insert(record(id, body));
+        }
+
+        // A redelivered webhook keeps its place with its latest body
+        let bodies: Vec<(&str, &str)> = batch
+            .records
+            .iter()
+            .map(|(id, r)| (id.as_str(), r.message.body.as_str()))
+            .collect();
+        assert_eq!(bodies, vec![("msg-1", "c"), ("msg-2", "b")]);
+
+        // Written as a part of the hour once it has waited flush_seconds
+        assert!(!batch.due(now));
+        batch.started -= Duration::from_secs(1);
+        assert!(batch.due(now));
+
+        let router = |archive: &str| {
+            crate::router::WebhookRouter::from_yaml(&format!(
+                "routes:\n  gitea:\n    url: \"http://gitea\"\n    batch: {{}}\n    archive:\n      bucket: webhooks\n      format: {}\n",
+                archive
+            ))
+        };
+        assert!(router("json").is_ok());
+        assert!(router("parquet").is_err());
+    }
+
+    #[test]
+    fn test_encode_batch() {
+        let (first, second) = (record("msg-1", "{\"a\": 1}"), record("msg-2", "{\"a\": 2}"));
+        let records = vec![&first, &second];
+
+        let ndjson = encode_batch(&records, ArchiveFormat::Ndjson).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(ndjson)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["route"], "gitea");
+        assert_eq!(lines[1]["messageId"], "msg-2");
+        assert_eq!(lines[1]["body"], "{\"a\": 2}");
+
+        let parquet = encode_batch(&records, ArchiveFormat::Parquet).unwrap();
+        let reader = SerializedFileReader::new(Bytes::from(parquet)).unwrap();
+        let rows: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get_string(1).unwrap(), "msg-1");
+        assert_eq!(rows[0].get_timestamp_millis(2).unwrap(), 1736937000000);
+        assert_eq!(
+            rows[0].get_string(5).unwrap(),
+            "{\"X-Gitea-Event\":[\"push\"]}"
+        );
+        assert_eq!(rows[1].get_bytes(8).unwrap().data(), b"{\"a\": 2}");
+    }
+}
@@ -0,0 +1,335 @@
+use opentelemetry::trace::SpanContext;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::claim_check::{ClaimCheck, ClaimCheckStore};
+use crate::forwarder::Forwarder;
+use crate::router::{BatchFormat, RouteTarget};
+use crate::sqs::{SqsConsumer, WebhookMessage};
+use crate::telemetry;
+use crate::validation::MessageError;
+
+/// How often the background task looks for batches that have waited long enough
+const FLUSH_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+struct PendingBatch {
+    queue: Arc<SqsConsumer>,
+    target: RouteTarget,
+    items: Vec<String>,
+    receipt_handles: Vec<String>,
+    claims: Vec<ClaimCheck>,
+    /// When each webhook was received
+    timestamps: Vec<String>,
+    /// The spans the webhooks were processed in, which the batch's span
+    /// links to
+    traces: Vec<SpanContext>,
+    started: Instant,
+}
+
+/// Buffers webhooks for batching routes and forwards them as a single request.
+/// Batches are kept per queue and target, so each batch is deleted from the
+/// queue its messages came from.
+///
+/// Buffered messages stay in flight on SQS, kept invisible by the consumer's
+/// heartbeat, until their batch has been forwarded, at which point they are
+/// removed with `DeleteMessageBatch`. If forwarding fails they are released
+/// and reappear after the visibility timeout.
+pub struct Batcher {
+    forwarder: Arc<Forwarder>,
+    claim_checks: Arc<ClaimCheckStore>,
+    pending: Mutex<HashMap<(String, String), PendingBatch>>,
+}
+
+impl Batcher {
+    pub fn new(forwarder: Arc<Forwarder>, claim_checks: Arc<ClaimCheckStore>) -> Self {
+        Batcher {
+            forwarder,
+            claim_checks,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Buffer a webhook for its target, flushing the batch if it is now full.
+    /// `claim` is the claim check the body was fetched from, if any.
+    pub async fn add(
+        &self,
+        queue: &Arc<SqsConsumer>,
+        target: &RouteTarget,
+        message: &WebhookMessage,
+        receipt_handle: String,
+        claim: Option<ClaimCheck>,
+    ) -> Result<(), MessageError> {
+        let item = encode_item(message)?;
+        let max_size = target.batch.as_ref().map_or(1, |b| b.max_size);
+        let key = (queue.name().to_string(), target.name.clone());
+
+        let full = {
+            let mut pending = self.pending.lock().unwrap();
+            let batch = pending.entry(key.clone()).or_insert_with(|| PendingBatch {
+                queue: Arc::clone(queue),
+                target: target.clone(),
+                items: Vec::new(),
+                receipt_handles: Vec::new(),
+                claims: Vec::new(),
+                timestamps: Vec::new(),
+                traces: Vec::new(),
+                started: Instant::now(),
+            });
+
+            batch.items.push(item);
+            batch.receipt_handles.push(receipt_handle);
+            batch.claims.extend(claim);
+            batch.timestamps.push(message.timestamp.clone());
+            batch.traces.push(telemetry::span_context());
+
+            if batch.items.len() >= max_size {
+                pending.remove(&key)
+            } else {
+                None
+            }
+        };
+
+        if let Some(batch) = full {
+            self.flush(batch).await;
+        }
+
+        Ok(())
+    }
+
+    /// Periodically flush batches whose oldest webhook has waited `max_wait_ms`
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(FLUSH_CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let due: Vec<PendingBatch> = {
+                let mut pending = self.pending.lock().unwrap();
+                let expired: Vec<(String, String)> = pending
+                    .iter()
+                    .filter(|(_, batch)| {
+                        let max_wait = batch.target.batch.as_ref().map_or(0, |b| b.max_wait_ms);
+                        batch.started.elapsed() >= Duration::from_millis(max_wait)
+                    })
+                    .map(|(key, _)| key.clone())
+                    .collect();
+
+                expired
+                    .iter()
+                    .filter_map(|key| pending.remove(key))
+                    .collect()
+            };
+
+            // Each batch in a task of its own, so a slow target does not
+            // hold up other routes' batches
+            for batch in due {
+                let batcher = Arc::clone(&self);
+                tokio::spawn(async move { batcher.flush(batch).await });
+            }
+        }
+    }
+
+    /// Forward a batch in a span of its own, linked to the spans of the
+    /// webhooks in it, whose context the batch request carries
+    async fn flush(&self, mut batch: PendingBatch) {
+        let span = info_span!(
+            parent: None,
+            "batch",
+            queue = %batch.queue.name(),
+            target = %batch.target.name,
+            webhooks = batch.items.len(),
+        );
+        telemetry::link(&span, std::mem::take(&mut batch.traces));
+        self.send(batch).instrument(span).await;
+    }
+
+    async fn send(&self, batch: PendingBatch) {
+        let queue = batch.queue.name();
+        let target = &batch.target;
+        let count = batch.items.len();
+        let format = target.batch.as_ref().map(|b| b.format).unwrap_or_default();
+        let (body, content_type) = encode_batch(&batch.items, format);
+
+        info!("Forwarding batch of {} webhooks to {}", count, target.url);
+
+        let metrics = self.forwarder.metrics();
+        let in_flight = metrics.in_flight(queue, &target.name);
+        let timer = metrics
+            .forward_duration
+            .with_label_values(&[queue, &target.name])
+            .start_timer();
+
+        let result = self
+            .forwarder
+            .forward_batch(target, body, content_type, count)
+            .await;
+        drop(in_flight);
+        match result {
+            Ok(status) => {
+                timer.observe_duration();
+                metrics
+                    .messages_forwarded
+                    .with_label_values(&[queue, &target.name, &status.to_string()])
+                    .inc_by(count as f64);
+                metrics
+                    .batch_size
+                    .with_label_values(&[queue, &target.name])
+                    .observe(count as f64);
+
+                if status.is_success() {
+                    info!("Batch forwarded successfully: {}", status);
+                    for timestamp in &batch.timestamps {
+                        metrics.observe_end_to_end(queue, &target.name, timestamp);
+                    }
+                    self.claim_checks.delivered(&batch.claims).await;
+                } else {
+                    // Still consider it processed - the target received it
+                    warn!("Batch forwarded but got error response: {}", status);
+                }
+
+                if let Err(e) = batch
+                    .queue
+                    .delete_message_batch(&batch.receipt_handles)
+                    .await
+                {
+                    error!("Failed to delete batched messages: {}", e);
+                }
+            }
+            Err(e) => {
+                timer.observe_duration();
+                metrics
+                    .messages_failed
+                    .with_label_values(&[queue, &target.name, "forward_error"])
+                    .inc_by(count as f64);
+                error!("Failed to forward batch: {:#}", e);
+                // Messages will return to queue after visibility timeout
+                for handle in &batch.receipt_handles {
+                    batch.queue.release(handle);
+                }
+            }
+        }
+    }
+}
+
+/// Encode a webhook body as a single JSON value. JSON bodies are embedded
+/// as-is; anything else becomes a JSON string.
+fn encode_item(message: &WebhookMessage) -> Result<String, MessageError> {
+    let body = message.decoded_body()?;
+    Ok(match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(value) => value.to_string(),
+        Err(_) => {
+            serde_json::Value::String(String::from_utf8_lossy(&body).into_owned()).to_string()
+        }
+    })
+}
+
+fn encode_batch(items: &[String], format: BatchFormat) -> (Vec<u8>, &'static str) {
+    match format {
+        BatchFormat::JsonArray => (
+            format!("[{}]", items.join(",")).into_bytes(),
+            "application/json",
+        ),
+        BatchFormat::Ndjson => {
+            let mut body = items.join("\n");
+            body.push('\n');
+            (body.into_bytes(), "application/x-ndjson")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::WebhookRouter;
+
+    fn message(body: &str) -> WebhookMessage {
+        serde_json::from_value(serde_json::json!({
+            "path": "/webhook/dagster/events",
+            "method": "POST",
+            "headers": {
+                "X-Hub-Signature-256": format!("sha256={}", body),
+                "X-GitHub-Delivery": body,
+            },
+            "body": body,
+            "sourceIp": "203.0.113.7",
+            "timestamp": "2025-01-15T10:30:00Z",
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_batch_headers() {
+        let received: Arc<Mutex<Option<axum::http::HeaderMap>>> = Default::default();
+        let app = axum::Router::new().route(
+            "/ingest",
+            axum::routing::post({
+                let received = Arc::clone(&received);
+                move |headers: axum::http::HeaderMap| async move {
+                    *received.lock().unwrap() = Some(headers);
+                    "ok"
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let yaml = format!(
+            "routes:\n  dagster:\n    url: \"http://{}/ingest\"\n    append_path: false\n    headers:\n      add:\n        X-Api-Key: key\n      forwarded: true\n    batch:\n      max_size: 2\n",
+            addr
+        );
+        let router = WebhookRouter::from_yaml(&yaml).unwrap();
+        let (target, _) = router.route("/webhook/dagster/events", None).unwrap();
+        let batcher = Batcher::new(Arc::new(Forwarder::default()), Default::default());
+        let queue = SqsConsumer::for_tests("https://sqs.eu-west-2.amazonaws.com/123/webhooks");
+        for body in ["1", "2"] {
+            let webhook = WebhookMessage {
+                message_group_id: Some("dagster".to_string()),
+                ..message(body)
+            };
+            batcher
+                .add(&queue, target, &webhook, body.to_string(), None)
+                .await
+                .unwrap();
+        }
+
+        // Only the route's static headers; none of the webhooks' own, which
+        // describe one event rather than the batch
+        let headers = received.lock().unwrap().take().unwrap();
+        assert_eq!(headers["x-api-key"], "key");
+        assert_eq!(headers["x-webhook-batch-size"], "2");
+        for name in [
+            "x-hub-signature-256",
+            "x-github-delivery",
+            "x-forwarded-for",
+            "x-message-group-id",
+        ] {
+            assert!(headers.get(name).is_none(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_encode_batch() {
+        let items = vec![
+            encode_item(&message(r#"{"event": "push", "id": 1}"#)).unwrap(),
+            encode_item(&message("plain text")).unwrap(),
+        ];
+
+        let (body, content_type) = encode_batch(&items, BatchFormat::JsonArray);
+        assert_eq!(content_type, "application/json");
+        let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            parsed,
+            serde_json::json!([{"event": "push", "id": 1}, "plain text"])
+        );
+
+        let (body, content_type) = encode_batch(&items, BatchFormat::Ndjson);
+        assert_eq!(content_type, "application/x-ndjson");
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "{\"event\":\"push\",\"id\":1}\n\"plain text\"\n"
+        );
+    }
+}
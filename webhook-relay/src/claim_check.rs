@@ -0,0 +1,255 @@
+use anyhow::{Context, Result};
+use aws_sdk_s3::Client;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::object_store;
+use crate::validation::MessageError;
+
+/// Reference to a webhook body too large for SQS, stored in an S3-compatible
+/// bucket by the ingestion side
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClaimCheck {
+    pub bucket: String,
+    pub key: String,
+    /// Hex-encoded SHA-256 of the stored body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Fetches claim-checked bodies from the object store, and removes them once
+/// they have been delivered when configured to
+pub struct ClaimCheckStore {
+    client: Client,
+    delete_after_delivery: bool,
+    max_bytes: usize,
+}
+
+impl ClaimCheckStore {
+    pub async fn new(config: &Config) -> Self {
+        ClaimCheckStore {
+            client: object_store::client(&config.object_store).await,
+            delete_after_delivery: config.claim_check_delete_after_delivery,
+            max_bytes: config.claim_check_max_bytes,
+        }
+    }
+
+    /// Download the body a claim check points at, verifying its checksum
+    pub async fn fetch(&self, claim: &ClaimCheck) -> Result<Vec<u8>> {
+        let mut object = self
+            .client
+            .get_object()
+            .bucket(&claim.bucket)
+            .key(&claim.key)
+            .send()
+            .await
+            .with_context(|| format!("Failed to fetch s3://{}/{}", claim.bucket, claim.key))?;
+
+        if object
+            .content_length()
+            .is_some_and(|len| len as u64 > self.max_bytes as u64)
+        {
+            return Err(MessageError::BodyTooLarge(self.max_bytes).into());
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = object
+            .body
+            .try_next()
+            .await
+            .context("Failed to read claim-checked body")?
+        {
+            if body.len() + chunk.len() > self.max_bytes {
+                return Err(MessageError::BodyTooLarge(self.max_bytes).into());
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        if let Some(ref expected) = claim.sha256 {
+            let actual = hex::encode(digest::digest(&digest::SHA256, &body));
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(MessageError::ChecksumMismatch {
+                    expected: expected.clone(),
+                    actual,
+                }
+                .into());
+            }
+        }
+
+        info!(
+            "Fetched claim-checked body s3://{}/{} ({} bytes)",
+            claim.bucket,
+            claim.key,
+            body.len()
+        );
+
+        Ok(body)
+    }
+
+    /// Remove delivered bodies, if CLAIM_CHECK_DELETE_AFTER_DELIVERY is set.
+    /// Failures are only logged: the webhook has already been delivered.
+    pub async fn delivered(&self, claims: &[ClaimCheck]) {
+        if !self.delete_after_delivery {
+            return;
+        }
+
+        for claim in claims {
+            if let Err(e) = self
+                .client
+                .delete_object()
+                .bucket(&claim.bucket)
+                .key(&claim.key)
+                .send()
+                .await
+            {
+                warn!(
+                    "Failed to delete claim-checked body s3://{}/{}: {}",
+                    claim.bucket, claim.key, e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl Default for ClaimCheckStore {
+    fn default() -> Self {
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version_latest()
+            .build();
+        ClaimCheckStore {
+            client: Client::from_conf(config),
+            delete_after_delivery: false,
+            max_bytes: usize::MAX,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ObjectStoreConfig;
+    use axum::extract::{Path, State};
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use axum::Router;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// Minimal path-style S3 stand-in supporting GetObject and DeleteObject
+    async fn object_store(objects: Objects) -> String {
+        async fn get_object(
+            State(objects): State<Objects>,
+            Path((bucket, key)): Path<(String, String)>,
+        ) -> Result<Vec<u8>, StatusCode> {
+            objects
+                .lock()
+                .unwrap()
+                .get(&format!("{}/{}", bucket, key))
+                .cloned()
+                .ok_or(StatusCode::NOT_FOUND)
+        }
+
+        async fn delete_object(
+            State(objects): State<Objects>,
+            Path((bucket, key)): Path<(String, String)>,
+        ) -> StatusCode {
+            objects
+                .lock()
+                .unwrap()
+                .remove(&format!("{}/{}", bucket, key));
+            StatusCode::NO_CONTENT
+        }
+
+        let app = Router::new()
+            .route("/:bucket/*key", get(get_object).delete(delete_object))
+            .with_state(objects);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        format!("http://{}", addr)
+    }
+
+    async fn store(endpoint: String, delete_after_delivery: bool) -> ClaimCheckStore {
+        let config = ObjectStoreConfig {
+            endpoint: Some(endpoint),
+            region: "us-east-1".to_string(),
+            access_key_id: Some("test".to_string()),
+            secret_access_key: Some("test".to_string()),
+            force_path_style: true,
+        };
+
+        ClaimCheckStore {
+            client: object_store::client(&config).await,
+            delete_after_delivery,
+            max_bytes: 1024,
+        }
+    }
+
+    fn claim(key: &str, sha256: Option<&str>) -> ClaimCheck {
+        ClaimCheck {
+            bucket: "webhooks".to_string(),
+            key: key.to_string(),
+            sha256: sha256.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_delete() {
+        let body = br#"{"action": "published"}"#.to_vec();
+        let sha256 = hex::encode(digest::digest(&digest::SHA256, &body));
+
+        let objects = Objects::default();
+        objects
+            .lock()
+            .unwrap()
+            .insert("webhooks/github/release.json".to_string(), body.clone());
+
+        let store = store(object_store(Arc::clone(&objects)).await, true).await;
+        let claim = claim("github/release.json", Some(&sha256));
+
+        assert_eq!(store.fetch(&claim).await.unwrap(), body);
+
+        store.delivered(&[claim]).await;
+        assert!(objects.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_rejects_bad_objects() {
+        let objects = Objects::default();
+        objects
+            .lock()
+            .unwrap()
+            .insert("webhooks/small".to_string(), b"payload".to_vec());
+        objects
+            .lock()
+            .unwrap()
+            .insert("webhooks/large".to_string(), vec![b'x'; 2048]);
+
+        let store = store(object_store(Arc::clone(&objects)).await, false).await;
+
+        let mismatch = claim("small", Some(&"0".repeat(64)));
+        let err = store.fetch(&mismatch).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(MessageError::ChecksumMismatch { .. })
+        ));
+
+        let err = store.fetch(&claim("large", None)).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(MessageError::BodyTooLarge(1024))
+        ));
+        assert!(store.fetch(&claim("missing", None)).await.is_err());
+
+        // Objects are kept unless deletion is enabled
+        store.delivered(&[claim("small", None)]).await;
+        assert_eq!(objects.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,144 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+use crate::sqs::WebhookMessage;
+use crate::validation::MessageError;
+
+/// Compression applied to a webhook body by the ingestion side
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentEncoding {
+    Gzip,
+    Zstd,
+    #[serde(alias = "brotli")]
+    Br,
+}
+
+impl ContentEncoding {
+    /// The `Content-Encoding` header value for this encoding
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Zstd => "zstd",
+            ContentEncoding::Br => "br",
+        }
+    }
+}
+
+/// Decompress `data`, failing once the output would exceed `max_bytes` so a
+/// small payload can't expand into an unbounded one
+pub fn decompress(
+    data: &[u8],
+    encoding: ContentEncoding,
+    max_bytes: usize,
+) -> Result<Vec<u8>, MessageError> {
+    let invalid = |source| MessageError::InvalidCompression {
+        encoding: encoding.as_str(),
+        source,
+    };
+
+    let reader: Box<dyn Read + '_> = match encoding {
+        ContentEncoding::Gzip => Box::new(flate2::read::MultiGzDecoder::new(data)),
+        ContentEncoding::Zstd => Box::new(zstd::stream::read::Decoder::new(data).map_err(invalid)?),
+        ContentEncoding::Br => Box::new(brotli::Decompressor::new(data, 4096)),
+    };
+
+    let mut output = Vec::new();
+    reader
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut output)
+        .map_err(invalid)?;
+
+    if output.len() > max_bytes {
+        return Err(MessageError::BodyTooLarge(max_bytes));
+    }
+
+    Ok(output)
+}
+
+/// Replace a compressed webhook body with its decompressed form. Any
+/// `Content-Encoding` header carried over from the original request no
+/// longer applies and is dropped.
+pub fn expand(message: &mut WebhookMessage, max_bytes: usize) -> Result<(), MessageError> {
+    let Some(encoding) = message.content_encoding.take() else {
+        return Ok(());
+    };
+
+    let body = decompress(&message.decoded_body()?, encoding, max_bytes)?;
+    message.body = BASE64.encode(body);
+    message.is_base64_encoded = true;
+    message.remove_header("content-encoding");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const BODY: &[u8] = br#"{"action": "published", "release": {"tag_name": "v1.2.0"}}"#;
+
+    fn compress(data: &[u8], encoding: ContentEncoding) -> Vec<u8> {
+        match encoding {
+            ContentEncoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            ContentEncoding::Zstd => zstd::encode_all(data, 0).unwrap(),
+            ContentEncoding::Br => {
+                let mut output = Vec::new();
+                let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, 5, 22);
+                encoder.write_all(data).unwrap();
+                drop(encoder);
+                output
+            }
+        }
+    }
+
+    #[test]
+    fn test_decompress() {
+        for encoding in [
+            ContentEncoding::Gzip,
+            ContentEncoding::Zstd,
+            ContentEncoding::Br,
+        ] {
+            let compressed = compress(BODY, encoding);
+            assert_eq!(decompress(&compressed, encoding, 1024).unwrap(), BODY);
+
+            // Expanding past the limit is refused rather than truncated
+            let bomb = compress(&vec![0; 1 << 20], encoding);
+            assert!(bomb.len() < 4096);
+            assert!(matches!(
+                decompress(&bomb, encoding, 64 * 1024),
+                Err(MessageError::BodyTooLarge(_))
+            ));
+
+            assert!(decompress(b"not compressed", encoding, 1024).is_err());
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        let mut message: WebhookMessage = serde_json::from_value(serde_json::json!({
+            "path": "/webhook/immich/asset",
+            "method": "POST",
+            "headers": {"Content-Encoding": "br", "content-type": "application/json"},
+            "body": BASE64.encode(compress(BODY, ContentEncoding::Br)),
+            "is_base64_encoded": true,
+            "contentEncoding": "brotli",
+            "timestamp": "2025-01-15T10:30:00Z",
+        }))
+        .unwrap();
+        assert_eq!(message.content_encoding, Some(ContentEncoding::Br));
+
+        expand(&mut message, 1024).unwrap();
+        assert_eq!(message.content_encoding, None);
+        assert_eq!(message.decoded_body().unwrap(), BODY);
+        assert_eq!(message.headers.len(), 1);
+        assert!(message.headers.contains_key("content-type"));
+    }
+}
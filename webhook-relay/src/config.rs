@@ -1,33 +1,183 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fs;
+
+use crate::http_client::ClientSettings;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     // AWS Configuration
     pub aws_region: String,
-    pub sqs_queue_url: String,
 
-    // Polling Configuration
-    pub poll_interval_ms: u64,
-    pub max_messages: i32,
+    // Queue Configuration
+    pub queues: Vec<QueueConfig>,
+    pub max_concurrency: usize,
 
     // Routing Configuration
     pub route_config_path: String,
+    pub discovery: DiscoveryConfig,
+
+    // Envelope Configuration
+    pub sns_verify_signatures: bool,
+    pub sns_cert_dir: Option<String>,
+
+    // Claim Check Configuration
+    pub object_store: ObjectStoreConfig,
+    pub claim_check_delete_after_delivery: bool,
+    pub claim_check_max_bytes: usize,
+
+    // Compression Configuration
+    pub max_decompressed_bytes: usize,
+
+    // Kafka Configuration, for routes that publish to a topic
+    pub kafka: KafkaConfig,
+
+    // Redis Configuration, for routes that write to a stream or channel
+    pub redis: RedisConfig,
+
+    // Outbound HTTP Configuration, which routes can override
+    pub http_client: ClientSettings,
+
+    // Response Configuration
+    pub response_max_bytes: usize,
+    pub delivery_log_path: Option<String>,
 
     // Server Configuration
     pub http_port: u16,
     pub metrics_port: u16,
+    /// How often queue message counts are fetched for metrics; 0 disables
+    pub queue_metrics_interval_seconds: u64,
+    pub metrics: MetricsConfig,
+}
+
+/// Settings for a single SQS queue consumed by the relay
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueConfig {
+    pub name: String,
+    pub url: String,
+
+    // Scheduling: queues with a higher priority are always served first,
+    // queues with equal priority share capacity in proportion to their weight
+    pub priority: u32,
+    pub weight: u32,
+    pub concurrency: usize,
+
+    // Polling Configuration
+    pub poll_interval_ms: u64,
+    pub max_messages: i32,
+    pub visibility_timeout_seconds: i32,
+    pub wait_time_seconds: i32,
+
+    /// Route used for messages whose path does not match any route
+    pub default_route: Option<String>,
+
+    /// Queue that invalid messages are moved to, with the reason attached
+    pub dead_letter_url: Option<String>,
+
+    /// Queue that target responses are published to, for webhooks that
+    /// carry a correlation ID
+    pub reply_url: Option<String>,
+}
+
+/// Connection settings for the S3-compatible object store (SeaweedFS in the
+/// homelab, or AWS S3 itself when no endpoint is set)
+#[derive(Debug, Clone)]
+pub struct ObjectStoreConfig {
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub force_path_style: bool,
+}
+
+/// Access to the Kubernetes API server for routes that reference a Service.
+/// Inside a cluster the API server and service account are found
+/// automatically.
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    pub api_url: Option<String>,
+    pub token_path: String,
+    pub ca_path: String,
+    pub refresh_seconds: u64,
+}
+
+/// Connection to the Kafka (Redpanda) cluster that kafka routes publish to
+#[derive(Debug, Clone)]
+pub struct KafkaConfig {
+    pub brokers: Option<String>,
+    pub client_id: String,
+    pub security_protocol: String,
+    pub ca_path: Option<String>,
+    pub sasl_mechanism: Option<String>,
+    pub sasl_username: Option<String>,
+    pub sasl_password: Option<String>,
+    pub compression: String,
+    pub delivery_timeout_seconds: u64,
+}
+
+/// Connection to the Redis server that redis routes write to
+#[derive(Debug, Clone)]
+pub struct RedisConfig {
+    pub url: Option<String>,
+    /// Overrides any password in the URL, so it can come from a secret
+    pub password: Option<String>,
+}
+
+/// Naming and histogram buckets of the relay's Prometheus metrics. Unset
+/// buckets keep their defaults.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsConfig {
+    /// Prepended to every metric name, separated by `_`
+    pub prefix: Option<String>,
+    /// Labels with fixed values added to every metric
+    pub labels: HashMap<String, String>,
+    /// Forward, mirror and SQS call latencies, in seconds
+    pub duration_buckets: Option<Vec<f64>>,
+    /// Time from a webhook being received to its delivery, in seconds
+    pub end_to_end_buckets: Option<Vec<f64>>,
+    /// Request and response body sizes, in bytes
+    pub size_buckets: Option<Vec<f64>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueuesFile {
+    queues: Vec<QueueEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueueEntry {
+    name: String,
+    url: String,
+    #[serde(default)]
+    priority: u32,
+    weight: Option<u32>,
+    concurrency: Option<usize>,
+    poll_interval_ms: Option<u64>,
+    max_messages: Option<i32>,
+    visibility_timeout_seconds: Option<i32>,
+    wait_time_seconds: Option<i32>,
+    default_route: Option<String>,
+    dead_letter_url: Option<String>,
+    reply_url: Option<String>,
 }
 
 impl Config {
     pub fn from_env() -> Result<Self> {
-        Ok(Config {
-            aws_region: env::var("AWS_REGION")
-                .or_else(|_| env::var("AWS_DEFAULT_REGION"))
-                .unwrap_or_else(|_| "us-east-1".to_string()),
+        // Environment settings apply to the single SQS_QUEUE_URL queue, and
+        // act as defaults for queues listed in QUEUE_CONFIG_PATH, apart from
+        // the dead-letter and reply queues, which each queue sets itself
+        let defaults = QueueConfig {
+            name: "default".to_string(),
+            url: String::new(),
+            priority: 0,
+            weight: 1,
 
-            sqs_queue_url: env::var("SQS_QUEUE_URL")
-                .context("SQS_QUEUE_URL environment variable is required")?,
+            concurrency: env::var("QUEUE_CONCURRENCY")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()
+                .context("QUEUE_CONCURRENCY must be a valid number")?,
 
             poll_interval_ms: env::var("POLL_INTERVAL_MS")
                 .unwrap_or_else(|_| "1000".to_string())
@@ -39,9 +189,207 @@ impl Config {
                 .parse()
                 .context("MAX_MESSAGES must be a valid number")?,
 
+            visibility_timeout_seconds: env::var("VISIBILITY_TIMEOUT_SECONDS")
+                .unwrap_or_else(|_| "60".to_string())
+                .parse()
+                .context("VISIBILITY_TIMEOUT_SECONDS must be a valid number")?,
+
+            wait_time_seconds: env::var("WAIT_TIME_SECONDS")
+                .unwrap_or_else(|_| "20".to_string())
+                .parse()
+                .context("WAIT_TIME_SECONDS must be a valid number")?,
+
+            default_route: None,
+
+            dead_letter_url: env::var("DEAD_LETTER_QUEUE_URL")
+                .ok()
+                .filter(|url| !url.is_empty()),
+
+            reply_url: env::var("REPLY_QUEUE_URL")
+                .ok()
+                .filter(|url| !url.is_empty()),
+        };
+
+        let queues = match env::var("QUEUE_CONFIG_PATH") {
+            Ok(path) => {
+                let content = fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read queue config from {}", path))?;
+                parse_queues(&content, &defaults)?
+            }
+            Err(_) => {
+                let queue = QueueConfig {
+                    url: env::var("SQS_QUEUE_URL").context(
+                        "SQS_QUEUE_URL or QUEUE_CONFIG_PATH environment variable is required",
+                    )?,
+                    ..defaults
+                };
+                validate_queue(&queue)?;
+                vec![queue]
+            }
+        };
+
+        let aws_region = env::var("AWS_REGION")
+            .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|_| "us-east-1".to_string());
+
+        let object_store = ObjectStoreConfig {
+            endpoint: env::var("S3_ENDPOINT").ok(),
+            region: env::var("S3_REGION").unwrap_or_else(|_| aws_region.clone()),
+            access_key_id: env::var("S3_ACCESS_KEY_ID").ok(),
+            secret_access_key: env::var("S3_SECRET_ACCESS_KEY").ok(),
+            force_path_style: env::var("S3_FORCE_PATH_STYLE")
+                .unwrap_or_else(|_| "true".to_string())
+                .parse()
+                .context("S3_FORCE_PATH_STYLE must be true or false")?,
+        };
+
+        let in_cluster_api_url = match (
+            env::var("KUBERNETES_SERVICE_HOST"),
+            env::var("KUBERNETES_SERVICE_PORT"),
+        ) {
+            (Ok(host), Ok(port)) if host.contains(':') => {
+                Some(format!("https://[{}]:{}", host, port))
+            }
+            (Ok(host), Ok(port)) => Some(format!("https://{}:{}", host, port)),
+            _ => None,
+        };
+
+        let discovery = DiscoveryConfig {
+            api_url: env::var("KUBE_API_URL").ok().or(in_cluster_api_url),
+            token_path: env::var("KUBE_TOKEN_PATH").unwrap_or_else(|_| {
+                "/var/run/secrets/kubernetes.io/serviceaccount/token".to_string()
+            }),
+            ca_path: env::var("KUBE_CA_PATH").unwrap_or_else(|_| {
+                "/var/run/secrets/kubernetes.io/serviceaccount/ca.crt".to_string()
+            }),
+            refresh_seconds: env::var("DISCOVERY_REFRESH_SECONDS")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()
+                .context("DISCOVERY_REFRESH_SECONDS must be a valid number")?,
+        };
+
+        let kafka = KafkaConfig {
+            brokers: env::var("KAFKA_BROKERS").ok().filter(|b| !b.is_empty()),
+            client_id: env::var("KAFKA_CLIENT_ID").unwrap_or_else(|_| "webhook-relay".to_string()),
+            security_protocol: env::var("KAFKA_SECURITY_PROTOCOL")
+                .unwrap_or_else(|_| "plaintext".to_string()),
+            ca_path: env::var("KAFKA_CA_PATH").ok(),
+            sasl_mechanism: env::var("KAFKA_SASL_MECHANISM").ok(),
+            sasl_username: env::var("KAFKA_SASL_USERNAME").ok(),
+            sasl_password: env::var("KAFKA_SASL_PASSWORD").ok(),
+            compression: env::var("KAFKA_COMPRESSION").unwrap_or_else(|_| "none".to_string()),
+            delivery_timeout_seconds: env::var("KAFKA_DELIVERY_TIMEOUT_SECONDS")
+                .unwrap_or_else(|_| "30".to_string())
+                .parse()
+                .context("KAFKA_DELIVERY_TIMEOUT_SECONDS must be a valid number")?,
+        };
+
+        let redis = RedisConfig {
+            url: env::var("REDIS_URL").ok().filter(|url| !url.is_empty()),
+            password: env::var("REDIS_PASSWORD").ok().filter(|p| !p.is_empty()),
+        };
+
+        let metrics = MetricsConfig {
+            prefix: env::var("METRICS_PREFIX").ok().filter(|p| !p.is_empty()),
+            labels: parse_labels(&env::var("METRICS_LABELS").unwrap_or_default())
+                .context("METRICS_LABELS must be a list of name=value pairs")?,
+            duration_buckets: parse_buckets("METRICS_DURATION_BUCKETS")?,
+            end_to_end_buckets: parse_buckets("METRICS_END_TO_END_BUCKETS")?,
+            size_buckets: parse_buckets("METRICS_SIZE_BUCKETS")?,
+        };
+
+        let http_client = ClientSettings {
+            connect_timeout_seconds: Some(
+                env::var("HTTP_CONNECT_TIMEOUT_SECONDS")
+                    .unwrap_or_else(|_| "10".to_string())
+                    .parse()
+                    .context("HTTP_CONNECT_TIMEOUT_SECONDS must be a valid number")?,
+            ),
+            pool_max_idle_per_host: Some(
+                env::var("HTTP_POOL_MAX_IDLE_PER_HOST")
+                    .unwrap_or_else(|_| "10".to_string())
+                    .parse()
+                    .context("HTTP_POOL_MAX_IDLE_PER_HOST must be a valid number")?,
+            ),
+            pool_idle_timeout_seconds: Some(
+                env::var("HTTP_POOL_IDLE_TIMEOUT_SECONDS")
+                    .unwrap_or_else(|_| "90".to_string())
+                    .parse()
+                    .context("HTTP_POOL_IDLE_TIMEOUT_SECONDS must be a valid number")?,
+            ),
+            http2_prior_knowledge: Some(
+                env::var("HTTP2_PRIOR_KNOWLEDGE")
+                    .unwrap_or_else(|_| "false".to_string())
+                    .parse()
+                    .context("HTTP2_PRIOR_KNOWLEDGE must be true or false")?,
+            ),
+            max_redirects: Some(
+                env::var("HTTP_MAX_REDIRECTS")
+                    .unwrap_or_else(|_| "10".to_string())
+                    .parse()
+                    .context("HTTP_MAX_REDIRECTS must be a valid number")?,
+            ),
+            proxy: env::var("HTTP_PROXY_URL").ok(),
+            resolve: Default::default(),
+            ca_path: env::var("HTTP_CA_PATH").ok(),
+            client_cert_path: env::var("HTTP_CLIENT_CERT_PATH").ok(),
+            client_key_path: env::var("HTTP_CLIENT_KEY_PATH").ok(),
+        };
+
+        let config = Config {
+            aws_region,
+
+            queues,
+
+            max_concurrency: env::var("MAX_CONCURRENCY")
+                .unwrap_or_else(|_| "10".to_string())
+                .parse()
+                .context("MAX_CONCURRENCY must be a valid number")?,
+
             route_config_path: env::var("ROUTE_CONFIG_PATH")
                 .unwrap_or_else(|_| "/config/routes.yaml".to_string()),
 
+            discovery,
+
+            sns_verify_signatures: env::var("SNS_VERIFY_SIGNATURES")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .context("SNS_VERIFY_SIGNATURES must be true or false")?,
+
+            sns_cert_dir: env::var("SNS_CERT_DIR").ok(),
+
+            object_store,
+
+            claim_check_delete_after_delivery: env::var("CLAIM_CHECK_DELETE_AFTER_DELIVERY")
+                .unwrap_or_else(|_| "false".to_string())
+                .parse()
+                .context("CLAIM_CHECK_DELETE_AFTER_DELIVERY must be true or false")?,
+
+            claim_check_max_bytes: env::var("CLAIM_CHECK_MAX_BYTES")
+                .unwrap_or_else(|_| "52428800".to_string())
+                .parse()
+                .context("CLAIM_CHECK_MAX_BYTES must be a valid number")?,
+
+            max_decompressed_bytes: env::var("MAX_DECOMPRESSED_BYTES")
+                .unwrap_or_else(|_| "52428800".to_string())
+                .parse()
+                .context("MAX_DECOMPRESSED_BYTES must be a valid number")?,
+
+            kafka,
+
+            redis,
+
+            http_client,
+
+            response_max_bytes: env::var("RESPONSE_MAX_BYTES")
+                .unwrap_or_else(|_| "65536".to_string())
+                .parse()
+                .context("RESPONSE_MAX_BYTES must be a valid number")?,
+
+            delivery_log_path: env::var("DELIVERY_LOG_PATH")
+                .ok()
+                .filter(|path| !path.is_empty()),
+
             http_port: env::var("HTTP_PORT")
                 .unwrap_or_else(|_| "8080".to_string())
                 .parse()
@@ -51,6 +399,235 @@ impl Config {
                 .unwrap_or_else(|_| "9090".to_string())
                 .parse()
                 .context("METRICS_PORT must be a valid port number")?,
+
+            queue_metrics_interval_seconds: env::var("QUEUE_METRICS_INTERVAL_SECONDS")
+                .unwrap_or_else(|_| "30".to_string())
+                .parse()
+                .context("QUEUE_METRICS_INTERVAL_SECONDS must be a valid number")?,
+
+            metrics,
+        };
+
+        if config.max_concurrency == 0 {
+            bail!("MAX_CONCURRENCY must be at least 1");
+        }
+
+        if config.discovery.refresh_seconds == 0 {
+            bail!("DISCOVERY_REFRESH_SECONDS must be at least 1");
+        }
+
+        if config.object_store.access_key_id.is_some()
+            != config.object_store.secret_access_key.is_some()
+        {
+            bail!("S3_ACCESS_KEY_ID and S3_SECRET_ACCESS_KEY must be set together");
+        }
+
+        Ok(config)
+    }
+}
+
+/// Parse comma-separated `name=value` pairs
+fn parse_labels(value: &str) -> Result<HashMap<String, String>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("{} has no value", pair))?;
+            Ok((name.trim().to_string(), value.trim().to_string()))
         })
+        .collect()
+}
+
+/// Read comma-separated histogram buckets from an environment variable
+fn parse_buckets(name: &str) -> Result<Option<Vec<f64>>> {
+    let Ok(value) = env::var(name) else {
+        return Ok(None);
+    };
+    let buckets = value
+        .split(',')
+        .map(|bucket| bucket.trim().parse())
+        .collect::<Result<Vec<f64>, _>>()
+        .with_context(|| format!("{} must be a list of numbers", name))?;
+    if buckets.is_empty() || buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+        bail!("{} must be in increasing order", name);
+    }
+    Ok(Some(buckets))
+}
+
+/// Parse a queue list, filling unset poll and concurrency settings from
+/// `defaults`. Dead-letter and reply queues are never inherited.
+fn parse_queues(yaml: &str, defaults: &QueueConfig) -> Result<Vec<QueueConfig>> {
+    let file: QueuesFile = serde_yaml::from_str(yaml).context("Failed to parse queues YAML")?;
+
+    if file.queues.is_empty() {
+        bail!("At least one queue must be configured");
+    }
+
+    let mut names = HashSet::new();
+    let mut queues = Vec::new();
+
+    for entry in file.queues {
+        if !names.insert(entry.name.clone()) {
+            bail!("Duplicate queue name: {}", entry.name);
+        }
+
+        let queue = QueueConfig {
+            name: entry.name,
+            url: entry.url,
+            priority: entry.priority,
+            weight: entry.weight.unwrap_or(defaults.weight),
+            concurrency: entry.concurrency.unwrap_or(defaults.concurrency),
+            poll_interval_ms: entry.poll_interval_ms.unwrap_or(defaults.poll_interval_ms),
+            max_messages: entry.max_messages.unwrap_or(defaults.max_messages),
+            visibility_timeout_seconds: entry
+                .visibility_timeout_seconds
+                .unwrap_or(defaults.visibility_timeout_seconds),
+            wait_time_seconds: entry
+                .wait_time_seconds
+                .unwrap_or(defaults.wait_time_seconds),
+            default_route: entry.default_route,
+            dead_letter_url: entry.dead_letter_url,
+            reply_url: entry.reply_url,
+        };
+
+        validate_queue(&queue)?;
+        queues.push(queue);
+    }
+
+    Ok(queues)
+}
+
+fn validate_queue(queue: &QueueConfig) -> Result<()> {
+    let invalid = |msg: &str| anyhow!("Queue {}: {}", queue.name, msg);
+
+    if !(1..=10).contains(&queue.max_messages) {
+        return Err(invalid("max_messages must be between 1 and 10"));
+    }
+
+    if !(1..=43200).contains(&queue.visibility_timeout_seconds) {
+        return Err(invalid(
+            "visibility_timeout_seconds must be between 1 and 43200",
+        ));
+    }
+
+    if !(0..=20).contains(&queue.wait_time_seconds) {
+        return Err(invalid("wait_time_seconds must be between 0 and 20"));
+    }
+
+    if queue.weight == 0 {
+        return Err(invalid("weight must be at least 1"));
+    }
+
+    if queue.concurrency == 0 {
+        return Err(invalid("concurrency must be at least 1"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> QueueConfig {
+        QueueConfig {
+            name: "default".to_string(),
+            url: String::new(),
+            priority: 0,
+            weight: 1,
+            concurrency: 10,
+            poll_interval_ms: 1000,
+            max_messages: 10,
+            visibility_timeout_seconds: 60,
+            wait_time_seconds: 20,
+            default_route: None,
+            dead_letter_url: Some(
+                "https://sqs.us-east-1.amazonaws.com/123456789012/dlq".to_string(),
+            ),
+            reply_url: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_queues() {
+        let yaml = r#"
+queues:
+  - name: payments
+    url: "https://sqs.us-east-1.amazonaws.com/123456789012/payments"
+    priority: 10
+    concurrency: 4
+    default_route: n8n
+    dead_letter_url: "https://sqs.us-east-1.amazonaws.com/123456789012/payments-dlq"
+    reply_url: "https://sqs.us-east-1.amazonaws.com/123456789012/replies"
+  - name: github
+    url: "https://sqs.us-east-1.amazonaws.com/123456789012/github"
+    weight: 3
+    max_messages: 5
+    wait_time_seconds: 10
+"#;
+
+        let queues = parse_queues(yaml, &defaults()).unwrap();
+        assert_eq!(queues.len(), 2);
+
+        assert_eq!(queues[0].name, "payments");
+        assert_eq!(queues[0].priority, 10);
+        assert_eq!(queues[0].weight, 1);
+        assert_eq!(queues[0].concurrency, 4);
+        assert_eq!(queues[0].max_messages, 10);
+        assert_eq!(queues[0].default_route.as_deref(), Some("n8n"));
+        assert_eq!(
+            queues[0].dead_letter_url.as_deref(),
+            Some("https://sqs.us-east-1.amazonaws.com/123456789012/payments-dlq")
+        );
+
+        assert_eq!(
+            queues[0].reply_url.as_deref(),
+            Some("https://sqs.us-east-1.amazonaws.com/123456789012/replies")
+        );
+
+        assert_eq!(queues[1].name, "github");
+        assert_eq!(queues[1].priority, 0);
+        assert_eq!(queues[1].weight, 3);
+        assert_eq!(queues[1].concurrency, 10);
+        assert_eq!(queues[1].max_messages, 5);
+        assert_eq!(queues[1].wait_time_seconds, 10);
+        assert_eq!(queues[1].visibility_timeout_seconds, 60);
+        // Dead-letter and reply queues are not inherited from the environment
+        assert_eq!(queues[1].dead_letter_url, None);
+        assert_eq!(queues[1].reply_url, None);
+    }
+
+    #[test]
+    fn test_invalid_queues() {
+        let duplicate = r#"
+queues:
+  - name: github
+    url: "https://sqs.us-east-1.amazonaws.com/123456789012/a"
+  - name: github
+    url: "https://sqs.us-east-1.amazonaws.com/123456789012/b"
+"#;
+        assert!(parse_queues(duplicate, &defaults()).is_err());
+
+        let bad_wait = r#"
+queues:
+  - name: github
+    url: "https://sqs.us-east-1.amazonaws.com/123456789012/github"
+    wait_time_seconds: 30
+"#;
+        assert!(parse_queues(bad_wait, &defaults()).is_err());
+
+        assert!(parse_queues("queues: []", &defaults()).is_err());
+    }
+
+    #[test]
+    fn test_parse_labels() {
+        let labels = parse_labels("cluster=homelab, instance = relay-a").unwrap();
+        assert_eq!(labels["cluster"], "homelab");
+        assert_eq!(labels["instance"], "relay-a");
+        assert!(parse_labels("").unwrap().is_empty());
+        assert!(parse_labels("cluster").is_err());
     }
 }
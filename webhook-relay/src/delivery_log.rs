@@ -0,0 +1,239 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{SecondsFormat, Utc};
+use indexmap::IndexMap;
+use serde::Serialize;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::forwarder::ForwardResponse;
+use crate::router::RouteTarget;
+use crate::sqs::WebhookMessage;
+
+/// A target's response to one delivered webhook. The status, headers and
+/// body follow the API Gateway proxy response format, so the ingestion side
+/// can hand a reply straight back to the sender.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliveryRecord {
+    pub version: u32,
+    pub timestamp: String,
+    pub queue: String,
+    pub route: String,
+    pub method: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    pub status_code: u16,
+    pub multi_value_headers: IndexMap<String, Vec<String>>,
+    pub body: String,
+    pub is_base64_encoded: bool,
+    /// The body was cut short at RESPONSE_MAX_BYTES
+    pub truncated: bool,
+    pub latency_ms: u64,
+}
+
+impl DeliveryRecord {
+    pub fn new(
+        queue: &str,
+        target: &RouteTarget,
+        message: &WebhookMessage,
+        message_id: Option<&str>,
+        response: &ForwardResponse,
+    ) -> Self {
+        let mut multi_value_headers: IndexMap<String, Vec<String>> = IndexMap::new();
+        for (name, value) in &response.headers {
+            multi_value_headers
+                .entry(name.to_string())
+                .or_default()
+                .push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+        }
+
+        // Text bodies are kept readable; anything else is base64 encoded. A
+        // truncated body may end part way through a character.
+        let (body, is_base64_encoded) = match std::str::from_utf8(&response.body) {
+            Ok(text) if !response.truncated => (text.to_string(), false),
+            _ => (BASE64.encode(&response.body), true),
+        };
+
+        DeliveryRecord {
+            version: 1,
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            queue: queue.to_string(),
+            route: target.name.clone(),
+            method: message.method.clone(),
+            path: message.path.clone(),
+            message_id: message_id.map(str::to_string),
+            correlation_id: message.correlation_id.clone(),
+            status_code: response.status.as_u16(),
+            multi_value_headers,
+            body,
+            is_base64_encoded,
+            truncated: response.truncated,
+            latency_ms: response.latency.as_millis() as u64,
+        }
+    }
+}
+
+/// Records every target response in the log, and as JSON lines in
+/// DELIVERY_LOG_PATH when it is set
+pub struct DeliveryLog {
+    file: Option<Mutex<File>>,
+}
+
+impl DeliveryLog {
+    pub async fn open(path: Option<&str>) -> Result<Self> {
+        let file = match path {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .with_context(|| format!("Failed to open delivery log {}", path))?,
+            )),
+            None => None,
+        };
+
+        Ok(DeliveryLog { file })
+    }
+
+    /// Failures to write are only logged: the webhook has already been
+    /// delivered.
+    pub async fn record(&self, record: &DeliveryRecord) {
+        info!(
+            "Delivered {} {} to {}: {} in {}ms{}",
+            record.method,
+            record.path,
+            record.route,
+            record.status_code,
+            record.latency_ms,
+            if record.truncated {
+                " (response truncated)"
+            } else {
+                ""
+            }
+        );
+
+        let Some(ref file) = self.file else {
+            return;
+        };
+
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to encode delivery record: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut file = file.lock().await;
+        if let Err(e) = file.write_all(&line).await {
+            warn!("Failed to write delivery log: {}", e);
+        } else if let Err(e) = file.flush().await {
+            warn!("Failed to flush delivery log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::TargetKind;
+    use reqwest::header::{HeaderMap, HeaderValue};
+    use reqwest::StatusCode;
+    use std::time::Duration;
+
+    fn target() -> RouteTarget {
+        RouteTarget {
+            name: "slack".to_string(),
+            kind: TargetKind::Http,
+            url: "https://slack-bot.example.com".to_string(),
+            service: None,
+            pool: None,
+            timeout_seconds: 5,
+            batch: None,
+            accept_encoding: Vec::new(),
+            url_rules: Default::default(),
+            methods: None,
+            headers: Default::default(),
+            client: Default::default(),
+            mirror: None,
+            archive: None,
+        }
+    }
+
+    fn response(body: &[u8], truncated: bool) -> ForwardResponse {
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", HeaderValue::from_static("a=1"));
+        headers.append("set-cookie", HeaderValue::from_static("b=2"));
+        headers.append("content-type", HeaderValue::from_static("text/plain"));
+
+        ForwardResponse {
+            status: StatusCode::OK,
+            headers,
+            body: body.to_vec(),
+            truncated,
+            latency: Duration::from_millis(42),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let message: WebhookMessage = serde_json::from_value(serde_json::json!({
+            "path": "/webhook/slack/command",
+            "method": "POST",
+            "headers": {},
+            "timestamp": "2025-01-15T10:30:00Z",
+            "correlationId": "req-1234",
+        }))
+        .unwrap();
+
+        let record = DeliveryRecord::new(
+            "default",
+            &target(),
+            &message,
+            Some("msg-1"),
+            &response(b"Deploying...", false),
+        );
+        assert_eq!(record.correlation_id.as_deref(), Some("req-1234"));
+        assert_eq!(record.status_code, 200);
+        assert_eq!(record.multi_value_headers["set-cookie"], vec!["a=1", "b=2"]);
+        assert_eq!(record.body, "Deploying...");
+        assert!(!record.is_base64_encoded);
+        assert_eq!(record.latency_ms, 42);
+
+        let binary = DeliveryRecord::new(
+            "default",
+            &target(),
+            &message,
+            None,
+            &response(&[0xff, 0x00], false),
+        );
+        assert!(binary.is_base64_encoded);
+        assert_eq!(binary.body, "/wA=");
+
+        let path = std::env::temp_dir().join(format!("delivery-log-{}.jsonl", std::process::id()));
+        let log = DeliveryLog::open(path.to_str()).await.unwrap();
+        log.record(&record).await;
+        log.record(&binary).await;
+        drop(log);
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["correlationId"], "req-1234");
+        assert_eq!(lines[0]["statusCode"], 200);
+        assert_eq!(lines[1]["isBase64Encoded"], true);
+    }
+}
@@ -0,0 +1,444 @@
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::{Certificate, Client};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::config::DiscoveryConfig;
+use crate::router::RouteTarget;
+
+/// A Kubernetes Service a route forwards to, reached through the addresses in
+/// its EndpointSlices rather than through DNS and the cluster IP
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ServiceRef {
+    pub name: String,
+    pub namespace: String,
+    pub port: ServicePort,
+    pub scheme: String,
+    /// Path prepended to the rest of the webhook path
+    pub base_path: String,
+}
+
+/// A port by name, or by the port number the endpoints listen on
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(untagged)]
+pub enum ServicePort {
+    Number(u16),
+    Name(String),
+}
+
+impl std::fmt::Display for ServiceRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Endpoint {
+    address: SocketAddr,
+    ready: bool,
+    serving: bool,
+}
+
+/// Known endpoints of one service, and where round-robin selection is up to
+#[derive(Default)]
+struct Endpoints {
+    endpoints: RwLock<Vec<Endpoint>>,
+    next: AtomicUsize,
+}
+
+// The parts of the discovery.k8s.io/v1 EndpointSlice API the relay reads
+
+#[derive(Deserialize)]
+struct EndpointSliceList {
+    items: Vec<EndpointSlice>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EndpointSlice {
+    address_type: String,
+    #[serde(default)]
+    endpoints: Vec<SliceEndpoint>,
+    #[serde(default)]
+    ports: Vec<SlicePort>,
+}
+
+#[derive(Deserialize)]
+struct SliceEndpoint {
+    addresses: Vec<String>,
+    #[serde(default)]
+    conditions: Conditions,
+}
+
+#[derive(Default, Deserialize)]
+struct Conditions {
+    ready: Option<bool>,
+    serving: Option<bool>,
+    terminating: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct SlicePort {
+    name: Option<String>,
+    port: Option<u16>,
+    protocol: Option<String>,
+}
+
+/// Resolves service routes to endpoint addresses, refreshed from the API
+/// server every DISCOVERY_REFRESH_SECONDS
+pub struct ServiceDiscovery {
+    client: Client,
+    api_url: Url,
+    token_path: String,
+    refresh: Duration,
+    services: HashMap<ServiceRef, Endpoints>,
+}
+
+impl ServiceDiscovery {
+    /// Set up discovery for the routes that reference a service. Returns
+    /// None when no route does.
+    pub fn new<'a>(
+        config: &DiscoveryConfig,
+        targets: impl Iterator<Item = &'a RouteTarget>,
+    ) -> Result<Option<Arc<Self>>> {
+        let services: HashMap<ServiceRef, Endpoints> = targets
+            .filter_map(|target| target.service.clone())
+            .map(|service| (service, Endpoints::default()))
+            .collect();
+        if services.is_empty() {
+            return Ok(None);
+        }
+
+        let Some(ref api_url) = config.api_url else {
+            bail!("Routes use service discovery, but KUBE_API_URL is not set and the relay is not running in a cluster");
+        };
+        let api_url =
+            Url::parse(api_url).with_context(|| format!("Invalid KUBE_API_URL {}", api_url))?;
+
+        let mut builder = Client::builder().timeout(Duration::from_secs(10));
+        if Path::new(&config.ca_path).exists() {
+            let pem = fs::read(&config.ca_path)
+                .with_context(|| format!("Failed to read {}", config.ca_path))?;
+            let ca = Certificate::from_pem(&pem)
+                .with_context(|| format!("Invalid CA certificate {}", config.ca_path))?;
+            builder = builder.add_root_certificate(ca);
+        }
+
+        Ok(Some(Arc::new(ServiceDiscovery {
+            client: builder
+                .build()
+                .context("Failed to create Kubernetes API client")?,
+            api_url,
+            token_path: config.token_path.clone(),
+            refresh: Duration::from_secs(config.refresh_seconds),
+            services,
+        })))
+    }
+
+    /// Pick an endpoint of the service, round-robin over the ready ones.
+    /// Endpoints that are still serving while terminating are only used when
+    /// none are ready.
+    pub fn resolve(&self, service: &ServiceRef) -> Result<Url> {
+        let entry = self
+            .services
+            .get(service)
+            .ok_or_else(|| anyhow!("Service {} is not being discovered", service))?;
+
+        let endpoints = entry.endpoints.read().unwrap();
+        let mut eligible: Vec<&Endpoint> = endpoints.iter().filter(|e| e.ready).collect();
+        if eligible.is_empty() {
+            eligible = endpoints.iter().filter(|e| e.serving).collect();
+        }
+        if eligible.is_empty() {
+            bail!("No ready endpoints for service {}", service);
+        }
+
+        let endpoint = eligible[entry.next.fetch_add(1, Ordering::Relaxed) % eligible.len()];
+        let url = format!(
+            "{}://{}{}",
+            service.scheme, endpoint.address, service.base_path
+        );
+        Url::parse(&url).with_context(|| format!("Invalid endpoint URL {}", url))
+    }
+
+    /// Refresh every service, keeping the last known endpoints of any that
+    /// fail
+    pub async fn refresh_all(&self) {
+        for (service, entry) in &self.services {
+            match self.fetch(service).await {
+                Ok(endpoints) => {
+                    let mut known = entry.endpoints.write().unwrap();
+                    if *known != endpoints {
+                        info!(
+                            "Service {}: {} endpoints ({} ready)",
+                            service,
+                            endpoints.len(),
+                            endpoints.iter().filter(|e| e.ready).count()
+                        );
+                        *known = endpoints;
+                    }
+                }
+                Err(e) => warn!("Failed to refresh endpoints of {}: {:#}", service, e),
+            }
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.refresh);
+        loop {
+            interval.tick().await;
+            self.refresh_all().await;
+        }
+    }
+
+    async fn fetch(&self, service: &ServiceRef) -> Result<Vec<Endpoint>> {
+        let mut url = self.api_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("KUBE_API_URL cannot be a base URL"))?
+            .pop_if_empty()
+            .extend([
+                "apis",
+                "discovery.k8s.io",
+                "v1",
+                "namespaces",
+                &service.namespace,
+                "endpointslices",
+            ]);
+        url.query_pairs_mut().append_pair(
+            "labelSelector",
+            &format!("kubernetes.io/service-name={}", service.name),
+        );
+
+        let mut request = self.client.get(url);
+        // Projected service account tokens rotate, so read it each time
+        if let Ok(token) = fs::read_to_string(&self.token_path) {
+            request = request.bearer_auth(token.trim());
+        }
+
+        let slices: EndpointSliceList = request
+            .send()
+            .await
+            .context("Failed to list EndpointSlices")?
+            .error_for_status()
+            .context("Failed to list EndpointSlices")?
+            .json()
+            .await
+            .context("Invalid EndpointSlice list")?;
+
+        let mut endpoints = Vec::new();
+        for slice in slices.items {
+            if !matches!(slice.address_type.as_str(), "IPv4" | "IPv6") {
+                continue;
+            }
+
+            let port = slice.ports.iter().find_map(|p| {
+                let tcp = p.protocol.as_deref().unwrap_or("TCP") == "TCP";
+                let matches = match service.port {
+                    ServicePort::Name(ref name) => p.name.as_deref().unwrap_or("") == name,
+                    ServicePort::Number(number) => p.port == Some(number),
+                };
+                (tcp && matches).then_some(p.port).flatten()
+            });
+            let Some(port) = port else {
+                debug!("EndpointSlice of {} has no matching port", service);
+                continue;
+            };
+
+            for endpoint in slice.endpoints {
+                let ready = endpoint.conditions.ready.unwrap_or(true);
+                let serving = endpoint.conditions.serving.unwrap_or(ready);
+                let terminating = endpoint.conditions.terminating.unwrap_or(false);
+
+                for address in endpoint.addresses {
+                    let Ok(ip) = address.parse::<IpAddr>() else {
+                        continue;
+                    };
+                    endpoints.push(Endpoint {
+                        address: SocketAddr::new(ip, port),
+                        ready: ready && !terminating,
+                        serving,
+                    });
+                }
+            }
+        }
+
+        // Keep a stable order so refreshes without changes are no-ops
+        endpoints.sort_by_key(|e| e.address);
+        Ok(endpoints)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{Path as UrlPath, Query, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use std::sync::Mutex;
+
+    type Slices = Arc<Mutex<serde_json::Value>>;
+
+    /// API server stand-in serving EndpointSlices from the n8n namespace
+    async fn api_server(slices: Slices) -> String {
+        async fn list(
+            State(slices): State<Slices>,
+            UrlPath(namespace): UrlPath<String>,
+            Query(query): Query<HashMap<String, String>>,
+            headers: HeaderMap,
+        ) -> Result<Json<serde_json::Value>, StatusCode> {
+            if headers
+                .get("authorization")
+                .is_some_and(|v| v != "Bearer token")
+            {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+            if namespace != "n8n" {
+                return Err(StatusCode::NOT_FOUND);
+            }
+            if query.get("labelSelector").map(String::as_str)
+                != Some("kubernetes.io/service-name=n8n")
+            {
+                return Ok(Json(serde_json::json!({"items": []})));
+            }
+            Ok(Json(slices.lock().unwrap().clone()))
+        }
+
+        let app = Router::new()
+            .route(
+                "/apis/discovery.k8s.io/v1/namespaces/:namespace/endpointslices",
+                get(list),
+            )
+            .with_state(slices);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        format!("http://{}", addr)
+    }
+
+    fn slice(endpoints: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "addressType": "IPv4",
+            "endpoints": endpoints,
+            "ports": [
+                {"name": "metrics", "port": 9090, "protocol": "TCP"},
+                {"name": "http", "port": 5678, "protocol": "TCP"}
+            ]
+        })
+    }
+
+    fn service(port: ServicePort) -> ServiceRef {
+        ServiceRef {
+            name: "n8n".to_string(),
+            namespace: "n8n".to_string(),
+            port,
+            scheme: "http".to_string(),
+            base_path: "/webhook".to_string(),
+        }
+    }
+
+    fn discovery(api_url: String, services: &[ServiceRef]) -> ServiceDiscovery {
+        ServiceDiscovery {
+            client: Client::new(),
+            api_url: Url::parse(&api_url).unwrap(),
+            token_path: "/nonexistent/token".to_string(),
+            refresh: Duration::from_secs(10),
+            services: services
+                .iter()
+                .map(|s| (s.clone(), Endpoints::default()))
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let slices = Slices::new(Mutex::new(serde_json::json!({
+            "items": [
+                slice(serde_json::json!([
+                    {"addresses": ["10.42.0.11"], "conditions": {"ready": true}},
+                    {"addresses": ["10.42.0.12"]},
+                    {"addresses": ["10.42.0.13"], "conditions": {"ready": false}},
+                ])),
+                slice(serde_json::json!([
+                    {"addresses": ["10.42.1.14"], "conditions": {"ready": false, "serving": true, "terminating": true}},
+                ])),
+            ]
+        })));
+
+        let by_name = service(ServicePort::Name("http".to_string()));
+        let by_number = service(ServicePort::Number(9090));
+        let discovery = discovery(
+            api_server(Arc::clone(&slices)).await,
+            &[by_name.clone(), by_number.clone()],
+        );
+
+        // Nothing is known before the first refresh
+        assert!(discovery.resolve(&by_name).is_err());
+        discovery.refresh_all().await;
+
+        // Ready endpoints are used in turn
+        let mut urls: Vec<String> = (0..4)
+            .map(|_| discovery.resolve(&by_name).unwrap().to_string())
+            .collect();
+        urls.sort();
+        assert_eq!(
+            urls,
+            vec![
+                "http://10.42.0.11:5678/webhook",
+                "http://10.42.0.11:5678/webhook",
+                "http://10.42.0.12:5678/webhook",
+                "http://10.42.0.12:5678/webhook",
+            ]
+        );
+        assert_eq!(discovery.resolve(&by_number).unwrap().port(), Some(9090));
+
+        // Terminating endpoints that still serve are a last resort
+        *slices.lock().unwrap() = serde_json::json!({
+            "items": [slice(serde_json::json!([
+                {"addresses": ["10.42.0.13"], "conditions": {"ready": false}},
+                {"addresses": ["10.42.1.14"], "conditions": {"ready": false, "serving": true, "terminating": true}},
+            ]))]
+        });
+        discovery.refresh_all().await;
+        assert_eq!(
+            discovery.resolve(&by_name).unwrap().as_str(),
+            "http://10.42.1.14:5678/webhook"
+        );
+
+        *slices.lock().unwrap() = serde_json::json!({"items": []});
+        discovery.refresh_all().await;
+        assert!(discovery.resolve(&by_name).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_failure_keeps_endpoints() {
+        let slices = Slices::new(Mutex::new(serde_json::json!({
+            "items": [slice(serde_json::json!([{"addresses": ["fd00::11"]}]))]
+        })));
+        let api_url = api_server(Arc::clone(&slices)).await;
+
+        let n8n = service(ServicePort::Name("http".to_string()));
+        let discovery = discovery(api_url, std::slice::from_ref(&n8n));
+        discovery.refresh_all().await;
+        assert_eq!(
+            discovery.resolve(&n8n).unwrap().as_str(),
+            "http://[fd00::11]:5678/webhook"
+        );
+
+        // An invalid response from the API server leaves the endpoints as
+        // they were
+        *slices.lock().unwrap() = serde_json::json!({"kind": "Status"});
+        discovery.refresh_all().await;
+        assert!(discovery.resolve(&n8n).is_ok());
+    }
+}
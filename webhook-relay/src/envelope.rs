@@ -0,0 +1,726 @@
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, SecondsFormat, Utc};
+use reqwest::Url;
+use ring::signature::{
+    UnparsedPublicKey, VerificationAlgorithm, RSA_PKCS1_2048_8192_SHA1_FOR_LEGACY_USE_ONLY,
+    RSA_PKCS1_2048_8192_SHA256,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use indexmap::IndexMap;
+
+use crate::config::Config;
+use crate::sqs::WebhookMessage;
+use crate::validation::{self, MessageError};
+
+/// The wrapper a webhook arrived in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Envelope {
+    /// The Lambda transformer's `WebhookMessage` JSON
+    Webhook,
+    /// An SNS notification delivered to SQS without raw message delivery
+    Sns,
+    /// An EventBridge event
+    EventBridge,
+    /// A raw API Gateway REST API (payload version 1.0) proxy event
+    ApiGatewayV1,
+    /// A raw API Gateway HTTP API (payload version 2.0) proxy event
+    ApiGatewayV2,
+}
+
+#[derive(Debug, Deserialize)]
+struct SnsNotification {
+    #[serde(rename = "Type")]
+    kind: String,
+    #[serde(rename = "MessageId")]
+    message_id: String,
+    #[serde(rename = "TopicArn")]
+    topic_arn: String,
+    #[serde(rename = "Subject")]
+    subject: Option<String>,
+    #[serde(rename = "Message")]
+    message: String,
+    #[serde(rename = "Timestamp")]
+    timestamp: String,
+    #[serde(rename = "SignatureVersion")]
+    signature_version: Option<String>,
+    #[serde(rename = "Signature")]
+    signature: Option<String>,
+    #[serde(rename = "SigningCertURL")]
+    signing_cert_url: Option<String>,
+}
+
+/// Turns SQS message bodies into `WebhookMessage`s, unwrapping SNS,
+/// EventBridge and API Gateway envelopes along the way
+pub struct MessageDecoder {
+    sns_verifier: Option<SnsVerifier>,
+}
+
+impl MessageDecoder {
+    pub fn new(config: &Config) -> Result<Self> {
+        let sns_verifier = if config.sns_verify_signatures {
+            Some(SnsVerifier::new(
+                config.sns_cert_dir.as_ref().map(PathBuf::from),
+            )?)
+        } else {
+            None
+        };
+
+        Ok(MessageDecoder { sns_verifier })
+    }
+
+    /// Decode and validate a message. Messages that can never be delivered
+    /// fail with a [`MessageError`].
+    pub async fn decode(&self, body: &str) -> Result<WebhookMessage> {
+        let value: Value = serde_json::from_str(body).map_err(MessageError::NotJson)?;
+
+        let (message, envelope) = if is_sns(&value) {
+            let notification: SnsNotification = serde_json::from_value(value)
+                .map_err(|e| MessageError::Malformed(format!("invalid SNS notification: {}", e)))?;
+
+            if notification.kind != "Notification" {
+                return Err(MessageError::Malformed(format!(
+                    "unsupported SNS message type {}",
+                    notification.kind
+                ))
+                .into());
+            }
+
+            if let Some(ref verifier) = self.sns_verifier {
+                verifier.verify(&notification).await?;
+            }
+
+            (unwrap_sns(notification)?, Envelope::Sns)
+        } else {
+            unwrap(value)?
+        };
+
+        debug!("Decoded {:?} envelope for {}", envelope, message.path);
+        validation::validate(&message)?;
+        Ok(message)
+    }
+}
+
+fn is_sns(value: &Value) -> bool {
+    value.get("Type").is_some_and(Value::is_string) && value.get("TopicArn").is_some()
+}
+
+fn is_event_bridge(value: &Value) -> bool {
+    value.get("detail-type").is_some() && value.get("detail").is_some()
+}
+
+fn is_api_gateway_v2(value: &Value) -> bool {
+    value.get("version").and_then(Value::as_str) == Some("2.0")
+        && value.get("rawPath").is_some()
+        && value.pointer("/requestContext/http").is_some()
+}
+
+fn is_api_gateway_v1(value: &Value) -> bool {
+    value.get("httpMethod").is_some() && value.get("requestContext").is_some()
+}
+
+/// Decode anything but an SNS notification
+fn unwrap(value: Value) -> Result<(WebhookMessage, Envelope), MessageError> {
+    if is_event_bridge(&value) {
+        Ok((unwrap_event_bridge(value)?, Envelope::EventBridge))
+    } else if is_api_gateway_v2(&value) {
+        Ok((unwrap_api_gateway_v2(&value)?, Envelope::ApiGatewayV2))
+    } else if is_api_gateway_v1(&value) {
+        Ok((unwrap_api_gateway_v1(&value)?, Envelope::ApiGatewayV1))
+    } else {
+        let message = serde_json::from_value(value)
+            .map_err(|e| MessageError::Malformed(format!("invalid webhook message: {}", e)))?;
+        Ok((message, Envelope::Webhook))
+    }
+}
+
+/// The SNS message is decoded as a webhook in its own right when it is one;
+/// any other payload is delivered to `/webhook/sns/<topic>`
+fn unwrap_sns(notification: SnsNotification) -> Result<WebhookMessage, MessageError> {
+    if let Ok(inner) = serde_json::from_str::<Value>(&notification.message) {
+        if let Ok((message, _)) = unwrap(inner) {
+            return Ok(message);
+        }
+    }
+
+    let topic = notification
+        .topic_arn
+        .rsplit(':')
+        .next()
+        .unwrap_or_default();
+
+    let mut headers = IndexMap::from([
+        ("x-amz-sns-message-type".to_string(), notification.kind),
+        ("x-amz-sns-message-id".to_string(), notification.message_id),
+        (
+            "x-amz-sns-topic-arn".to_string(),
+            notification.topic_arn.clone(),
+        ),
+    ]);
+    if let Some(subject) = notification.subject {
+        headers.insert("x-amz-sns-subject".to_string(), subject);
+    }
+    if serde_json::from_str::<Value>(&notification.message).is_ok() {
+        headers.insert("content-type".to_string(), "application/json".to_string());
+    }
+
+    Ok(WebhookMessage {
+        path: format!("/webhook/sns/{}", topic),
+        method: "POST".to_string(),
+        headers,
+        body: notification.message,
+        is_base64_encoded: false,
+        query_string_parameters: IndexMap::new(),
+        timestamp: notification.timestamp,
+        source_ip: String::new(),
+        ..Default::default()
+    })
+}
+
+/// An event whose `detail` is a webhook is decoded as that webhook; any other
+/// event is delivered whole to `/webhook/eventbridge/<source>`
+fn unwrap_event_bridge(value: Value) -> Result<WebhookMessage, MessageError> {
+    if let Some(detail) = value.get("detail") {
+        if let Ok((message, _)) = unwrap(detail.clone()) {
+            return Ok(message);
+        }
+    }
+
+    let field = |name: &str| {
+        value
+            .get(name)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+
+    let source = field("source");
+    if source.is_empty() {
+        return Err(MessageError::Malformed(
+            "EventBridge event has no source".to_string(),
+        ));
+    }
+
+    let headers = IndexMap::from([
+        ("content-type".to_string(), "application/json".to_string()),
+        ("x-eventbridge-id".to_string(), field("id")),
+        ("x-eventbridge-source".to_string(), source.clone()),
+        (
+            "x-eventbridge-detail-type".to_string(),
+            field("detail-type"),
+        ),
+    ]);
+
+    Ok(WebhookMessage {
+        path: format!("/webhook/eventbridge/{}", source),
+        method: "POST".to_string(),
+        headers,
+        timestamp: field("time"),
+        body: value.to_string(),
+        is_base64_encoded: false,
+        query_string_parameters: IndexMap::new(),
+        source_ip: String::new(),
+        ..Default::default()
+    })
+}
+
+/// REST APIs carry repeated headers and query parameters in separate
+/// multi-value maps
+fn unwrap_api_gateway_v1(value: &Value) -> Result<WebhookMessage, MessageError> {
+    Ok(WebhookMessage {
+        path: required_str(value, "/path")?,
+        method: required_str(value, "/httpMethod")?,
+        headers: string_map(value.get("headers")),
+        multi_value_headers: string_list_map(value.get("multiValueHeaders")),
+        body: optional_str(value, "/body"),
+        is_base64_encoded: value
+            .get("isBase64Encoded")
+            .and_then(Value::as_bool)
+            .unwrap_or_default(),
+        query_string_parameters: string_map(value.get("queryStringParameters")),
+        multi_value_query_string_parameters: string_list_map(
+            value.get("multiValueQueryStringParameters"),
+        ),
+        timestamp: epoch_millis_to_rfc3339(value.pointer("/requestContext/requestTimeEpoch")),
+        source_ip: optional_str(value, "/requestContext/identity/sourceIp"),
+        ..Default::default()
+    })
+}
+
+/// HTTP APIs join repeated headers and query parameters with commas. The
+/// raw query string still has the repeats, so parameters are taken from it.
+fn unwrap_api_gateway_v2(value: &Value) -> Result<WebhookMessage, MessageError> {
+    let mut headers = string_map(value.get("headers"));
+
+    // HTTP APIs move cookies out of the headers into their own array
+    if let Some(cookies) = value.get("cookies").and_then(Value::as_array) {
+        let cookies: Vec<&str> = cookies.iter().filter_map(Value::as_str).collect();
+        if !cookies.is_empty() {
+            headers.insert("cookie".to_string(), cookies.join("; "));
+        }
+    }
+
+    Ok(WebhookMessage {
+        path: required_str(value, "/rawPath")?,
+        method: required_str(value, "/requestContext/http/method")?,
+        headers,
+        body: optional_str(value, "/body"),
+        is_base64_encoded: value
+            .get("isBase64Encoded")
+            .and_then(Value::as_bool)
+            .unwrap_or_default(),
+        query_string_parameters: string_map(value.get("queryStringParameters")),
+        multi_value_query_string_parameters: parse_query(&optional_str(value, "/rawQueryString")),
+        timestamp: epoch_millis_to_rfc3339(value.pointer("/requestContext/timeEpoch")),
+        source_ip: optional_str(value, "/requestContext/http/sourceIp"),
+        ..Default::default()
+    })
+}
+
+fn required_str(value: &Value, pointer: &str) -> Result<String, MessageError> {
+    value
+        .pointer(pointer)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| MessageError::Malformed(format!("API Gateway event is missing {}", pointer)))
+}
+
+fn optional_str(value: &Value, pointer: &str) -> String {
+    value
+        .pointer(pointer)
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// API Gateway sends `null` rather than an empty object for missing maps
+fn string_map(value: Option<&Value>) -> IndexMap<String, String> {
+    value
+        .and_then(Value::as_object)
+        .map(|map| {
+            map.iter()
+                .filter_map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn string_list_map(value: Option<&Value>) -> IndexMap<String, Vec<String>> {
+    value
+        .and_then(Value::as_object)
+        .map(|map| {
+            map.iter()
+                .filter_map(|(k, v)| {
+                    let values = v
+                        .as_array()?
+                        .iter()
+                        .filter_map(|v| v.as_str().map(str::to_string))
+                        .collect();
+                    Some((k.clone(), values))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn parse_query(query: &str) -> IndexMap<String, Vec<String>> {
+    let mut params: IndexMap<String, Vec<String>> = IndexMap::new();
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        params
+            .entry(key.into_owned())
+            .or_default()
+            .push(value.into_owned());
+    }
+    params
+}
+
+fn epoch_millis_to_rfc3339(value: Option<&Value>) -> String {
+    value
+        .and_then(Value::as_i64)
+        .and_then(DateTime::<Utc>::from_timestamp_millis)
+        .unwrap_or_else(Utc::now)
+        .to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Verifies SNS message signatures against the signing certificate.
+///
+/// Certificates are looked up in memory, then in the local certificate
+/// directory by file name, and only then downloaded from the (validated)
+/// `SigningCertURL`. Downloaded certificates are written back to the
+/// directory when it is writable.
+pub struct SnsVerifier {
+    cert_dir: Option<PathBuf>,
+    client: reqwest::Client,
+    keys: Mutex<HashMap<String, Arc<Vec<u8>>>>,
+}
+
+impl SnsVerifier {
+    pub fn new(cert_dir: Option<PathBuf>) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .context("Failed to create HTTP client for SNS certificates")?;
+
+        Ok(SnsVerifier {
+            cert_dir,
+            client,
+            keys: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// A bad signature fails with [`MessageError::InvalidSignature`]; failing
+    /// to fetch the certificate is an ordinary, retryable error
+    async fn verify(&self, notification: &SnsNotification) -> Result<()> {
+        let invalid = |reason: &str| MessageError::InvalidSignature(reason.to_string());
+
+        let algorithm: &'static dyn VerificationAlgorithm = match notification
+            .signature_version
+            .as_deref()
+        {
+            Some("1") => &RSA_PKCS1_2048_8192_SHA1_FOR_LEGACY_USE_ONLY,
+            Some("2") => &RSA_PKCS1_2048_8192_SHA256,
+            other => {
+                return Err(invalid(&format!("unsupported signature version {:?}", other)).into())
+            }
+        };
+
+        let signature = notification
+            .signature
+            .as_deref()
+            .ok_or_else(|| invalid("notification is not signed"))?;
+        let signature = BASE64
+            .decode(signature)
+            .map_err(|_| invalid("signature is not valid base64"))?;
+
+        let cert_url = notification
+            .signing_cert_url
+            .as_deref()
+            .ok_or_else(|| invalid("notification has no SigningCertURL"))?;
+        let key = self.public_key(cert_url).await?;
+
+        UnparsedPublicKey::new(algorithm, key.as_slice())
+            .verify(string_to_sign(notification).as_bytes(), &signature)
+            .map_err(|_| invalid("signature does not match").into())
+    }
+
+    async fn public_key(&self, cert_url: &str) -> Result<Arc<Vec<u8>>> {
+        if let Some(key) = self.keys.lock().unwrap().get(cert_url) {
+            return Ok(Arc::clone(key));
+        }
+
+        let url = validate_cert_url(cert_url)?;
+        let file_name = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .unwrap_or_default()
+            .to_string();
+        let local_path = self.cert_dir.as_ref().map(|dir| dir.join(&file_name));
+
+        let pem = match local_path.as_ref().map(std::fs::read) {
+            Some(Ok(pem)) => pem,
+            _ => {
+                debug!("Fetching SNS signing certificate {}", url);
+                let pem = self
+                    .client
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .context("Failed to fetch SNS signing certificate")?
+                    .bytes()
+                    .await?
+                    .to_vec();
+
+                if let Some(ref path) = local_path {
+                    if let Err(e) = std::fs::write(path, &pem) {
+                        warn!("Failed to cache SNS certificate in {:?}: {}", path, e);
+                    }
+                }
+                pem
+            }
+        };
+
+        let key = Arc::new(public_key_from_pem(&pem)?);
+        self.keys
+            .lock()
+            .unwrap()
+            .insert(cert_url.to_string(), Arc::clone(&key));
+        Ok(key)
+    }
+}
+
+/// Only accept certificates served by SNS itself over HTTPS
+fn validate_cert_url(cert_url: &str) -> Result<Url, MessageError> {
+    let untrusted =
+        || MessageError::InvalidSignature(format!("untrusted SigningCertURL {}", cert_url));
+
+    let url = Url::parse(cert_url).map_err(|_| untrusted())?;
+
+    // Exactly sns.<region>.amazonaws.com(.cn): other amazonaws.com hosts,
+    // such as S3 buckets and API Gateway, serve content anyone can control
+    let host = url.host_str().unwrap_or_default();
+    let region = host
+        .strip_prefix("sns.")
+        .and_then(|rest| {
+            rest.strip_suffix(".amazonaws.com")
+                .or_else(|| rest.strip_suffix(".amazonaws.com.cn"))
+        })
+        .unwrap_or_default();
+    let trusted_host = !region.is_empty()
+        && region
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+
+    if url.scheme() != "https" || !trusted_host || !url.path().ends_with(".pem") {
+        return Err(untrusted());
+    }
+
+    Ok(url)
+}
+
+fn public_key_from_pem(pem: &[u8]) -> Result<Vec<u8>> {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(pem)
+        .map_err(|e| anyhow!("Invalid SNS certificate PEM: {}", e))?;
+    let cert = pem
+        .parse_x509()
+        .map_err(|e| anyhow!("Invalid SNS certificate: {}", e))?;
+
+    Ok(cert.public_key().subject_public_key.data.to_vec())
+}
+
+/// The canonical string SNS signs for a notification
+fn string_to_sign(notification: &SnsNotification) -> String {
+    let mut fields = vec![
+        ("Message", notification.message.as_str()),
+        ("MessageId", notification.message_id.as_str()),
+    ];
+    if let Some(ref subject) = notification.subject {
+        fields.push(("Subject", subject));
+    }
+    fields.push(("Timestamp", &notification.timestamp));
+    fields.push(("TopicArn", &notification.topic_arn));
+    fields.push(("Type", &notification.kind));
+
+    fields
+        .iter()
+        .map(|(name, value)| format!("{}\n{}\n", name, value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SNS_NOTIFICATION: &str = include_str!("../tests/fixtures/sns-notification.json");
+
+    fn decoder(verify: bool) -> MessageDecoder {
+        let cert_dir = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures"));
+        MessageDecoder {
+            sns_verifier: verify.then(|| SnsVerifier::new(Some(cert_dir)).unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_webhook_message() {
+        let body = r#"{
+            "path": "/webhook/n8n/test",
+            "method": "POST",
+            "headers": {"content-type": "application/json"},
+            "body": "{}",
+            "timestamp": "2025-01-15T10:30:00Z"
+        }"#;
+
+        let message = decoder(false).decode(body).await.unwrap();
+        assert_eq!(message.path, "/webhook/n8n/test");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_webhook_message() {
+        let reason = |body: &str| {
+            let body = body.to_string();
+            async move {
+                let err = decoder(false).decode(&body).await.unwrap_err();
+                err.downcast_ref::<MessageError>().unwrap().reason()
+            }
+        };
+
+        assert_eq!(reason("not json").await, "not_json");
+        assert_eq!(reason(r#"{"path": "/webhook/n8n"}"#).await, "malformed");
+        assert_eq!(
+            reason(
+                r#"{
+                    "version": 1,
+                    "path": "/webhook/n8n/test",
+                    "method": "POST",
+                    "headers": {},
+                    "body": "%%%",
+                    "isBase64Encoded": true,
+                    "timestamp": "2025-01-15T10:30:00Z"
+                }"#
+            )
+            .await,
+            "invalid_base64"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sns_envelope() {
+        let message = decoder(true).decode(SNS_NOTIFICATION).await.unwrap();
+        assert_eq!(message.path, "/webhook/n8n/orders");
+        assert_eq!(message.body, r#"{"order":42}"#);
+
+        // Any change to the signed fields invalidates the signature
+        let tampered = SNS_NOTIFICATION.replace("Order", "Refund");
+        assert!(decoder(true).decode(&tampered).await.is_err());
+        assert!(decoder(false).decode(&tampered).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_sns_raw_payload() {
+        let body = serde_json::json!({
+            "Type": "Notification",
+            "MessageId": "abc",
+            "TopicArn": "arn:aws:sns:us-east-1:123456789012:alerts",
+            "Subject": "Disk full",
+            "Message": "{\"alarm\": \"disk\"}",
+            "Timestamp": "2025-01-15T10:30:00.000Z"
+        });
+
+        let message = decoder(false).decode(&body.to_string()).await.unwrap();
+        assert_eq!(message.path, "/webhook/sns/alerts");
+        assert_eq!(message.body, "{\"alarm\": \"disk\"}");
+        assert_eq!(message.headers["x-amz-sns-subject"], "Disk full");
+        assert_eq!(message.headers["content-type"], "application/json");
+    }
+
+    #[tokio::test]
+    async fn test_event_bridge_envelope() {
+        let body = serde_json::json!({
+            "version": "0",
+            "id": "6a7e8feb-b491-4cf7-a9f1-bf3703467718",
+            "detail-type": "Object Created",
+            "source": "aws.s3",
+            "time": "2025-01-15T10:30:00Z",
+            "detail": {"bucket": {"name": "uploads"}}
+        });
+
+        let message = decoder(false).decode(&body.to_string()).await.unwrap();
+        assert_eq!(message.path, "/webhook/eventbridge/aws.s3");
+        assert_eq!(message.timestamp, "2025-01-15T10:30:00Z");
+        assert_eq!(
+            message.headers["x-eventbridge-detail-type"],
+            "Object Created"
+        );
+        let forwarded: Value = serde_json::from_str(&message.body).unwrap();
+        assert_eq!(forwarded, body);
+
+        // A webhook carried as the event detail is unwrapped
+        let body = serde_json::json!({
+            "detail-type": "Webhook",
+            "source": "webhooks",
+            "detail": {
+                "path": "/webhook/gitea/push",
+                "method": "POST",
+                "headers": {},
+                "body": "",
+                "timestamp": "2025-01-15T10:30:00Z"
+            }
+        });
+        let message = decoder(false).decode(&body.to_string()).await.unwrap();
+        assert_eq!(message.path, "/webhook/gitea/push");
+    }
+
+    #[tokio::test]
+    async fn test_api_gateway_v1_envelope() {
+        let body = serde_json::json!({
+            "resource": "/{proxy+}",
+            "path": "/webhook/n8n/stripe",
+            "httpMethod": "POST",
+            "headers": {"Content-Type": "application/json", "Via": "1.1 b"},
+            "multiValueHeaders": {
+                "Content-Type": ["application/json"],
+                "Via": ["1.1 a", "1.1 b"]
+            },
+            "queryStringParameters": null,
+            "multiValueQueryStringParameters": null,
+            "requestContext": {
+                "requestTimeEpoch": 1736937000000_i64,
+                "identity": {"sourceIp": "192.0.2.1"}
+            },
+            "body": "eyJpZCI6IDF9",
+            "isBase64Encoded": true
+        });
+
+        let message = decoder(false).decode(&body.to_string()).await.unwrap();
+        assert_eq!(message.path, "/webhook/n8n/stripe");
+        assert_eq!(message.method, "POST");
+        assert_eq!(message.source_ip, "192.0.2.1");
+        assert_eq!(message.timestamp, "2025-01-15T10:30:00.000Z");
+        assert!(message.query_values().is_empty());
+        assert_eq!(
+            message.header_values(),
+            vec![
+                ("Content-Type", "application/json"),
+                ("Via", "1.1 a"),
+                ("Via", "1.1 b")
+            ]
+        );
+        assert_eq!(message.decoded_body().unwrap(), br#"{"id": 1}"#);
+    }
+
+    #[tokio::test]
+    async fn test_api_gateway_v2_envelope() {
+        let body = serde_json::json!({
+            "version": "2.0",
+            "routeKey": "$default",
+            "rawPath": "/webhook/gitea/push",
+            "rawQueryString": "ref=main&tag=a&tag=b%20c",
+            "cookies": ["a=1", "b=2"],
+            "headers": {"x-gitea-event": "push"},
+            "queryStringParameters": {"ref": "main", "tag": "a,b c"},
+            "requestContext": {
+                "http": {"method": "PUT", "sourceIp": "198.51.100.7"},
+                "timeEpoch": 1736937000000_i64
+            },
+            "body": "{}",
+            "isBase64Encoded": false
+        });
+
+        let message = decoder(false).decode(&body.to_string()).await.unwrap();
+        assert_eq!(message.path, "/webhook/gitea/push");
+        assert_eq!(message.method, "PUT");
+        assert_eq!(message.headers["cookie"], "a=1; b=2");
+        assert_eq!(
+            message.query_values(),
+            vec![("ref", "main"), ("tag", "a"), ("tag", "b c")]
+        );
+        assert_eq!(message.source_ip, "198.51.100.7");
+    }
+
+    #[test]
+    fn test_cert_url_validation() {
+        assert!(validate_cert_url(
+            "https://sns.eu-west-1.amazonaws.com/SimpleNotificationService-1.pem"
+        )
+        .is_ok());
+        assert!(validate_cert_url("http://sns.eu-west-1.amazonaws.com/cert.pem").is_err());
+        assert!(validate_cert_url("https://sns.evil.example.com/cert.pem").is_err());
+        assert!(validate_cert_url("https://s3.amazonaws.com/cert.pem").is_err());
+        assert!(validate_cert_url(
+            "https://sns.cn-north-1.amazonaws.com.cn/SimpleNotificationService-1.pem"
+        )
+        .is_ok());
+        assert!(
+            validate_cert_url("https://sns.attacker-bucket.s3.amazonaws.com/cert.pem").is_err()
+        );
+        assert!(validate_cert_url("https://sns.evil.execute-api.amazonaws.com/cert.pem").is_err());
+        assert!(validate_cert_url("https://sns.amazonaws.com/cert.pem").is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use anyhow::{anyhow, bail, Context, Result};
+use indexmap::IndexMap;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+use url::form_urlencoded;
+
+use crate::router::RouteTarget;
+use crate::sqs::WebhookMessage;
+
+/// Most of each output stream that is logged; longer output keeps its end
+const MAX_LOGGED_OUTPUT: usize = 8192;
+
+/// An exec route's command, as configured in the routes YAML
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandConfig {
+    /// Program and arguments, run without a shell
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
+    /// Extra environment variables
+    #[serde(default)]
+    pub env: IndexMap<String, String>,
+    /// Relay environment variables passed through; PATH always is
+    #[serde(default)]
+    pub inherit_env: Vec<String>,
+    /// Runs of the command at once; further webhooks wait their turn
+    #[serde(default = "default_max_concurrency")]
+    pub max_concurrency: usize,
+    /// Exit codes that count as delivered
+    #[serde(default = "default_success_codes")]
+    pub success_codes: Vec<i32>,
+}
+
+fn default_max_concurrency() -> usize {
+    1
+}
+
+fn default_success_codes() -> Vec<i32> {
+    vec![0]
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecTarget {
+    pub program: String,
+    args: Vec<String>,
+    working_dir: Option<PathBuf>,
+    env: IndexMap<String, String>,
+    inherit_env: Vec<String>,
+    success_codes: Vec<i32>,
+    running: Arc<Semaphore>,
+}
+
+impl ExecTarget {
+    pub fn from_config(config: CommandConfig) -> Result<Self> {
+        let mut command = config.command.into_iter();
+        let Some(program) = command.next().filter(|p| !p.is_empty()) else {
+            bail!("command must name a program");
+        };
+        if config.max_concurrency == 0 {
+            bail!("max_concurrency must be at least 1");
+        }
+        if config.success_codes.is_empty() {
+            bail!("success_codes must not be empty");
+        }
+
+        Ok(ExecTarget {
+            program,
+            args: command.collect(),
+            working_dir: config.working_dir,
+            env: config.env,
+            inherit_env: config.inherit_env,
+            success_codes: config.success_codes,
+            running: Arc::new(Semaphore::new(config.max_concurrency)),
+        })
+    }
+
+    /// Run the command for a webhook, with the body on stdin and the rest of
+    /// the webhook in the environment. Fails if the command exits with other
+    /// than a success code or outlives the route's timeout, when it is
+    /// killed. Returns the exit code.
+    pub async fn run(
+        &self,
+        target: &RouteTarget,
+        message: &WebhookMessage,
+        message_id: Option<&str>,
+    ) -> Result<i32> {
+        let body = message.decoded_body()?;
+        let _permit = self.running.acquire().await?;
+
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .env_clear()
+            .envs(self.inherited_env())
+            .envs(webhook_env(target, message, message_id))
+            .envs(&self.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(ref dir) = self.working_dir {
+            command.current_dir(dir);
+        }
+
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("Failed to run {}", self.program))?;
+
+        // Commands that ignore stdin may exit before reading it
+        let mut stdin = child.stdin.take();
+        tokio::spawn(async move {
+            if let Some(ref mut stdin) = stdin {
+                let _ = stdin.write_all(&body).await;
+            }
+        });
+
+        let timeout = Duration::from_secs(target.timeout_seconds);
+        let output = tokio::time::timeout(timeout, child.wait_with_output())
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "{} timed out after {}s",
+                    self.program,
+                    target.timeout_seconds
+                )
+            })?
+            .with_context(|| format!("Failed to run {}", self.program))?;
+
+        let stdout = logged_output(&output.stdout);
+        if !stdout.is_empty() {
+            info!("{} stdout: {}", target.name, stdout);
+        }
+        let stderr = logged_output(&output.stderr);
+        if !stderr.is_empty() {
+            warn!("{} stderr: {}", target.name, stderr);
+        }
+
+        match output.status.code() {
+            Some(code) if self.success_codes.contains(&code) => Ok(code),
+            Some(code) => Err(anyhow!("{} exited with code {}", self.program, code)),
+            None => Err(anyhow!("{} was killed by a signal", self.program)),
+        }
+    }
+
+    fn inherited_env(&self) -> Vec<(String, String)> {
+        std::iter::once("PATH")
+            .chain(self.inherit_env.iter().map(String::as_str))
+            .filter_map(|name| Some((name.to_string(), std::env::var(name).ok()?)))
+            .collect()
+    }
+}
+
+/// The webhook's details as WEBHOOK_* variables. Headers the route's policy
+/// lets through become WEBHOOK_HEADER_<NAME>, with repeated values joined.
+fn webhook_env(
+    target: &RouteTarget,
+    message: &WebhookMessage,
+    message_id: Option<&str>,
+) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("WEBHOOK_ROUTE".to_string(), target.name.clone()),
+        ("WEBHOOK_METHOD".to_string(), message.method.clone()),
+        ("WEBHOOK_PATH".to_string(), message.path.clone()),
+        ("WEBHOOK_SOURCE_IP".to_string(), message.source_ip.clone()),
+        ("WEBHOOK_TIMESTAMP".to_string(), message.timestamp.clone()),
+        (
+            "WEBHOOK_QUERY".to_string(),
+            form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(message.query_values())
+                .finish(),
+        ),
+    ];
+    if let Some(id) = message_id {
+        env.push(("WEBHOOK_MESSAGE_ID".to_string(), id.to_string()));
+    }
+    if let Some(ref id) = message.correlation_id {
+        env.push(("WEBHOOK_CORRELATION_ID".to_string(), id.clone()));
+    }
+
+    let mut headers: IndexMap<String, Vec<String>> = IndexMap::new();
+    for (name, value) in &target.headers.forwarded_headers(message) {
+        let name = name
+            .as_str()
+            .chars()
+            .map(|c| match c {
+                'a'..='z' => c.to_ascii_uppercase(),
+                'A'..='Z' | '0'..='9' => c,
+                _ => '_',
+            })
+            .collect::<String>();
+        headers
+            .entry(format!("WEBHOOK_HEADER_{}", name))
+            .or_default()
+            .push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+    }
+    env.extend(
+        headers
+            .into_iter()
+            .map(|(name, values)| (name, values.join(", "))),
+    );
+
+    env
+}
+
+fn logged_output(output: &[u8]) -> String {
+    let text = String::from_utf8_lossy(output);
+    let text = text.trim_end();
+    match text.char_indices().rev().nth(MAX_LOGGED_OUTPUT) {
+        Some((start, _)) => format!("...{}", &text[start..]),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{TargetKind, WebhookRouter};
+
+    fn message() -> WebhookMessage {
+        serde_json::from_value(serde_json::json!({
+            "path": "/webhook/deploy/relay",
+            "method": "POST",
+            "headers": {"X-Gitea-Event": "push", "Authorization": "secret"},
+            "queryStringParameters": {"ref": "main"},
+            "body": "{\"after\": \"abc\"}",
+            "timestamp": "2025-01-15T10:30:00Z",
+        }))
+        .unwrap()
+    }
+
+    fn route(exec: &str) -> (WebhookRouter, RouteTarget) {
+        let yaml = format!(
+            "routes:\n  deploy:\n    timeout_seconds: 1\n    headers:\n      deny: [\"authorization\"]\n    exec:\n{}",
+            exec
+        );
+        let router = WebhookRouter::from_yaml(&yaml).unwrap();
+        let (target, _) = router.route("/webhook/deploy/relay", None).unwrap();
+        let target = target.clone();
+        (router, target)
+    }
+
+    async fn run(exec: &str) -> Result<i32> {
+        let (_, target) = route(exec);
+        let TargetKind::Exec(ref command) = target.kind else {
+            panic!("expected an exec target");
+        };
+        command.run(&target, &message(), Some("msg-1")).await
+    }
+
+    #[tokio::test]
+    async fn test_exec() {
+        let out = std::env::temp_dir().join(format!("exec-{}.txt", std::process::id()));
+        let exec = format!(
+            concat!(
+                "      command: [\"sh\", \"-c\", \"cat > \\\"$OUT\\\"; ",
+                "env | grep ^WEBHOOK_ | sort >> \\\"$OUT\\\"; echo done\"]\n",
+                "      env:\n        OUT: \"{}\"\n"
+            ),
+            out.display()
+        );
+        assert_eq!(run(&exec).await.unwrap(), 0);
+
+        let written = std::fs::read_to_string(&out).unwrap();
+        std::fs::remove_file(&out).unwrap();
+        assert!(written.starts_with("{\"after\": \"abc\"}"));
+        assert!(written.contains("WEBHOOK_HEADER_X_GITEA_EVENT=push\n"));
+        assert!(written.contains("WEBHOOK_MESSAGE_ID=msg-1\n"));
+        assert!(written.contains("WEBHOOK_PATH=/webhook/deploy/relay\n"));
+        assert!(written.contains("WEBHOOK_QUERY=ref=main\n"));
+        assert!(!written.contains("AUTHORIZATION"));
+
+        let failed = run("      command: [\"sh\", \"-c\", \"exit 3\"]\n").await;
+        assert!(failed.unwrap_err().to_string().contains("code 3"));
+
+        let allowed = "      command: [\"sh\", \"-c\", \"exit 3\"]\n      success_codes: [0, 3]\n";
+        assert_eq!(run(allowed).await.unwrap(), 3);
+
+        let slow = run("      command: [\"sleep\", \"5\"]\n").await;
+        assert!(slow.unwrap_err().to_string().contains("timed out"));
+
+        for bad in [
+            "      command: []\n",
+            "      command: [\"true\"]\n      max_concurrency: 0\n",
+            "      command: [\"true\"]\n    batch: {}\n",
+        ] {
+            let yaml = format!("routes:\n  deploy:\n    exec:\n{}", bad);
+            assert!(WebhookRouter::from_yaml(&yaml).is_err(), "{}", bad);
+        }
+    }
+}
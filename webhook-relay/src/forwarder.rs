@@ -1,26 +1,140 @@
-use anyhow::{Context, Result};
-use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
-use reqwest::{header::HeaderMap, header::HeaderName, header::HeaderValue, Client, StatusCode};
-use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use reqwest::{header::HeaderMap, header::HeaderValue, Client, StatusCode};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tonic::transport::Channel;
+use url::Url;
 
 use crate::config::Config;
-use crate::router::RouteTarget;
+use crate::discovery::ServiceDiscovery;
+use crate::grpc;
+use crate::http_client::ClientSettings;
+use crate::metrics::Metrics;
+use crate::router::{RouteTarget, TargetKind};
 use crate::sqs::WebhookMessage;
+use crate::target_url;
+use crate::telemetry;
+use crate::validation;
 
 pub struct Forwarder {
+    /// Client for routes using the global settings
     client: Client,
+    /// Clients for routes with their own settings, by route name. Routes
+    /// whose settings come out the same share a client.
+    route_clients: HashMap<String, Client>,
+    /// Channels to grpc routes' services, by route name
+    grpc_channels: HashMap<String, Channel>,
+    discovery: Option<Arc<ServiceDiscovery>>,
+    response_max_bytes: usize,
+    metrics: Arc<Metrics>,
+}
+
+/// What a target answered with. The body is cut short at RESPONSE_MAX_BYTES.
+#[derive(Debug)]
+pub struct ForwardResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    pub truncated: bool,
+    pub latency: Duration,
 }
 
 impl Forwarder {
-    pub fn new(_config: &Config) -> Result<Self> {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(60))
-            .connect_timeout(Duration::from_secs(10))
-            .pool_max_idle_per_host(10)
-            .build()
-            .context("Failed to create HTTP client")?;
+    pub fn new<'a>(
+        config: &Config,
+        targets: impl Iterator<Item = &'a RouteTarget>,
+        discovery: Option<Arc<ServiceDiscovery>>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        let client = config.http_client.build()?;
+
+        let mut clients: HashMap<ClientSettings, Client> = HashMap::new();
+        let mut route_clients = HashMap::new();
+        let mut grpc_channels = HashMap::new();
+        for target in targets {
+            if let TargetKind::Grpc(ref grpc) = target.kind {
+                let settings = target.client.or(&config.http_client);
+                let channel = grpc
+                    .channel(&settings)
+                    .with_context(|| format!("Route {}: invalid client settings", target.name))?;
+                grpc_channels.insert(target.name.clone(), channel);
+                continue;
+            }
+            if target.client == ClientSettings::default() {
+                continue;
+            }
+
+            let settings = target.client.or(&config.http_client);
+            let client = match clients.get(&settings) {
+                Some(client) => client.clone(),
+                None => {
+                    let client = settings.build().with_context(|| {
+                        format!("Route {}: invalid client settings", target.name)
+                    })?;
+                    clients.insert(settings, client.clone());
+                    client
+                }
+            };
+            route_clients.insert(target.name.clone(), client);
+        }
+
+        Ok(Forwarder {
+            client,
+            route_clients,
+            grpc_channels,
+            discovery,
+            response_max_bytes: config.response_max_bytes,
+            metrics,
+        })
+    }
+
+    /// Metrics shared with everything delivering through this forwarder
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
 
-        Ok(Forwarder { client })
+    /// The target's URL, or the URL of one of its service's endpoints or
+    /// pool members. Pool members come with their index, to report the
+    /// outcome with.
+    fn base_url(
+        &self,
+        target: &RouteTarget,
+        message: Option<&WebhookMessage>,
+    ) -> Result<(Url, Option<usize>)> {
+        if let Some(ref pool) = target.pool {
+            let (index, url) = pool.select(message);
+            return Ok((url.clone(), Some(index)));
+        }
+
+        let url = match (&target.service, &self.discovery) {
+            (Some(service), Some(discovery)) => discovery.resolve(service)?,
+            (Some(service), None) => bail!("No service discovery for {}", service),
+            (None, _) => Url::parse(&target.url)
+                .with_context(|| format!("Invalid target URL {}", target.url))?,
+        };
+        Ok((url, None))
+    }
+
+    /// Passive health check for pool routes: connection failures, timeouts
+    /// and 5xx responses count against the member
+    fn report<T>(
+        target: &RouteTarget,
+        member: Option<usize>,
+        result: &reqwest::Result<T>,
+        status: impl Fn(&T) -> StatusCode,
+    ) {
+        if let (Some(pool), Some(index)) = (&target.pool, member) {
+            let success = result
+                .as_ref()
+                .is_ok_and(|value| !status(value).is_server_error());
+            pool.report(index, success);
+        }
+    }
+
+    /// The client to reach a target with
+    fn client(&self, target: &RouteTarget) -> &Client {
+        self.route_clients.get(&target.name).unwrap_or(&self.client)
     }
 
     pub async fn forward(
@@ -28,89 +142,360 @@ impl Forwarder {
         message: &WebhookMessage,
         target: &RouteTarget,
         rest_path: &str,
-    ) -> Result<StatusCode> {
-        // Build the target URL
-        let url = format!("{}{}", target.url.trim_end_matches('/'), rest_path);
+    ) -> Result<ForwardResponse> {
+        if let TargetKind::Grpc(ref grpc) = target.kind {
+            let Some(channel) = self.grpc_channels.get(&target.name) else {
+                bail!("No gRPC channel for route {}", target.name);
+            };
+            return grpc::call(
+                channel.clone(),
+                target,
+                grpc,
+                message,
+                self.response_max_bytes,
+            )
+            .await;
+        }
 
-        // Build query string if present
-        let url = if !message.query_string_parameters.is_empty() {
-            let query: Vec<String> = message
-                .query_string_parameters
-                .iter()
-                .map(|(k, v)| format!("{}={}", k, v))
-                .collect();
-            format!("{}?{}", url, query.join("&"))
-        } else {
-            url
-        };
+        // Build the target URL from the route's base URL, path and query
+        let (base, member) = self.base_url(target, Some(message))?;
+        let url = target_url::build(&base, rest_path, &message.query_values(), target.url_rules)?;
 
         // Decode body if base64 encoded
-        let body = if message.is_base64_encoded {
-            match BASE64.decode(&message.body) {
-                Ok(decoded) => decoded,
-                Err(_) => message.body.as_bytes().to_vec(),
+        let body = message.decoded_body()?;
+
+        // Build headers from the webhook's own and the route's
+        let headers = relay_headers(message, target);
+
+        // Build the request with the webhook's own method, keeping the body
+        // whatever the method
+        let method = validation::parse_method(&message.method)?;
+        let mut request = self
+            .client(target)
+            .request(method, url.clone())
+            .headers(headers);
+        if !body.is_empty() {
+            request = request.body(body);
+        }
+
+        // Set timeout from target config
+        let request = request.timeout(Duration::from_secs(target.timeout_seconds));
+
+        // Send the request
+        let started = Instant::now();
+        let result = request.send().await;
+        Self::report(target, member, &result, |r| r.status());
+        let mut response =
+            result.with_context(|| format!("Failed to forward webhook to {}", url))?;
+
+        let status = response.status();
+        let headers = std::mem::take(response.headers_mut());
+
+        // Keep at most response_max_bytes of the body, dropping the rest
+        let mut body = Vec::new();
+        let mut truncated = false;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .with_context(|| format!("Failed to read response from {}", url))?
+        {
+            let room = self.response_max_bytes - body.len();
+            if chunk.len() > room {
+                body.extend_from_slice(&chunk[..room]);
+                truncated = true;
+                break;
             }
-        } else {
-            message.body.as_bytes().to_vec()
-        };
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(ForwardResponse {
+            status,
+            headers,
+            body,
+            truncated,
+            latency: started.elapsed(),
+        })
+    }
 
-        // Build headers
+    /// Forward a pre-encoded batch of webhooks to the target's base URL.
+    /// None of the webhooks' own headers describe the combined body, so the
+    /// batch only carries our own and the route's static headers.
+    pub async fn forward_batch(
+        &self,
+        target: &RouteTarget,
+        body: Vec<u8>,
+        content_type: &'static str,
+        count: usize,
+    ) -> Result<StatusCode> {
         let mut headers = HeaderMap::new();
-        for (key, value) in &message.headers {
-            // Skip hop-by-hop headers and some that shouldn't be forwarded
-            let key_lower = key.to_lowercase();
-            if matches!(
-                key_lower.as_str(),
-                "host"
-                    | "connection"
-                    | "keep-alive"
-                    | "proxy-authenticate"
-                    | "proxy-authorization"
-                    | "te"
-                    | "trailers"
-                    | "transfer-encoding"
-                    | "upgrade"
-                    | "content-length"
-            ) {
-                continue;
-            }
+        headers.insert("Content-Type", HeaderValue::from_static(content_type));
+        headers.insert("X-Webhook-Batch-Size", HeaderValue::from(count));
+        add_relay_headers(&mut headers, target);
 
-            if let (Ok(name), Ok(val)) = (
-                HeaderName::try_from(key.as_str()),
-                HeaderValue::try_from(value.as_str()),
-            ) {
-                headers.insert(name, val);
-            }
+        let (url, member) = self.base_url(target, None)?;
+        let result = self
+            .client(target)
+            .post(url.clone())
+            .headers(headers)
+            .body(body)
+            .timeout(Duration::from_secs(target.timeout_seconds))
+            .send()
+            .await;
+        Self::report(target, member, &result, |r| r.status());
+        let response = result.with_context(|| format!("Failed to forward batch to {}", url))?;
+
+        Ok(response.status())
+    }
+}
+
+#[cfg(test)]
+impl Default for Forwarder {
+    fn default() -> Self {
+        Forwarder {
+            client: Client::new(),
+            route_clients: HashMap::new(),
+            grpc_channels: HashMap::new(),
+            discovery: None,
+            response_max_bytes: 65536,
+            metrics: Default::default(),
         }
+    }
+}
+
+/// The headers a webhook is delivered with: the ones the route's policy lets
+/// through, then our own and the trace context, then the route's static
+/// headers
+pub fn relay_headers(message: &WebhookMessage, target: &RouteTarget) -> HeaderMap {
+    let mut headers = target.headers.forwarded_headers(message);
 
-        // Add our own headers
-        if let Ok(val) = HeaderValue::try_from(&message.source_ip) {
-            headers.insert("X-Forwarded-For", val);
+    if let Ok(val) = HeaderValue::try_from(&message.source_ip) {
+        headers.insert("X-Forwarded-For", val);
+    }
+    if let Some(ref group_id) = message.message_group_id {
+        if let Ok(val) = HeaderValue::try_from(group_id) {
+            headers.insert("X-Message-Group-Id", val);
         }
+    }
+    if let Some(encoding) = message.content_encoding {
         headers.insert(
-            "X-Webhook-Relay",
-            HeaderValue::from_static("webhook-relay/1.0"),
+            "Content-Encoding",
+            HeaderValue::from_static(encoding.as_str()),
+        );
+    }
+    add_relay_headers(&mut headers, target);
+
+    headers
+}
+
+/// Add our own headers and the trace context, then the route's static
+/// headers
+fn add_relay_headers(headers: &mut HeaderMap, target: &RouteTarget) {
+    headers.insert(
+        "X-Webhook-Relay",
+        HeaderValue::from_static("webhook-relay/1.0"),
+    );
+    telemetry::inject(headers);
+    target.headers.add_static(headers);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use axum::http::{HeaderMap as ReceivedHeaders, Method, Uri};
+    use axum::routing::any;
+    use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+    use std::sync::{Arc, Mutex};
+    use tracing::Instrument;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::prelude::*;
+
+    struct Request {
+        method: Method,
+        uri: Uri,
+        headers: ReceivedHeaders,
+        body: Bytes,
+    }
+
+    type Received = Arc<Mutex<Option<Request>>>;
+
+    /// Start a target that records the last request it received
+    async fn target(received: Received) -> RouteTarget {
+        let app = axum::Router::new().route(
+            "/*path",
+            any(
+                move |method: Method, uri: Uri, headers: ReceivedHeaders, body: Bytes| async move {
+                    *received.lock().unwrap() = Some(Request {
+                        method,
+                        uri,
+                        headers,
+                        body,
+                    });
+                    "ok"
+                },
+            ),
         );
 
-        // Build the request
-        let request = match message.method.to_uppercase().as_str() {
-            "GET" => self.client.get(&url).headers(headers),
-            "POST" => self.client.post(&url).headers(headers).body(body),
-            "PUT" => self.client.put(&url).headers(headers).body(body),
-            "PATCH" => self.client.patch(&url).headers(headers).body(body),
-            "DELETE" => self.client.delete(&url).headers(headers),
-            _ => self.client.post(&url).headers(headers).body(body),
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        RouteTarget {
+            name: "test".to_string(),
+            kind: TargetKind::Http,
+            url: format!("http://{}", addr),
+            service: None,
+            pool: None,
+            timeout_seconds: 5,
+            batch: None,
+            accept_encoding: Vec::new(),
+            url_rules: Default::default(),
+            methods: None,
+            headers: Default::default(),
+            client: Default::default(),
+            mirror: None,
+            archive: None,
+        }
+    }
+
+    fn forwarder(response_max_bytes: usize) -> Forwarder {
+        Forwarder {
+            response_max_bytes,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_repeated_values() {
+        let received = Received::default();
+        let target = target(Arc::clone(&received)).await;
+        let forwarder = forwarder(1024);
+
+        let message: WebhookMessage = serde_json::from_value(serde_json::json!({
+            "path": "/webhook/test/hook",
+            "method": "POST",
+            "headers": {"via": "1.1 b"},
+            "multiValueHeaders": {"via": ["1.1 a", "1.1 b"], "x-tag": ["one", "two"]},
+            "multiValueQueryStringParameters": {"id": ["1", "2"], "page": ["3"]},
+            "timestamp": "2025-01-15T10:30:00Z"
+        }))
+        .unwrap();
+
+        let response = forwarder.forward(&message, &target, "/hook").await.unwrap();
+        assert!(response.status.is_success());
+        assert_eq!(response.body, b"ok");
+        assert!(!response.truncated);
+
+        let Request { uri, headers, .. } = received.lock().unwrap().take().unwrap();
+        assert_eq!(uri.query(), Some("id=1&id=2&page=3"));
+
+        let values = |name: &str| -> Vec<&str> {
+            headers
+                .get_all(name)
+                .iter()
+                .map(|v| v.to_str().unwrap())
+                .collect()
         };
+        assert_eq!(values("via"), vec!["1.1 a", "1.1 b"]);
+        assert_eq!(values("x-tag"), vec!["one", "two"]);
+    }
 
-        // Set timeout from target config
-        let request = request.timeout(Duration::from_secs(target.timeout_seconds));
+    #[tokio::test]
+    async fn test_forward_methods() {
+        let received = Received::default();
+        let target = target(Arc::clone(&received)).await;
+        let forwarder = forwarder(1024);
 
-        // Send the request
-        let response = request
-            .send()
+        for (method, body, expected) in [
+            ("get", "{\"query\": 1}", "{\"query\": 1}"),
+            ("DELETE", "{\"id\": 7}", "{\"id\": 7}"),
+            ("OPTIONS", "", ""),
+            ("PROPFIND", "<propfind/>", "<propfind/>"),
+        ] {
+            let message: WebhookMessage = serde_json::from_value(serde_json::json!({
+                "path": "/webhook/test/hook",
+                "method": method,
+                "headers": {},
+                "body": body,
+                "timestamp": "2025-01-15T10:30:00Z"
+            }))
+            .unwrap();
+
+            forwarder.forward(&message, &target, "/hook").await.unwrap();
+
+            let request = received.lock().unwrap().take().unwrap();
+            assert_eq!(request.method.as_str(), method.to_uppercase());
+            assert_eq!(request.body, expected.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_response() {
+        let app = axum::Router::new().route(
+            "/*path",
+            any(|| async { ([("x-reply", "pong")], "x".repeat(100)) }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut target = target(Received::default()).await;
+        target.url = format!("http://{}", addr);
+
+        let message: WebhookMessage = serde_json::from_value(serde_json::json!({
+            "path": "/webhook/test/slash",
+            "method": "POST",
+            "headers": {},
+            "timestamp": "2025-01-15T10:30:00Z"
+        }))
+        .unwrap();
+
+        let response = forwarder(16)
+            .forward(&message, &target, "/slash")
             .await
-            .with_context(|| format!("Failed to forward webhook to {}", url))?;
+            .unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.headers["x-reply"], "pong");
+        assert_eq!(response.body, b"x".repeat(16));
+        assert!(response.truncated);
+    }
 
-        Ok(response.status())
+    #[tokio::test]
+    async fn test_forward_batch_headers() {
+        let received = Received::default();
+        let mut target = target(Arc::clone(&received)).await;
+        target.url.push_str("/ingest");
+        target.headers = crate::header_policy::HeaderPolicy::from_rules(
+            serde_yaml::from_str("add:\n  X-Api-Key: key\nforwarded: true").unwrap(),
+        )
+        .unwrap();
+        let forwarder = forwarder(1024);
+
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::registry()
+                .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test"))),
+        );
+
+        // Batches carry the route's static headers and the batch span's trace
+        // context
+        let span = tracing::info_span!("batch");
+        let trace_id = span.context().span().span_context().trace_id();
+        let status = forwarder
+            .forward_batch(&target, b"[]".to_vec(), "application/json", 2)
+            .instrument(span)
+            .await
+            .unwrap();
+        assert!(status.is_success());
+
+        let Request { headers, .. } = received.lock().unwrap().take().unwrap();
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers["x-webhook-batch-size"], "2");
+        assert_eq!(headers["x-webhook-relay"], "webhook-relay/1.0");
+        assert_eq!(headers["x-api-key"], "key");
+        assert!(headers["traceparent"]
+            .to_str()
+            .unwrap()
+            .starts_with(&format!("00-{}-", trace_id)));
     }
 }
@@ -0,0 +1,444 @@
+use anyhow::{anyhow, bail, Context, Result};
+use axum::http::uri::PathAndQuery;
+use prost::bytes::{Buf, BufMut};
+use prost::Message;
+use reqwest::{header::HeaderMap, StatusCode};
+use serde::Deserialize;
+use std::error::Error as _;
+use std::time::{Duration, Instant};
+use tonic::codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder};
+use tonic::metadata::MetadataMap;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tonic::{Code, Status};
+use url::Url;
+
+use crate::forwarder::{self, ForwardResponse};
+use crate::http_client::ClientSettings;
+use crate::router::RouteTarget;
+use crate::sqs::WebhookMessage;
+use crate::telemetry;
+
+/// Method called when a route does not name one
+const DEFAULT_METHOD: &str = "/webhook_relay.v1.WebhookReceiver/Deliver";
+
+/// A grpc route's service and method, as configured in the routes YAML
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// `http://` for plaintext HTTP/2, `https://` for TLS
+    pub url: String,
+    /// Full method path, `/package.Service/Method`
+    #[serde(default)]
+    pub method: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GrpcTarget {
+    url: Url,
+    method: PathAndQuery,
+}
+
+impl GrpcTarget {
+    pub fn from_config(config: GrpcConfig) -> Result<Self> {
+        let url = Url::parse(&config.url)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https") && url.path() == "/")
+            .ok_or_else(|| anyhow!("invalid URL {}", config.url))?;
+
+        let method = config.method.as_deref().unwrap_or(DEFAULT_METHOD);
+        let valid = method
+            .strip_prefix('/')
+            .and_then(|m| m.split_once('/'))
+            .is_some_and(|(service, name)| {
+                service.contains('.')
+                    && !name.is_empty()
+                    && [service, name].iter().all(|part| {
+                        part.chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+                    })
+            });
+        if !valid {
+            bail!("method must be /package.Service/Method, not {}", method);
+        }
+
+        Ok(GrpcTarget {
+            url,
+            method: PathAndQuery::try_from(method)?,
+        })
+    }
+
+    /// Describes the target in logs
+    pub fn describe(&self) -> String {
+        format!("{}{}", self.url.as_str().trim_end_matches('/'), self.method)
+    }
+
+    /// A channel to the service, connecting on first use. TLS trusts the
+    /// same certificates as the HTTP client built from `settings`.
+    pub fn channel(&self, settings: &ClientSettings) -> Result<Channel> {
+        let mut endpoint = Endpoint::from_shared(self.url.to_string())?;
+        if let Some(seconds) = settings.connect_timeout_seconds {
+            endpoint = endpoint.connect_timeout(Duration::from_secs(seconds));
+        }
+
+        if self.url.scheme() == "https" {
+            // The AWS SDK and reqwest bring in two rustls crypto providers,
+            // so tonic needs one picked for it
+            let _ = rustls::crypto::ring::default_provider().install_default();
+
+            let tls = settings.tls_files()?;
+            let mut config = ClientTlsConfig::new().with_webpki_roots();
+            if let Some(ca) = tls.ca {
+                config = config.ca_certificate(Certificate::from_pem(ca));
+            }
+            if let Some((cert, key)) = tls.identity {
+                config = config.identity(Identity::from_pem(cert, key));
+            }
+            endpoint = endpoint.tls_config(config)?;
+        }
+
+        Ok(endpoint.connect_lazy())
+    }
+}
+
+/// A webhook as sent to gRPC targets; see proto/webhook_relay/v1/webhook.proto
+#[derive(Clone, PartialEq, Message)]
+pub struct Webhook {
+    #[prost(string, tag = "1")]
+    pub route: String,
+    #[prost(string, tag = "2")]
+    pub method: String,
+    #[prost(string, tag = "3")]
+    pub path: String,
+    #[prost(message, repeated, tag = "4")]
+    pub headers: Vec<Header>,
+    #[prost(message, repeated, tag = "5")]
+    pub query: Vec<QueryParameter>,
+    #[prost(bytes = "vec", tag = "6")]
+    pub body: Vec<u8>,
+    #[prost(string, tag = "7")]
+    pub source_ip: String,
+    #[prost(string, tag = "8")]
+    pub received_at: String,
+    #[prost(string, tag = "9")]
+    pub correlation_id: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Header {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct QueryParameter {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+/// Encodes requests with prost and keeps responses as raw bytes, whatever
+/// message the method returns
+#[derive(Debug, Default)]
+struct WebhookCodec;
+
+impl Codec for WebhookCodec {
+    type Encode = Webhook;
+    type Decode = Vec<u8>;
+    type Encoder = WebhookCodec;
+    type Decoder = WebhookCodec;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        WebhookCodec
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        WebhookCodec
+    }
+}
+
+impl Encoder for WebhookCodec {
+    type Item = Webhook;
+    type Error = Status;
+
+    fn encode(&mut self, item: Webhook, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        dst.reserve(item.encoded_len());
+        item.encode(dst)
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+impl Decoder for WebhookCodec {
+    type Item = Vec<u8>;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<Vec<u8>>, Status> {
+        let mut message = Vec::with_capacity(src.remaining());
+        message.put(src);
+        Ok(Some(message))
+    }
+}
+
+/// Call the route's method with a webhook. A status from the service comes
+/// back as the equivalent HTTP status, so it is handled like an HTTP
+/// response; failing to reach the service or a timeout is an error, and the
+/// webhook is retried.
+pub async fn call(
+    channel: Channel,
+    target: &RouteTarget,
+    grpc: &GrpcTarget,
+    message: &WebhookMessage,
+    response_max_bytes: usize,
+) -> Result<ForwardResponse> {
+    let webhook = Webhook {
+        route: target.name.clone(),
+        method: message.method.clone(),
+        path: message.path.clone(),
+        headers: forwarder::relay_headers(message, target)
+            .iter()
+            .map(|(name, value)| Header {
+                name: name.to_string(),
+                value: String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            })
+            .collect(),
+        query: message
+            .query_values()
+            .into_iter()
+            .map(|(name, value)| QueryParameter {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect(),
+        body: message.decoded_body()?,
+        source_ip: message.source_ip.clone(),
+        received_at: message.timestamp.clone(),
+        correlation_id: message.correlation_id.clone().unwrap_or_default(),
+    };
+
+    // The trace context and the route's own headers, such as credentials,
+    // also go in the metadata
+    let mut metadata = HeaderMap::new();
+    telemetry::inject(&mut metadata);
+    target.headers.add_static(&mut metadata);
+    let request = tonic::Request::from_parts(
+        MetadataMap::from_headers(metadata),
+        Default::default(),
+        webhook,
+    );
+
+    let started = Instant::now();
+    let call = async {
+        let mut client = tonic::client::Grpc::new(channel);
+        client
+            .ready()
+            .await
+            .map_err(|e| Status::from_error(e.into()))?;
+        client
+            .unary(request, grpc.method.clone(), WebhookCodec)
+            .await
+    };
+    let result = tokio::time::timeout(Duration::from_secs(target.timeout_seconds), call)
+        .await
+        .map_err(|_| anyhow!("Timed out calling {}", grpc.describe()))?;
+    let latency = started.elapsed();
+
+    let (status, headers, mut body) = match result {
+        Ok(response) => {
+            let (metadata, body, _) = response.into_parts();
+            (StatusCode::OK, metadata.into_headers(), body)
+        }
+        // Statuses raised by the client itself, rather than sent by the
+        // service, mean the service could not be reached
+        Err(status) if status.source().is_some() => {
+            return Err(anyhow!(status))
+                .with_context(|| format!("Failed to call {}", grpc.describe()));
+        }
+        Err(status) => {
+            let code = http_status(status.code());
+            let body = status.message().as_bytes().to_vec();
+            (code, status.metadata().clone().into_headers(), body)
+        }
+    };
+
+    let truncated = body.len() > response_max_bytes;
+    body.truncate(response_max_bytes);
+
+    Ok(ForwardResponse {
+        status,
+        headers,
+        body,
+        truncated,
+        latency,
+    })
+}
+
+/// The HTTP status equivalent to a gRPC status code
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Cancelled => StatusCode::from_u16(499).unwrap(),
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{TargetKind, WebhookRouter};
+    use axum::body::{Body, Bytes};
+    use axum::http::{HeaderMap as ReceivedHeaders, Uri};
+    use axum::routing::post;
+    use http_body_util::{BodyExt, Full};
+    use std::sync::{Arc, Mutex};
+
+    type Received = Arc<Mutex<Vec<(Uri, ReceivedHeaders, Webhook)>>>;
+
+    /// Start a gRPC service over h2c that records each webhook. Paths ending
+    /// in /Missing answer NOT_FOUND; others answer with a two byte message.
+    async fn service(received: Received) -> String {
+        let app = axum::Router::new().route(
+            "/*method",
+            post(
+                move |uri: Uri, headers: ReceivedHeaders, body: Bytes| async move {
+                    let webhook = Webhook::decode(&body[5..]).unwrap();
+                    received
+                        .lock()
+                        .unwrap()
+                        .push((uri.clone(), headers, webhook));
+
+                    let mut trailers = ReceivedHeaders::new();
+                    let frame: &[u8] = if uri.path().ends_with("/Missing") {
+                        trailers.insert("grpc-status", "5".parse().unwrap());
+                        trailers.insert("grpc-message", "no such run".parse().unwrap());
+                        b""
+                    } else {
+                        trailers.insert("grpc-status", "0".parse().unwrap());
+                        b"\0\0\0\0\x02\x08\x01"
+                    };
+                    let body = Full::new(Bytes::from_static(frame))
+                        .with_trailers(async move { Some(Ok(trailers)) });
+                    axum::http::Response::builder()
+                        .header("content-type", "application/grpc")
+                        .body(Body::new(body))
+                        .unwrap()
+                },
+            ),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_grpc_call() {
+        let received = Received::default();
+        let url = service(Arc::clone(&received)).await;
+
+        let router = WebhookRouter::from_yaml(&format!(
+            r#"
+routes:
+  dagster:
+    headers:
+      deny: ["cookie"]
+      add:
+        authorization: "Bearer relay"
+    grpc:
+      url: "{url}"
+  missing:
+    grpc:
+      url: "{url}"
+      method: /runs.v1.Runs/Missing
+"#
+        ))
+        .unwrap();
+
+        let message: WebhookMessage = serde_json::from_value(serde_json::json!({
+            "path": "/webhook/dagster/runs",
+            "method": "POST",
+            "headers": {"X-Run": "42", "Cookie": "secret"},
+            "queryStringParameters": {"ref": "main"},
+            "body": "{\"run\": 42}",
+            "timestamp": "2025-01-15T10:30:00Z",
+        }))
+        .unwrap();
+
+        let (target, _) = router.route(&message.path, None).unwrap();
+        let TargetKind::Grpc(ref grpc) = target.kind else {
+            panic!("expected a grpc target");
+        };
+        assert_eq!(target.url, format!("{}{}", url, DEFAULT_METHOD));
+
+        let channel = grpc.channel(&ClientSettings::default()).unwrap();
+        let response = call(channel, target, grpc, &message, 1024).await.unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body, b"\x08\x01");
+
+        let (uri, headers, webhook) = received.lock().unwrap().remove(0);
+        assert_eq!(uri.path(), DEFAULT_METHOD);
+        assert_eq!(headers["authorization"], "Bearer relay");
+        assert_eq!(webhook.route, "dagster");
+        assert_eq!(webhook.body, b"{\"run\": 42}");
+        assert_eq!(webhook.query[0].value, "main");
+        assert!(webhook
+            .headers
+            .iter()
+            .any(|h| h.name == "x-run" && h.value == "42"));
+        assert!(!webhook.headers.iter().any(|h| h.name == "cookie"));
+
+        let (target, _) = router.route("/webhook/missing/x", None).unwrap();
+        let TargetKind::Grpc(ref grpc) = target.kind else {
+            panic!("expected a grpc target");
+        };
+        let channel = grpc.channel(&ClientSettings::default()).unwrap();
+        let response = call(channel, target, grpc, &message, 1024).await.unwrap();
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert_eq!(response.body, b"no such run");
+
+        // A service that cannot be reached is an error, so it is retried
+        let closed = GrpcTarget::from_config(GrpcConfig {
+            url: "http://127.0.0.1:1".to_string(),
+            method: None,
+        })
+        .unwrap();
+        let channel = closed.channel(&ClientSettings::default()).unwrap();
+        assert!(call(channel, target, &closed, &message, 1024)
+            .await
+            .is_err());
+
+        let tls = GrpcTarget::from_config(GrpcConfig {
+            url: "https://runs.example.com:8443".to_string(),
+            method: Some("/runs.v1.Runs/Start".to_string()),
+        })
+        .unwrap();
+        assert_eq!(
+            tls.describe(),
+            "https://runs.example.com:8443/runs.v1.Runs/Start"
+        );
+        tls.channel(&ClientSettings::default()).unwrap();
+
+        for bad in [
+            "url: \"ftp://runs.example.com\"",
+            "url: \"http://runs.example.com/api\"",
+            "url: \"http://runs.example.com\"\n      method: Deliver",
+            "url: \"http://runs.example.com\"\n    batch: {}",
+        ] {
+            let yaml = format!("routes:\n  dagster:\n    grpc:\n      {}\n", bad);
+            assert!(WebhookRouter::from_yaml(&yaml).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use anyhow::{anyhow, Result};
+use indexmap::IndexMap;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+
+use crate::sqs::WebhookMessage;
+
+/// Hop-by-hop and framing headers, which are never forwarded
+const HOP_BY_HOP: &[&str] = &[
+    "host",
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailers",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+/// A route's `headers` section as written in the routes file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRules {
+    /// Only forward headers matching one of these patterns
+    #[serde(default)]
+    allow: Option<Vec<String>>,
+    /// Never forward headers matching one of these patterns
+    #[serde(default)]
+    deny: Vec<String>,
+    /// Forward a header under a different name
+    #[serde(default)]
+    rename: IndexMap<String, String>,
+    /// Headers to set on every request, replacing any forwarded value
+    #[serde(default)]
+    add: IndexMap<String, String>,
+    /// Add `X-Forwarded-Proto/Host/Path` and `Forwarded` headers
+    #[serde(default)]
+    forwarded: bool,
+}
+
+/// Which of a webhook's headers reach the target, and under what names
+#[derive(Debug, Clone, Default)]
+pub struct HeaderPolicy {
+    allow: Option<Vec<String>>,
+    deny: Vec<String>,
+    rename: Vec<(HeaderName, HeaderName)>,
+    add: HeaderMap,
+    forwarded: bool,
+}
+
+impl HeaderPolicy {
+    pub fn from_rules(rules: HeaderRules) -> Result<Self> {
+        let lowercase = |patterns: Vec<String>| -> Vec<String> {
+            patterns.iter().map(|p| p.to_ascii_lowercase()).collect()
+        };
+
+        let mut rename = Vec::new();
+        for (from, to) in rules.rename {
+            rename.push((header_name(&from)?, header_name(&to)?));
+        }
+
+        let mut add = HeaderMap::new();
+        for (name, value) in rules.add {
+            let value = HeaderValue::try_from(&value)
+                .map_err(|_| anyhow!("invalid value for header {:?}", name))?;
+            add.insert(header_name(&name)?, value);
+        }
+
+        Ok(HeaderPolicy {
+            allow: rules.allow.map(lowercase),
+            deny: lowercase(rules.deny),
+            rename,
+            add,
+            forwarded: rules.forwarded,
+        })
+    }
+
+    /// Whether a header received with the webhook should be forwarded
+    fn passes(&self, name: &HeaderName) -> bool {
+        let name = name.as_str();
+        if HOP_BY_HOP.contains(&name) {
+            return false;
+        }
+        if let Some(ref allow) = self.allow {
+            if !allow.iter().any(|p| wildcard_match(p, name)) {
+                return false;
+            }
+        }
+        !self.deny.iter().any(|p| wildcard_match(p, name))
+    }
+
+    /// The webhook's headers that pass the policy, renamed, in order. Repeated
+    /// headers keep every value.
+    pub fn forwarded_headers(&self, message: &WebhookMessage) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (key, value) in message.header_values() {
+            let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value))
+            else {
+                continue;
+            };
+            if !self.passes(&name) {
+                continue;
+            }
+
+            let name = self
+                .rename
+                .iter()
+                .find(|(from, _)| *from == name)
+                .map_or(name, |(_, to)| to.clone());
+            headers.append(name, value);
+        }
+
+        if self.forwarded {
+            add_forwarded(message, &mut headers);
+        }
+
+        headers
+    }
+
+    /// Set the route's static headers, replacing any existing values
+    pub fn add_static(&self, headers: &mut HeaderMap) {
+        for (name, value) in &self.add {
+            headers.insert(name, value.clone());
+        }
+    }
+}
+
+fn header_name(name: &str) -> Result<HeaderName> {
+    HeaderName::try_from(name).map_err(|_| anyhow!("invalid header name {:?}", name))
+}
+
+/// Match a lowercase header name against a pattern where `*` matches any
+/// run of characters
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Describe the original request with `X-Forwarded-*` and RFC 7239
+/// `Forwarded` headers
+fn add_forwarded(message: &WebhookMessage, headers: &mut HeaderMap) {
+    let received = |name: &str| {
+        message
+            .header_values()
+            .into_iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.to_string())
+    };
+
+    // The ingestion endpoints only accept HTTPS, but API Gateway passes on
+    // the scheme it saw when it can
+    let proto = received("x-forwarded-proto").unwrap_or_else(|| "https".to_string());
+    let host = received("host");
+
+    let mut forwarded = Vec::new();
+    if !message.source_ip.is_empty() {
+        let node = if message.source_ip.contains(':') {
+            format!("[{}]", message.source_ip)
+        } else {
+            message.source_ip.clone()
+        };
+        forwarded.push(format!("for={}", forwarded_value(&node)));
+    }
+    if let Some(ref host) = host {
+        forwarded.push(format!("host={}", forwarded_value(host)));
+    }
+    forwarded.push(format!("proto={}", forwarded_value(&proto)));
+
+    let mut set = |name: &'static str, value: &str| {
+        if let Ok(value) = HeaderValue::try_from(value) {
+            headers.insert(name, value);
+        }
+    };
+    set("X-Forwarded-Proto", &proto);
+    if let Some(ref host) = host {
+        set("X-Forwarded-Host", host);
+    }
+    set("X-Forwarded-Path", &message.path);
+    set("Forwarded", &forwarded.join(";"));
+}
+
+/// A `Forwarded` parameter value: a token as-is, anything else quoted
+fn forwarded_value(value: &str) -> String {
+    let token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(yaml: &str) -> HeaderPolicy {
+        HeaderPolicy::from_rules(serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    fn message() -> WebhookMessage {
+        serde_json::from_value(serde_json::json!({
+            "path": "/webhook/gitea/push",
+            "method": "POST",
+            "headers": {
+                "Host": "hooks.example.com",
+                "Authorization": "Bearer secret",
+                "Cookie": "session=1",
+                "Content-Type": "application/json",
+                "X-Gitea-Event": "push",
+                "X-Gitea-Signature": "abc",
+                "Connection": "keep-alive",
+            },
+            "timestamp": "2025-01-15T10:30:00Z",
+            "sourceIp": "2001:db8::1",
+        }))
+        .unwrap()
+    }
+
+    fn names(headers: &HeaderMap) -> Vec<&str> {
+        headers.keys().map(|k| k.as_str()).collect()
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("x-gitea-*", "x-gitea-event"));
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("x-*-signature", "x-hub-signature"));
+        assert!(wildcard_match("cookie", "cookie"));
+        assert!(!wildcard_match("cookie", "set-cookie"));
+        assert!(!wildcard_match("x-*-signature", "x-signature"));
+    }
+
+    #[test]
+    fn test_allow_and_deny() {
+        let headers = policy("{}").forwarded_headers(&message());
+        assert_eq!(
+            names(&headers),
+            vec![
+                "authorization",
+                "cookie",
+                "content-type",
+                "x-gitea-event",
+                "x-gitea-signature"
+            ]
+        );
+
+        let headers =
+            policy("deny: [authorization, cookie, x-gitea-sig*]").forwarded_headers(&message());
+        assert_eq!(names(&headers), vec!["content-type", "x-gitea-event"]);
+
+        // Hop-by-hop headers stay out even when allowed
+        let headers = policy("allow: [Content-Type, x-gitea-*, connection]\ndeny: [x-gitea-event]")
+            .forwarded_headers(&message());
+        assert_eq!(names(&headers), vec!["content-type", "x-gitea-signature"]);
+    }
+
+    #[test]
+    fn test_rename_and_add() {
+        let policy = policy(
+            r#"
+allow: [content-type, x-gitea-*]
+rename:
+  X-Gitea-Signature: X-Hub-Signature-256
+add:
+  X-Gitea-Event: relayed
+  X-Api-Key: key
+"#,
+        );
+
+        let mut headers = policy.forwarded_headers(&message());
+        policy.add_static(&mut headers);
+
+        assert_eq!(headers["x-hub-signature-256"], "abc");
+        assert_eq!(headers["x-gitea-event"], "relayed");
+        assert_eq!(headers["x-api-key"], "key");
+        assert!(!headers.contains_key("x-gitea-signature"));
+
+        let invalid = serde_yaml::from_str("add: {\"bad header\": x}").unwrap();
+        assert!(HeaderPolicy::from_rules(invalid).is_err());
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let headers = policy("forwarded: true").forwarded_headers(&message());
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["x-forwarded-host"], "hooks.example.com");
+        assert_eq!(headers["x-forwarded-path"], "/webhook/gitea/push");
+        assert_eq!(
+            headers["forwarded"],
+            "for=\"[2001:db8::1]\";host=hooks.example.com;proto=https"
+        );
+
+        assert!(!policy("{}")
+            .forwarded_headers(&message())
+            .contains_key("forwarded"));
+    }
+}
@@ -19,9 +19,17 @@ pub async fn liveness() -> impl IntoResponse {
     })
 }
 
-/// Readiness probe - checks SQS connectivity
-pub async fn readiness(sqs: Arc<SqsConsumer>) -> impl IntoResponse {
-    match sqs.check_connectivity().await {
+/// Readiness probe - checks SQS connectivity for every queue
+pub async fn readiness(queues: Arc<Vec<Arc<SqsConsumer>>>) -> impl IntoResponse {
+    let mut result = Ok(());
+    for queue in queues.iter() {
+        if let Err(e) = queue.check_connectivity().await {
+            result = Err(format!("{}: {}", queue.name(), e));
+            break;
+        }
+    }
+
+    match result {
         Ok(_) => (
             StatusCode::OK,
             Json(HealthResponse {
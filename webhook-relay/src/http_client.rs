@@ -0,0 +1,189 @@
+use anyhow::{bail, Context, Result};
+use reqwest::{redirect, Certificate, Client, Identity, Proxy};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// HTTP client options. Unset fields on a route fall back to the global
+/// settings from the environment.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientSettings {
+    pub connect_timeout_seconds: Option<u64>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub pool_idle_timeout_seconds: Option<u64>,
+    /// Speak HTTP/2 without negotiating it first (h2c for plain HTTP)
+    pub http2_prior_knowledge: Option<bool>,
+    /// Redirects to follow; 0 returns redirect responses to the relay as-is
+    pub max_redirects: Option<usize>,
+    /// Outbound proxy URL; an empty string connects directly
+    pub proxy: Option<String>,
+    /// Hostnames to connect to at a fixed address instead of resolving them
+    #[serde(default)]
+    pub resolve: BTreeMap<String, IpAddr>,
+    /// PEM CA certificates trusted besides the built-in roots
+    pub ca_path: Option<String>,
+    /// PEM client certificate and key, for targets that require mutual TLS
+    pub client_cert_path: Option<String>,
+    pub client_key_path: Option<String>,
+}
+
+/// PEM files read from a set of client settings
+pub struct TlsFiles {
+    pub ca: Option<Vec<u8>>,
+    pub identity: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ClientSettings {
+    /// These settings with unset fields taken from `global`. Resolve
+    /// overrides are combined, with the route's taking precedence.
+    pub fn or(&self, global: &ClientSettings) -> ClientSettings {
+        let mut resolve = global.resolve.clone();
+        resolve.extend(self.resolve.clone());
+
+        ClientSettings {
+            connect_timeout_seconds: self
+                .connect_timeout_seconds
+                .or(global.connect_timeout_seconds),
+            pool_max_idle_per_host: self
+                .pool_max_idle_per_host
+                .or(global.pool_max_idle_per_host),
+            pool_idle_timeout_seconds: self
+                .pool_idle_timeout_seconds
+                .or(global.pool_idle_timeout_seconds),
+            http2_prior_knowledge: self.http2_prior_knowledge.or(global.http2_prior_knowledge),
+            max_redirects: self.max_redirects.or(global.max_redirects),
+            proxy: self.proxy.clone().or_else(|| global.proxy.clone()),
+            resolve,
+            ca_path: self.ca_path.clone().or_else(|| global.ca_path.clone()),
+            client_cert_path: self
+                .client_cert_path
+                .clone()
+                .or_else(|| global.client_cert_path.clone()),
+            client_key_path: self
+                .client_key_path
+                .clone()
+                .or_else(|| global.client_key_path.clone()),
+        }
+    }
+
+    /// Read the CA and client certificate files. Shared by HTTP and gRPC
+    /// targets, so both trust the same certificates.
+    pub fn tls_files(&self) -> Result<TlsFiles> {
+        let read =
+            |path: &str| std::fs::read(path).with_context(|| format!("Failed to read {}", path));
+
+        let ca = self.ca_path.as_deref().map(read).transpose()?;
+        let identity = match (&self.client_cert_path, &self.client_key_path) {
+            (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+            (None, None) => None,
+            _ => bail!("client_cert_path and client_key_path must be set together"),
+        };
+        Ok(TlsFiles { ca, identity })
+    }
+
+    /// Build a client with these settings. Request timeouts are set per
+    /// request from the route's `timeout_seconds`.
+    pub fn build(&self) -> Result<Client> {
+        let mut builder = Client::builder();
+
+        if let Some(seconds) = self.connect_timeout_seconds {
+            builder = builder.connect_timeout(Duration::from_secs(seconds));
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if let Some(seconds) = self.pool_idle_timeout_seconds {
+            builder = builder.pool_idle_timeout(Duration::from_secs(seconds));
+        }
+        if self.http2_prior_knowledge == Some(true) {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(max) = self.max_redirects {
+            builder = builder.redirect(match max {
+                0 => redirect::Policy::none(),
+                max => redirect::Policy::limited(max),
+            });
+        }
+        match self.proxy.as_deref() {
+            Some("") => builder = builder.no_proxy(),
+            Some(url) => {
+                builder = builder
+                    .proxy(Proxy::all(url).with_context(|| format!("Invalid proxy URL {}", url))?);
+            }
+            None => {}
+        }
+        for (host, ip) in &self.resolve {
+            // The port is ignored; the URL's port is used
+            builder = builder.resolve(host, SocketAddr::new(*ip, 0));
+        }
+
+        let tls = self.tls_files()?;
+        if let Some(ca) = tls.ca {
+            for cert in Certificate::from_pem_bundle(&ca).context("Invalid CA certificate")? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if let Some((cert, key)) = tls.identity {
+            let pem = [cert, key].join(&b'\n');
+            builder =
+                builder.identity(Identity::from_pem(&pem).context("Invalid client certificate")?);
+        }
+
+        builder.build().context("Failed to create HTTP client")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_or() {
+        let global = ClientSettings {
+            connect_timeout_seconds: Some(10),
+            pool_max_idle_per_host: Some(10),
+            max_redirects: Some(10),
+            proxy: Some("http://proxy.internal:3128".to_string()),
+            resolve: BTreeMap::from([("a.example.com".to_string(), [10, 0, 0, 1].into())]),
+            ..Default::default()
+        };
+
+        let route: ClientSettings = serde_yaml::from_str(
+            r#"
+connect_timeout_seconds: 2
+max_redirects: 0
+proxy: ""
+resolve:
+  a.example.com: 10.0.0.2
+  b.example.com: "fd00::2"
+"#,
+        )
+        .unwrap();
+
+        let merged = route.or(&global);
+        assert_eq!(merged.connect_timeout_seconds, Some(2));
+        assert_eq!(merged.pool_max_idle_per_host, Some(10));
+        assert_eq!(merged.max_redirects, Some(0));
+        assert_eq!(merged.proxy.as_deref(), Some(""));
+        assert_eq!(merged.resolve["a.example.com"].to_string(), "10.0.0.2");
+        assert_eq!(merged.resolve["b.example.com"].to_string(), "fd00::2");
+        merged.build().unwrap();
+
+        // Routes without settings share the global configuration
+        assert_eq!(ClientSettings::default().or(&global), global);
+
+        let bad_proxy = ClientSettings {
+            proxy: Some("not a url".to_string()),
+            ..Default::default()
+        };
+        assert!(bad_proxy.build().is_err());
+
+        let cert_only = ClientSettings {
+            client_cert_path: Some("/etc/webhook-relay/tls.crt".to_string()),
+            ..Default::default()
+        };
+        assert!(cert_only.build().is_err());
+    }
+}
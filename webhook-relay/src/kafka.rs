@@ -0,0 +1,227 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::DateTime;
+use rdkafka::config::ClientConfig;
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use serde::Deserialize;
+use std::time::Duration;
+use url::form_urlencoded;
+
+use crate::config::KafkaConfig;
+use crate::forwarder;
+use crate::message_key::MessageKey;
+use crate::router::RouteTarget;
+use crate::sqs::WebhookMessage;
+
+/// A kafka route's topic and record key, as configured in the routes YAML
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TopicConfig {
+    pub topic: String,
+    /// `header:<name>` or `body:<json.path>`; records without one are
+    /// spread over the topic's partitions
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct KafkaTarget {
+    pub topic: String,
+    key: Option<MessageKey>,
+}
+
+impl KafkaTarget {
+    pub fn from_config(config: TopicConfig) -> Result<Self> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-');
+        if config.topic.is_empty() || config.topic.len() > 249 || !config.topic.chars().all(valid) {
+            bail!("invalid topic {:?}", config.topic);
+        }
+
+        let key = match config.key {
+            Some(key) => Some(MessageKey::parse(&key).context("invalid key")?),
+            None => None,
+        };
+
+        Ok(KafkaTarget {
+            topic: config.topic,
+            key,
+        })
+    }
+
+    fn key(&self, message: &WebhookMessage) -> Option<String> {
+        self.key.as_ref()?.value(message)
+    }
+}
+
+/// Publishes webhooks to Kafka with an idempotent producer, so retries after
+/// a lost acknowledgement do not write a record twice
+pub struct KafkaPublisher {
+    producer: FutureProducer,
+    queue_timeout: Duration,
+}
+
+impl KafkaPublisher {
+    pub fn new(config: &KafkaConfig) -> Result<Self> {
+        let Some(ref brokers) = config.brokers else {
+            bail!("KAFKA_BROKERS is required for kafka routes");
+        };
+
+        let mut client = ClientConfig::new();
+        client
+            .set("bootstrap.servers", brokers)
+            .set("client.id", &config.client_id)
+            .set("enable.idempotence", "true")
+            .set("acks", "all")
+            .set("compression.type", &config.compression)
+            .set(
+                "message.timeout.ms",
+                (config.delivery_timeout_seconds * 1000).to_string(),
+            )
+            .set("security.protocol", &config.security_protocol);
+        if let Some(ref path) = config.ca_path {
+            client.set("ssl.ca.location", path);
+        }
+        if let Some(ref mechanism) = config.sasl_mechanism {
+            client.set("sasl.mechanism", mechanism);
+        }
+        if let Some(ref username) = config.sasl_username {
+            client.set("sasl.username", username);
+        }
+        if let Some(ref password) = config.sasl_password {
+            client.set("sasl.password", password);
+        }
+
+        let producer = client.create().context("Failed to create Kafka producer")?;
+
+        Ok(KafkaPublisher {
+            producer,
+            queue_timeout: Duration::from_secs(config.delivery_timeout_seconds),
+        })
+    }
+
+    /// Publish a webhook's body to the route's topic, returning once every
+    /// in-sync replica has it. Returns the record's partition and offset.
+    pub async fn publish(
+        &self,
+        target: &RouteTarget,
+        kafka: &KafkaTarget,
+        message: &WebhookMessage,
+    ) -> Result<(i32, i64)> {
+        let body = message.decoded_body()?;
+        let key = kafka.key(message);
+
+        let mut headers = OwnedHeaders::new();
+        for (name, value) in record_headers(message, target) {
+            headers = headers.insert(Header {
+                key: &name,
+                value: Some(&value),
+            });
+        }
+
+        let mut record = FutureRecord::<String, Vec<u8>>::to(&kafka.topic)
+            .payload(&body)
+            .headers(headers);
+        if let Some(ref key) = key {
+            record = record.key(key);
+        }
+        if let Ok(received) = DateTime::parse_from_rfc3339(&message.timestamp) {
+            record = record.timestamp(received.timestamp_millis());
+        }
+
+        self.producer
+            .send(record, self.queue_timeout)
+            .await
+            .map_err(|(e, _)| anyhow!("Failed to publish webhook to {}: {}", kafka.topic, e))
+    }
+}
+
+/// The headers an HTTP target would get, plus the webhook's method, path
+/// and query string, which a record has nowhere else to carry
+fn record_headers(message: &WebhookMessage, target: &RouteTarget) -> Vec<(String, Vec<u8>)> {
+    let mut headers: Vec<(String, Vec<u8>)> = forwarder::relay_headers(message, target)
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+        .collect();
+
+    headers.push((
+        "x-webhook-method".to_string(),
+        message.method.clone().into(),
+    ));
+    headers.push(("x-webhook-path".to_string(), message.path.clone().into()));
+
+    let query = message.query_values();
+    if !query.is_empty() {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(query)
+            .finish();
+        headers.push(("x-webhook-query".to_string(), query.into()));
+    }
+
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{TargetKind, WebhookRouter};
+
+    #[test]
+    fn test_kafka_target() {
+        let router = WebhookRouter::from_yaml(
+            r#"
+routes:
+  github:
+    kafka:
+      topic: webhooks.github
+      key: header:X-GitHub-Delivery
+    headers:
+      deny: ["cookie"]
+"#,
+        )
+        .unwrap();
+        let (target, _) = router.route("/webhook/github/push", None).unwrap();
+        assert_eq!(target.url, "kafka://webhooks.github");
+        let TargetKind::Kafka(ref kafka) = target.kind else {
+            panic!("expected a kafka target");
+        };
+
+        let message: WebhookMessage = serde_json::from_value(serde_json::json!({
+            "path": "/webhook/github/push",
+            "method": "POST",
+            "headers": {"X-GitHub-Delivery": "d-1", "Cookie": "a=1"},
+            "queryStringParameters": {"ref": "main"},
+            "body": "{}",
+            "timestamp": "2025-01-15T10:30:00Z",
+            "sourceIp": "192.0.2.1",
+        }))
+        .unwrap();
+        assert_eq!(kafka.key(&message).as_deref(), Some("d-1"));
+
+        let headers = record_headers(&message, target);
+        let value = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| String::from_utf8(v.clone()).unwrap())
+        };
+        assert_eq!(value("x-github-delivery").as_deref(), Some("d-1"));
+        assert_eq!(value("cookie"), None);
+        assert_eq!(value("x-forwarded-for").as_deref(), Some("192.0.2.1"));
+        assert_eq!(
+            value("x-webhook-path").as_deref(),
+            Some("/webhook/github/push")
+        );
+        assert_eq!(value("x-webhook-query").as_deref(), Some("ref=main"));
+
+        for bad in [
+            "kafka:\n      topic: \"\"",
+            "kafka:\n      topic: \"bad topic\"",
+            "kafka:\n      topic: events\n      key: query:id",
+            "kafka:\n      topic: events\n    url: \"https://example.com\"",
+            "kafka:\n      topic: events\n    batch: {}",
+        ] {
+            let yaml = format!("routes:\n  github:\n    {}\n", bad);
+            assert!(WebhookRouter::from_yaml(&yaml).is_err(), "{}", bad);
+        }
+    }
+}
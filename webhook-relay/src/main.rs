@@ -1,68 +1,228 @@
+mod archive;
+mod batcher;
+mod claim_check;
+mod compression;
 mod config;
+mod delivery_log;
+mod discovery;
+mod envelope;
+mod exec;
 mod forwarder;
+mod grpc;
+mod header_policy;
 mod health;
+mod http_client;
+mod kafka;
+mod message_key;
 mod metrics;
+mod mirror;
+mod object_store;
+mod pool;
+mod redis_target;
 mod router;
+mod scheduler;
 mod sqs;
+mod target_url;
+mod telemetry;
+mod validation;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use aws_sdk_sqs::types::Message;
 use axum::{routing::get, Router};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tracing::info;
-use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use tracing::{info, info_span, Instrument};
 
+use crate::archive::Archiver;
+use crate::batcher::Batcher;
+use crate::claim_check::ClaimCheckStore;
 use crate::config::Config;
+use crate::delivery_log::{DeliveryLog, DeliveryRecord};
+use crate::discovery::ServiceDiscovery;
+use crate::envelope::MessageDecoder;
+use crate::exec::ExecTarget;
 use crate::forwarder::Forwarder;
-use crate::router::WebhookRouter;
+use crate::kafka::{KafkaPublisher, KafkaTarget};
+use crate::metrics::Metrics;
+use crate::redis_target::{RedisPublisher, RedisTarget};
+use crate::router::{RouteTarget, TargetKind, WebhookRouter};
+use crate::scheduler::Scheduler;
 use crate::sqs::SqsConsumer;
+use crate::validation::MessageError;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize logging
-    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-
-    tracing_subscriber::registry()
-        .with(fmt::layer().with_target(true))
-        .with(filter)
-        .init();
+    // Initialize logging, and trace export when a collector is configured
+    let tracer_provider = telemetry::init()?;
 
     info!("Starting webhook-relay...");
 
     // Load configuration
     let config = Config::from_env()?;
     info!("Configuration loaded");
-    info!("  SQS Queue: {}", config.sqs_queue_url);
+    for queue in &config.queues {
+        info!(
+            "  SQS Queue: {} ({}, priority: {}, weight: {}, concurrency: {})",
+            queue.name, queue.url, queue.priority, queue.weight, queue.concurrency
+        );
+    }
+    info!("  Max Concurrency: {}", config.max_concurrency);
     info!("  HTTP Port: {}", config.http_port);
     info!("  Metrics Port: {}", config.metrics_port);
 
     // Load routing configuration
     let router = WebhookRouter::from_file(&config.route_config_path)?;
-    let router = Arc::new(router);
     info!("Routes loaded: {} configured", router.route_count());
 
+    // A FIFO queue delivers each message group one message at a time, so a
+    // batch would hold at most one webhook per group and always wait out
+    // max_wait_ms
+    if let Some(fifo) = config.queues.iter().find(|q| q.url.ends_with(".fifo")) {
+        if let Some(route) = router.batch_route() {
+            bail!(
+                "Route {} batches webhooks, which is not supported with FIFO queue {}",
+                route,
+                fifo.name
+            );
+        }
+    }
+
+    for queue in &config.queues {
+        if let Some(ref route) = queue.default_route {
+            if !router.has_route(route) {
+                bail!(
+                    "Queue {}: default route {} does not exist",
+                    queue.name,
+                    route
+                );
+            }
+        }
+    }
+
+    // Resolve routes that reference Kubernetes Services through their
+    // EndpointSlices
+    let discovery = ServiceDiscovery::new(&config.discovery, router.targets())?;
+    if let Some(ref discovery) = discovery {
+        discovery.refresh_all().await;
+        tokio::spawn(Arc::clone(discovery).run());
+        info!("Service discovery enabled");
+    }
+
+    // Create the metrics registry shared by every part of the relay
+    let metrics = Arc::new(Metrics::new(&config.metrics)?);
+
     // Create HTTP client for forwarding
-    let forwarder = Forwarder::new(&config)?;
+    let forwarder = Forwarder::new(&config, router.targets(), discovery, Arc::clone(&metrics))?;
     let forwarder = Arc::new(forwarder);
 
-    // Create SQS consumer
-    let sqs_consumer = SqsConsumer::new(&config).await?;
-    let sqs_consumer = Arc::new(sqs_consumer);
-    info!("SQS consumer initialized");
+    // Create the Kafka producer for routes that publish to a topic
+    let kafka = if router.has_kafka_routes() {
+        let publisher = KafkaPublisher::new(&config.kafka)?;
+        info!(
+            "Kafka producer connecting to {}",
+            config.kafka.brokers.as_deref().unwrap_or_default()
+        );
+        Some(publisher)
+    } else {
+        None
+    };
+
+    // Create the Redis client for routes that write to a stream or channel
+    let redis = if router.has_redis_routes() {
+        let publisher = RedisPublisher::new(&config.redis)?;
+        info!("Redis client created");
+        Some(publisher)
+    } else {
+        None
+    };
+
+    // Create an SQS consumer per queue
+    let sqs_client = sqs::client(&config).await;
+    let queues: Vec<Arc<SqsConsumer>> = config
+        .queues
+        .iter()
+        .map(|queue| {
+            Arc::new(SqsConsumer::new(
+                sqs_client.clone(),
+                queue.clone(),
+                Arc::clone(&metrics),
+            ))
+        })
+        .collect();
+    info!("SQS consumers initialized for {} queues", queues.len());
+
+    // Keep messages that are still being forwarded invisible on the queue,
+    // delete processed messages in batches, and track each queue's depth
+    let mut sqs_tasks = tokio::task::JoinSet::new();
+    for queue in &queues {
+        sqs_tasks.spawn(Arc::clone(queue).run_heartbeat());
+        sqs_tasks.spawn(Arc::clone(queue).run_deleter());
+        if config.queue_metrics_interval_seconds > 0 {
+            let period = Duration::from_secs(config.queue_metrics_interval_seconds);
+            sqs_tasks.spawn(Arc::clone(queue).run_depth_poller(period));
+        }
+    }
+
+    // Create the object store client for claim-checked bodies
+    let claim_checks = Arc::new(ClaimCheckStore::new(&config).await);
+    if let Some(ref endpoint) = config.object_store.endpoint {
+        info!("Claim checks fetched from {}", endpoint);
+    }
+
+    // Create the archiver for routes that keep a copy of their webhooks
+    let archiver = Arc::new(Archiver::new(&config, Arc::clone(&metrics)).await);
+    let archive_handle = tokio::spawn(Arc::clone(&archiver).run());
+
+    // Create the batcher for routes that deliver several webhooks per request
+    let batcher = Arc::new(Batcher::new(
+        Arc::clone(&forwarder),
+        Arc::clone(&claim_checks),
+    ));
+    let batch_handle = tokio::spawn(Arc::clone(&batcher).run());
+
+    // Create the decoder for SNS, EventBridge and API Gateway envelopes
+    let decoder = MessageDecoder::new(&config)?;
+    if config.sns_verify_signatures {
+        info!("SNS signature verification enabled");
+    }
+
+    // Open the delivery log for target responses
+    let delivery_log = DeliveryLog::open(config.delivery_log_path.as_deref()).await?;
+    if let Some(ref path) = config.delivery_log_path {
+        info!("Delivery log: {}", path);
+    }
+
+    let relay = Arc::new(Relay {
+        router,
+        decoder,
+        forwarder,
+        kafka,
+        redis,
+        batcher,
+        claim_checks,
+        archiver,
+        delivery_log,
+        metrics: Arc::clone(&metrics),
+        max_decompressed_bytes: config.max_decompressed_bytes,
+    });
 
     // Start the HTTP server for health checks
     let health_app = Router::new()
         .route("/health", get(health::liveness))
         .route("/ready", get({
-            let sqs = Arc::clone(&sqs_consumer);
-            move || health::readiness(sqs.clone())
+            let queues = Arc::new(queues.clone());
+            move || health::readiness(queues.clone())
         }));
 
     let http_listener = TcpListener::bind(format!("0.0.0.0:{}", config.http_port)).await?;
     info!("Health server listening on port {}", config.http_port);
 
     // Start the metrics server
-    let metrics_app = Router::new().route("/metrics", get(metrics::handler));
+    let metrics_app = Router::new()
+        .route("/metrics", get(metrics::handler))
+        .with_state(metrics);
 
     let metrics_listener = TcpListener::bind(format!("0.0.0.0:{}", config.metrics_port)).await?;
     info!("Metrics server listening on port {}", config.metrics_port);
@@ -76,107 +236,339 @@ async fn main() -> Result<()> {
         axum::serve(metrics_listener, metrics_app).await
     });
 
-    // Start the SQS polling loop
-    let poll_interval = std::time::Duration::from_millis(config.poll_interval_ms);
-    let max_messages = config.max_messages;
-
-    info!("Starting SQS polling loop (interval: {:?}, max_messages: {})", poll_interval, max_messages);
-
-    let poll_handle = tokio::spawn(async move {
-        loop {
-            match sqs_consumer.receive_messages(max_messages).await {
-                Ok(messages) => {
-                    if !messages.is_empty() {
-                        info!("Received {} messages from SQS", messages.len());
-                        metrics::MESSAGES_RECEIVED.inc_by(messages.len() as f64);
-                    }
-
-                    for msg in messages {
-                        let receipt_handle = match &msg.receipt_handle {
-                            Some(h) => h.clone(),
-                            None => {
-                                tracing::warn!("Message without receipt handle, skipping");
-                                continue;
-                            }
-                        };
-
-                        let body = match &msg.body {
-                            Some(b) => b.clone(),
-                            None => {
-                                tracing::warn!("Message without body, skipping");
-                                continue;
-                            }
-                        };
-
-                        // Process the message
-                        match process_message(&body, &router, &forwarder).await {
-                            Ok(()) => {
-                                // Delete the message from SQS
-                                if let Err(e) = sqs_consumer.delete_message(&receipt_handle).await {
-                                    tracing::error!("Failed to delete message: {}", e);
-                                }
-                            }
-                            Err(e) => {
-                                tracing::error!("Failed to process message: {}", e);
-                                metrics::MESSAGES_FAILED
-                                    .with_label_values(&["unknown", "processing_error"])
-                                    .inc();
-                                // Message will return to queue after visibility timeout
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    tracing::error!("Failed to receive messages: {:?}", e);
-                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                }
-            }
+    // Start polling the queues and dispatching their messages
+    let scheduler = Arc::new(Scheduler::new(queues, config.max_concurrency));
 
-            tokio::time::sleep(poll_interval).await;
-        }
-    });
+    info!(
+        "Starting SQS polling (max concurrency: {})",
+        config.max_concurrency
+    );
+
+    let poll_handle = tokio::spawn(scheduler.run(move |queue, msg| {
+        let relay = Arc::clone(&relay);
+        async move { handle_message(&relay, queue, msg).await }
+    }));
 
     // Wait for any task to complete (shouldn't happen normally)
     tokio::select! {
         _ = http_handle => tracing::error!("HTTP server exited"),
         _ = metrics_handle => tracing::error!("Metrics server exited"),
         _ = poll_handle => tracing::error!("Polling loop exited"),
+        _ = batch_handle => tracing::error!("Batch flush loop exited"),
+        _ = archive_handle => tracing::error!("Archive flush loop exited"),
+        _ = sqs_tasks.join_next() => tracing::error!("SQS background task exited"),
+    }
+
+    if let Some(provider) = tracer_provider {
+        if let Err(e) = provider.shutdown() {
+            tracing::warn!("Failed to flush traces: {}", e);
+        }
     }
 
     Ok(())
 }
 
+/// Everything needed to turn a received message into a delivered webhook
+struct Relay {
+    router: WebhookRouter,
+    decoder: MessageDecoder,
+    forwarder: Arc<Forwarder>,
+    kafka: Option<KafkaPublisher>,
+    redis: Option<RedisPublisher>,
+    batcher: Arc<Batcher>,
+    claim_checks: Arc<ClaimCheckStore>,
+    archiver: Arc<Archiver>,
+    delivery_log: DeliveryLog,
+    metrics: Arc<Metrics>,
+    max_decompressed_bytes: usize,
+}
+
+/// What happened to a successfully processed message
+enum Outcome {
+    /// Forwarded to the target; the message can be deleted
+    Forwarded,
+    /// Handed to the batcher, which deletes it once the batch is forwarded
+    Batched,
+}
+
+/// Process a received message, then acknowledge it or release it back to the
+/// queue. Returns whether the message was processed.
+#[tracing::instrument(
+    name = "message",
+    skip_all,
+    fields(
+        queue = queue.name(),
+        message_id = msg.message_id(),
+        trace_id = tracing::field::Empty
+    )
+)]
+async fn handle_message(relay: &Relay, queue: Arc<SqsConsumer>, msg: Message) -> bool {
+    let group_id = sqs::message_group_id(&msg).map(str::to_string);
+    let message_id = msg.message_id.clone();
+    let trace_parent = telemetry::message_context(&msg);
+
+    let receipt_handle = match msg.receipt_handle {
+        Some(h) => h,
+        None => {
+            tracing::warn!("Message without receipt handle, skipping");
+            return false;
+        }
+    };
+
+    let body = match msg.body {
+        Some(b) => b,
+        None => {
+            tracing::warn!("Message without body, skipping");
+            queue.release(&receipt_handle);
+            return false;
+        }
+    };
+
+    match process_message(
+        relay,
+        &body,
+        group_id.clone(),
+        trace_parent,
+        message_id.as_deref(),
+        &receipt_handle,
+        &queue,
+    )
+    .await
+    {
+        Ok(Outcome::Batched) => {
+            // The batcher deletes the message once its batch is forwarded
+            true
+        }
+        Ok(Outcome::Forwarded) => {
+            queue.acknowledge(receipt_handle, telemetry::span_context());
+            true
+        }
+        Err(e) => {
+            if let Some(invalid) = e.downcast_ref::<MessageError>() {
+                return dead_letter(
+                    &relay.metrics,
+                    &queue,
+                    &body,
+                    invalid,
+                    group_id.as_deref(),
+                    message_id.as_deref(),
+                    receipt_handle,
+                )
+                .await;
+            }
+
+            tracing::error!("Failed to process message from {}: {}", queue.name(), e);
+            relay
+                .metrics
+                .messages_failed
+                .with_label_values(&[queue.name(), "unknown", "processing_error"])
+                .inc();
+            // Message will return to queue after visibility timeout
+            queue.release(&receipt_handle);
+            false
+        }
+    }
+}
+
+/// Move a message that can never be delivered to the queue's dead-letter
+/// queue. Without one the message is released, and SQS moves it once its
+/// receive count runs out.
+async fn dead_letter(
+    metrics: &Metrics,
+    queue: &SqsConsumer,
+    body: &str,
+    error: &MessageError,
+    group_id: Option<&str>,
+    message_id: Option<&str>,
+    receipt_handle: String,
+) -> bool {
+    tracing::warn!("Invalid message from {}: {}", queue.name(), error);
+    metrics
+        .messages_failed
+        .with_label_values(&[queue.name(), "unknown", error.reason()])
+        .inc();
+
+    match queue.dead_letter(body, error, group_id, message_id).await {
+        Ok(true) => {
+            metrics
+                .messages_dead_lettered
+                .with_label_values(&[queue.name(), error.reason()])
+                .inc();
+            queue.acknowledge(receipt_handle, telemetry::span_context());
+            true
+        }
+        Ok(false) => {
+            queue.release(&receipt_handle);
+            false
+        }
+        Err(e) => {
+            tracing::error!("Failed to dead-letter message from {}: {}", queue.name(), e);
+            queue.release(&receipt_handle);
+            false
+        }
+    }
+}
+
 async fn process_message(
+    relay: &Relay,
     body: &str,
-    router: &WebhookRouter,
-    forwarder: &Forwarder,
-) -> Result<()> {
-    // Parse the message
-    let webhook: sqs::WebhookMessage = serde_json::from_str(body)?;
+    group_id: Option<String>,
+    trace_parent: Option<opentelemetry::Context>,
+    message_id: Option<&str>,
+    receipt_handle: &str,
+    queue: &Arc<SqsConsumer>,
+) -> Result<Outcome> {
+    // Parse the message, unwrapping any envelope
+    let mut webhook = relay.decoder.decode(body).await?;
+    webhook.message_group_id = group_id;
+    telemetry::continue_trace(trace_parent, &webhook);
+
+    // Replace a claim check with the body it points at
+    let claim = webhook.claim_check.take();
+    if let Some(ref claim) = claim {
+        let body = relay.claim_checks.fetch(claim).await?;
+        webhook.body = BASE64.encode(body);
+        webhook.is_base64_encoded = true;
+    }
 
     // Extract the target service from the path
     // Path format: /webhook/<service>/<rest>
-    let (target, rest_path) = router.route(&webhook.path)?;
+    let (target, rest_path) = relay
+        .router
+        .route(&webhook.path, queue.config().default_route.as_deref())?;
+    target.check_method(&validation::parse_method(&webhook.method)?)?;
+
+    // Decompress the body unless the target accepts it compressed. Batches
+    // embed bodies as JSON, so they are always decompressed.
+    let accepted = webhook
+        .content_encoding
+        .is_some_and(|e| target.batch.is_none() && target.accept_encoding.contains(&e));
+    if !accepted {
+        compression::expand(&mut webhook, relay.max_decompressed_bytes)?;
+    }
+    relay
+        .metrics
+        .request_body_bytes
+        .with_label_values(&[queue.name(), &target.name])
+        .observe(webhook.body_len() as f64);
+
+    // Write single archive objects before delivering the webhook, so a
+    // failed write is retried without the target seeing the webhook twice
+    if let Some(ref archive) = target.archive {
+        if !archive.format.is_batched() {
+            relay
+                .archiver
+                .write(target, archive, &webhook, message_id)
+                .await?;
+        }
+    }
+
+    if target.batch.is_some() {
+        info!("Batching webhook: {} -> {}", webhook.path, target.url);
+        relay
+            .batcher
+            .add(queue, target, &webhook, receipt_handle.to_string(), claim)
+            .await?;
+        return Ok(Outcome::Batched);
+    }
+
+    match target.kind {
+        TargetKind::Http | TargetKind::Grpc(_) => {
+            forward(
+                relay, queue, target, &webhook, &rest_path, message_id, claim,
+            )
+            .await?;
+        }
+        TargetKind::Kafka(ref kafka) => {
+            publish(relay, queue, target, kafka, &webhook, claim).await?;
+        }
+        TargetKind::Redis(ref redis) => {
+            write_redis(relay, queue, target, redis, &webhook, claim).await?;
+        }
+        TargetKind::Exec(ref exec) => {
+            run_command(relay, queue, target, exec, &webhook, message_id, claim).await?;
+        }
+    }
 
+    // Batched archives only take delivered webhooks
+    if let Some(ref archive) = target.archive {
+        if archive.format.is_batched() {
+            relay
+                .archiver
+                .add(target, archive, &webhook, message_id)
+                .await;
+        }
+    }
+
+    Ok(Outcome::Forwarded)
+}
+
+/// Forward a webhook to an HTTP or gRPC route. Any response from the target
+/// counts as delivered.
+async fn forward(
+    relay: &Relay,
+    queue: &SqsConsumer,
+    target: &RouteTarget,
+    webhook: &sqs::WebhookMessage,
+    rest_path: &str,
+    message_id: Option<&str>,
+    claim: Option<claim_check::ClaimCheck>,
+) -> Result<()> {
     info!(
         "Routing webhook: {} -> {} (path: {})",
         webhook.path, target.url, rest_path
     );
 
-    let timer = metrics::FORWARD_DURATION
-        .with_label_values(&[&target.name])
+    // Copy the webhook to the route's shadow target in the background
+    if let Some(ref mirror) = target.mirror {
+        mirror.send(&relay.forwarder, queue.name(), webhook, rest_path);
+    }
+
+    let in_flight = relay.metrics.in_flight(queue.name(), &target.name);
+    let timer = relay
+        .metrics
+        .forward_duration
+        .with_label_values(&[queue.name(), &target.name])
         .start_timer();
 
     // Forward the webhook
-    match forwarder.forward(&webhook, target, &rest_path).await {
-        Ok(status) => {
+    let result = relay
+        .forwarder
+        .forward(webhook, target, rest_path)
+        .instrument(info_span!("forward", target = %target.name))
+        .await;
+    drop(in_flight);
+    match result {
+        Ok(response) => {
             timer.observe_duration();
-            metrics::MESSAGES_FORWARDED
-                .with_label_values(&[&target.name, &status.to_string()])
+            let status = response.status;
+            relay
+                .metrics
+                .messages_forwarded
+                .with_label_values(&[queue.name(), &target.name, &status.to_string()])
                 .inc();
+            relay
+                .metrics
+                .response_body_bytes
+                .with_label_values(&[queue.name(), &target.name])
+                .observe(response.body.len() as f64);
+
+            // Keep the response, and send it back towards the sender when
+            // they are waiting for it
+            let record = DeliveryRecord::new(queue.name(), target, webhook, message_id, &response);
+            relay.delivery_log.record(&record).await;
+            if let Some(ref correlation_id) = webhook.correlation_id {
+                if let Err(e) = queue.reply(&record, correlation_id).await {
+                    tracing::warn!("Failed to publish reply for {}: {}", correlation_id, e);
+                }
+            }
 
             if status.is_success() {
                 info!("Webhook forwarded successfully: {}", status);
+                relay
+                    .metrics
+                    .observe_end_to_end(queue.name(), &target.name, &webhook.timestamp);
+                relay.claim_checks.delivered(claim.as_slice()).await;
                 Ok(())
             } else {
                 tracing::warn!("Webhook forwarded but got error response: {}", status);
@@ -186,8 +578,163 @@ async fn process_message(
         }
         Err(e) => {
             timer.observe_duration();
-            metrics::MESSAGES_FAILED
-                .with_label_values(&[&target.name, "forward_error"])
+            relay
+                .metrics
+                .messages_failed
+                .with_label_values(&[queue.name(), &target.name, "forward_error"])
+                .inc();
+            Err(e)
+        }
+    }
+}
+
+/// Publish a webhook to a kafka route's topic. The message is only deleted
+/// once the broker has acknowledged the record.
+async fn publish(
+    relay: &Relay,
+    queue: &SqsConsumer,
+    target: &RouteTarget,
+    kafka: &KafkaTarget,
+    webhook: &sqs::WebhookMessage,
+    claim: Option<claim_check::ClaimCheck>,
+) -> Result<()> {
+    let Some(ref publisher) = relay.kafka else {
+        bail!("No Kafka producer for route {}", target.name);
+    };
+
+    info!("Publishing webhook: {} -> {}", webhook.path, target.url);
+
+    let _in_flight = relay.metrics.in_flight(queue.name(), &target.name);
+    let timer = relay
+        .metrics
+        .forward_duration
+        .with_label_values(&[queue.name(), &target.name])
+        .start_timer();
+
+    match publisher.publish(target, kafka, webhook).await {
+        Ok((partition, offset)) => {
+            timer.observe_duration();
+            relay
+                .metrics
+                .messages_forwarded
+                .with_label_values(&[queue.name(), &target.name, "published"])
+                .inc();
+            info!(
+                "Webhook published to {} partition {} offset {}",
+                kafka.topic, partition, offset
+            );
+            relay
+                .metrics
+                .observe_end_to_end(queue.name(), &target.name, &webhook.timestamp);
+            relay.claim_checks.delivered(claim.as_slice()).await;
+            Ok(())
+        }
+        Err(e) => {
+            timer.observe_duration();
+            relay
+                .metrics
+                .messages_failed
+                .with_label_values(&[queue.name(), &target.name, "publish_error"])
+                .inc();
+            Err(e)
+        }
+    }
+}
+
+/// Write a webhook to a redis route's stream or channel. Messages on a
+/// channel are gone once published, whether or not anyone was subscribed.
+async fn write_redis(
+    relay: &Relay,
+    queue: &SqsConsumer,
+    target: &RouteTarget,
+    redis: &RedisTarget,
+    webhook: &sqs::WebhookMessage,
+    claim: Option<claim_check::ClaimCheck>,
+) -> Result<()> {
+    let Some(ref publisher) = relay.redis else {
+        bail!("No Redis client for route {}", target.name);
+    };
+
+    info!("Writing webhook: {} -> {}", webhook.path, target.url);
+
+    let _in_flight = relay.metrics.in_flight(queue.name(), &target.name);
+    let timer = relay
+        .metrics
+        .forward_duration
+        .with_label_values(&[queue.name(), &target.name])
+        .start_timer();
+
+    match publisher.publish(target, redis, webhook).await {
+        Ok(reply) => {
+            timer.observe_duration();
+            relay
+                .metrics
+                .messages_forwarded
+                .with_label_values(&[queue.name(), &target.name, "published"])
+                .inc();
+            match redis {
+                RedisTarget::Stream { .. } => info!("Webhook added to stream as {}", reply),
+                RedisTarget::Channel(_) => info!("Webhook published to {} subscribers", reply),
+            }
+            relay
+                .metrics
+                .observe_end_to_end(queue.name(), &target.name, &webhook.timestamp);
+            relay.claim_checks.delivered(claim.as_slice()).await;
+            Ok(())
+        }
+        Err(e) => {
+            timer.observe_duration();
+            relay
+                .metrics
+                .messages_failed
+                .with_label_values(&[queue.name(), &target.name, "publish_error"])
+                .inc();
+            Err(e)
+        }
+    }
+}
+
+/// Run an exec route's command for a webhook. A failing or timed out command
+/// leaves the message on the queue to be retried.
+async fn run_command(
+    relay: &Relay,
+    queue: &SqsConsumer,
+    target: &RouteTarget,
+    exec: &ExecTarget,
+    webhook: &sqs::WebhookMessage,
+    message_id: Option<&str>,
+    claim: Option<claim_check::ClaimCheck>,
+) -> Result<()> {
+    info!("Running command: {} -> {}", webhook.path, target.url);
+
+    let _in_flight = relay.metrics.in_flight(queue.name(), &target.name);
+    let timer = relay
+        .metrics
+        .forward_duration
+        .with_label_values(&[queue.name(), &target.name])
+        .start_timer();
+
+    match exec.run(target, webhook, message_id).await {
+        Ok(code) => {
+            timer.observe_duration();
+            relay
+                .metrics
+                .messages_forwarded
+                .with_label_values(&[queue.name(), &target.name, &format!("exit_{}", code)])
+                .inc();
+            info!("Command exited with code {}", code);
+            relay
+                .metrics
+                .observe_end_to_end(queue.name(), &target.name, &webhook.timestamp);
+            relay.claim_checks.delivered(claim.as_slice()).await;
+            Ok(())
+        }
+        Err(e) => {
+            timer.observe_duration();
+            relay
+                .metrics
+                .messages_failed
+                .with_label_values(&[queue.name(), &target.name, "exec_error"])
                 .inc();
             Err(e)
         }
@@ -0,0 +1,77 @@
+use anyhow::{anyhow, Context, Result};
+
+use crate::sqs::WebhookMessage;
+
+/// A value taken from each webhook, written as `header:<name>` or
+/// `body:<json.path>` in the routes YAML
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageKey {
+    Header(String),
+    /// Dotted path into a JSON body; numeric fields index into arrays
+    Body(Vec<String>),
+}
+
+impl MessageKey {
+    pub fn parse(key: &str) -> Result<Self> {
+        match key.split_once(':') {
+            Some(("header", name)) if !name.is_empty() => Ok(MessageKey::Header(name.to_string())),
+            Some(("body", path)) if !path.is_empty() => Ok(MessageKey::Body(
+                path.split('.').map(str::to_string).collect(),
+            )),
+            _ => Err(anyhow!("invalid key {:?}", key))
+                .context("expected header:<name> or body:<json.path>"),
+        }
+    }
+
+    /// The key's value in a webhook, if it has one
+    pub fn value(&self, message: &WebhookMessage) -> Option<String> {
+        match self {
+            MessageKey::Header(name) => message
+                .header_values()
+                .into_iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.to_string()),
+            MessageKey::Body(path) => {
+                let body: serde_json::Value =
+                    serde_json::from_slice(&message.decoded_body().ok()?).ok()?;
+                let value = path.iter().try_fold(&body, |value, field| match value {
+                    serde_json::Value::Array(items) => items.get(field.parse::<usize>().ok()?),
+                    _ => value.get(field),
+                })?;
+                match value {
+                    serde_json::Value::String(s) => Some(s.clone()),
+                    serde_json::Value::Null => None,
+                    other => Some(other.to_string()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_key() {
+        let message: WebhookMessage = serde_json::from_value(serde_json::json!({
+            "path": "/webhook/gitea/push",
+            "method": "POST",
+            "headers": {"X-Gitea-Delivery": "d-1"},
+            "body": "{\"repository\": {\"id\": 42}, \"commits\": [{\"id\": \"abc\"}]}",
+            "timestamp": "2025-01-15T10:30:00Z",
+        }))
+        .unwrap();
+
+        let value = |key: &str| MessageKey::parse(key).unwrap().value(&message);
+        assert_eq!(value("header:x-gitea-delivery").as_deref(), Some("d-1"));
+        assert_eq!(value("body:repository.id").as_deref(), Some("42"));
+        assert_eq!(value("body:commits.0.id").as_deref(), Some("abc"));
+        assert_eq!(value("body:repository.name"), None);
+        assert_eq!(value("header:x-missing"), None);
+
+        for bad in ["header:", "body:", "query:id", "repository.id"] {
+            assert!(MessageKey::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -1,62 +1,306 @@
+use anyhow::{Context, Result};
+use axum::extract::State;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
-use lazy_static::lazy_static;
+use chrono::{DateTime, Utc};
 use prometheus::{
-    register_counter, register_counter_vec, register_histogram_vec, Counter, CounterVec, Encoder,
-    HistogramVec, TextEncoder,
+    exponential_buckets, CounterVec, Encoder, HistogramOpts, HistogramVec, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder,
 };
+use std::sync::Arc;
 
-lazy_static! {
-    pub static ref MESSAGES_RECEIVED: Counter = register_counter!(
-        "webhook_relay_messages_received_total",
-        "Total number of messages received from SQS"
-    )
-    .unwrap();
-    pub static ref MESSAGES_FORWARDED: CounterVec = register_counter_vec!(
-        "webhook_relay_messages_forwarded_total",
-        "Total number of messages forwarded to targets",
-        &["target", "status"]
-    )
-    .unwrap();
-    pub static ref MESSAGES_FAILED: CounterVec = register_counter_vec!(
-        "webhook_relay_messages_failed_total",
-        "Total number of messages that failed to process",
-        &["target", "reason"]
-    )
-    .unwrap();
-    pub static ref FORWARD_DURATION: HistogramVec = register_histogram_vec!(
-        "webhook_relay_forward_duration_seconds",
-        "Time spent forwarding webhooks to targets",
-        &["target"],
-        vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
-    )
-    .unwrap();
+use crate::config::MetricsConfig;
+
+const DURATION_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const END_TO_END_BUCKETS: &[f64] = &[
+    0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0,
+];
+const BATCH_SIZE_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0];
+
+/// The relay's Prometheus metrics, registered in a registry of their own
+/// rather than the process-wide default one
+pub struct Metrics {
+    registry: Registry,
+    pub messages_received: CounterVec,
+    pub messages_forwarded: CounterVec,
+    pub messages_failed: CounterVec,
+    pub messages_dead_lettered: CounterVec,
+    pub forward_duration: HistogramVec,
+    pub forwards_in_flight: IntGaugeVec,
+    pub end_to_end_duration: HistogramVec,
+    pub request_body_bytes: HistogramVec,
+    pub response_body_bytes: HistogramVec,
+    pub queue_messages: IntGaugeVec,
+    pub sqs_request_duration: HistogramVec,
+    pub sqs_request_errors: CounterVec,
+    pub mirror_forwarded: CounterVec,
+    pub mirror_failed: CounterVec,
+    pub mirror_duration: HistogramVec,
+    pub webhooks_archived: CounterVec,
+    pub archive_failed: CounterVec,
+    pub batch_size: HistogramVec,
 }
 
-pub async fn handler() -> impl IntoResponse {
-    let encoder = TextEncoder::new();
-    let metric_families = prometheus::gather();
+impl Metrics {
+    pub fn new(config: &MetricsConfig) -> Result<Self> {
+        let labels = (!config.labels.is_empty()).then(|| config.labels.clone());
+        let registry = Registry::new_custom(config.prefix.clone(), labels)
+            .context("Invalid metrics prefix or labels")?;
 
-    let mut buffer = Vec::new();
-    if let Err(e) = encoder.encode(&metric_families, &mut buffer) {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to encode metrics: {}", e),
-        )
-            .into_response();
+        let duration = || {
+            config
+                .duration_buckets
+                .clone()
+                .unwrap_or_else(|| DURATION_BUCKETS.to_vec())
+        };
+        let size = || {
+            config
+                .size_buckets
+                .clone()
+                .unwrap_or_else(|| exponential_buckets(64.0, 4.0, 10).unwrap())
+        };
+        let end_to_end = config
+            .end_to_end_buckets
+            .clone()
+            .unwrap_or_else(|| END_TO_END_BUCKETS.to_vec());
+
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            let counter = CounterVec::new(Opts::new(name, help), labels)?;
+            registry.register(Box::new(counter.clone()))?;
+            Ok::<_, prometheus::Error>(counter)
+        };
+        let gauge = |name: &str, help: &str, labels: &[&str]| {
+            let gauge = IntGaugeVec::new(Opts::new(name, help), labels)?;
+            registry.register(Box::new(gauge.clone()))?;
+            Ok::<_, prometheus::Error>(gauge)
+        };
+        let histogram = |name: &str, help: &str, labels: &[&str], buckets: Vec<f64>| {
+            let opts = HistogramOpts::new(name, help).buckets(buckets);
+            let histogram = HistogramVec::new(opts, labels)?;
+            registry.register(Box::new(histogram.clone()))?;
+            Ok::<_, prometheus::Error>(histogram)
+        };
+
+        let metrics = Metrics {
+            messages_received: counter(
+                "webhook_relay_messages_received_total",
+                "Total number of messages received from SQS",
+                &["queue"],
+            )?,
+            messages_forwarded: counter(
+                "webhook_relay_messages_forwarded_total",
+                "Total number of messages forwarded to targets",
+                &["queue", "target", "status"],
+            )?,
+            messages_failed: counter(
+                "webhook_relay_messages_failed_total",
+                "Total number of messages that failed to process",
+                &["queue", "target", "reason"],
+            )?,
+            messages_dead_lettered: counter(
+                "webhook_relay_messages_dead_lettered_total",
+                "Total number of invalid messages sent to a dead-letter queue",
+                &["queue", "reason"],
+            )?,
+            forward_duration: histogram(
+                "webhook_relay_forward_duration_seconds",
+                "Time spent forwarding webhooks to targets",
+                &["queue", "target"],
+                duration(),
+            )?,
+            forwards_in_flight: gauge(
+                "webhook_relay_forwards_in_flight",
+                "Number of webhooks currently being delivered to each target",
+                &["queue", "target"],
+            )?,
+            end_to_end_duration: histogram(
+                "webhook_relay_end_to_end_duration_seconds",
+                "Time from a webhook being received to its delivery",
+                &["queue", "target"],
+                end_to_end,
+            )?,
+            request_body_bytes: histogram(
+                "webhook_relay_request_body_bytes",
+                "Size of webhook bodies as delivered, still compressed when the target accepts the encoding",
+                &["queue", "target"],
+                size(),
+            )?,
+            response_body_bytes: histogram(
+                "webhook_relay_response_body_bytes",
+                "Size of target response bodies, up to RESPONSE_MAX_BYTES",
+                &["queue", "target"],
+                size(),
+            )?,
+            queue_messages: gauge(
+                "webhook_relay_queue_messages",
+                "Approximate number of messages on each SQS queue, by state",
+                &["queue", "state"],
+            )?,
+            sqs_request_duration: histogram(
+                "webhook_relay_sqs_request_duration_seconds",
+                "Time spent on SQS API calls",
+                &["queue", "operation"],
+                duration(),
+            )?,
+            sqs_request_errors: counter(
+                "webhook_relay_sqs_request_errors_total",
+                "Total number of failed SQS API calls",
+                &["queue", "operation"],
+            )?,
+            mirror_forwarded: counter(
+                "webhook_relay_mirror_forwarded_total",
+                "Total number of webhook copies delivered to mirror targets",
+                &["queue", "target", "status"],
+            )?,
+            mirror_failed: counter(
+                "webhook_relay_mirror_failed_total",
+                "Total number of webhook copies that could not be mirrored",
+                &["queue", "target", "reason"],
+            )?,
+            mirror_duration: histogram(
+                "webhook_relay_mirror_duration_seconds",
+                "Time spent delivering webhook copies to mirror targets",
+                &["queue", "target"],
+                duration(),
+            )?,
+            webhooks_archived: counter(
+                "webhook_relay_webhooks_archived_total",
+                "Total number of webhooks written to the archive",
+                &["target"],
+            )?,
+            archive_failed: counter(
+                "webhook_relay_archive_failed_total",
+                "Total number of failed archive writes",
+                &["target", "reason"],
+            )?,
+            batch_size: histogram(
+                "webhook_relay_batch_size",
+                "Number of webhooks in each forwarded batch",
+                &["queue", "target"],
+                BATCH_SIZE_BUCKETS.to_vec(),
+            )?,
+            registry,
+        };
+
+        Ok(metrics)
+    }
+
+    /// Counts a delivery as in flight to its target until the returned guard
+    /// is dropped
+    pub fn in_flight(&self, queue: &str, target: &str) -> InFlight {
+        let gauge = self.forwards_in_flight.with_label_values(&[queue, target]);
+        gauge.inc();
+        InFlight(gauge)
     }
 
-    match String::from_utf8(buffer) {
+    /// Record how long ago a delivered webhook was received. Webhooks without
+    /// a valid timestamp are left out.
+    pub fn observe_end_to_end(&self, queue: &str, target: &str, received_at: &str) {
+        let Ok(received) = DateTime::parse_from_rfc3339(received_at) else {
+            return;
+        };
+        let elapsed = (Utc::now() - received.with_timezone(&Utc)).num_milliseconds();
+        self.end_to_end_duration
+            .with_label_values(&[queue, target])
+            .observe(elapsed.max(0) as f64 / 1000.0);
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .context("Failed to encode metrics")?;
+        String::from_utf8(buffer).context("Failed to convert metrics to string")
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new(&MetricsConfig::default()).expect("default metrics are valid")
+    }
+}
+
+/// Decrements the in-flight gauge it was created from when dropped
+pub struct InFlight(IntGauge);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+pub async fn handler(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    match metrics.encode() {
         Ok(output) => (
             StatusCode::OK,
             [("content-type", "text/plain; version=0.0.4")],
             output,
         )
             .into_response(),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to convert metrics to string: {}", e),
-        )
-            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_delivery_metrics() {
+        let metrics = Metrics::default();
+        {
+            let _first = metrics.in_flight("webhooks", "n8n");
+            let _second = metrics.in_flight("webhooks", "n8n");
+            let gauge = metrics
+                .forwards_in_flight
+                .with_label_values(&["webhooks", "n8n"]);
+            assert_eq!(gauge.get(), 2);
+        }
+        let gauge = metrics
+            .forwards_in_flight
+            .with_label_values(&["webhooks", "n8n"]);
+        assert_eq!(gauge.get(), 0);
+
+        let received = (Utc::now() - chrono::Duration::seconds(90)).to_rfc3339();
+        metrics.observe_end_to_end("webhooks", "n8n", &received);
+        metrics.observe_end_to_end("webhooks", "n8n", "not a time");
+        let histogram = metrics
+            .end_to_end_duration
+            .with_label_values(&["webhooks", "n8n"]);
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert!((90.0..91.0).contains(&histogram.get_sample_sum()));
+
+        // Each instance counts on its own
+        assert_eq!(
+            Metrics::default()
+                .end_to_end_duration
+                .with_label_values(&["webhooks", "n8n"])
+                .get_sample_count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_metrics_config() {
+        let metrics = Metrics::new(&MetricsConfig {
+            prefix: Some("homelab".to_string()),
+            labels: HashMap::from([("cluster".to_string(), "house".to_string())]),
+            duration_buckets: Some(vec![0.5, 5.0]),
+            ..Default::default()
+        })
+        .unwrap();
+        metrics
+            .forward_duration
+            .with_label_values(&["webhooks", "n8n"])
+            .observe(1.0);
+
+        let output = metrics.encode().unwrap();
+        assert!(output.contains(
+            "homelab_webhook_relay_forward_duration_seconds_bucket{queue=\"webhooks\",\
+             target=\"n8n\",cluster=\"house\",le=\"5\"} 1"
+        ));
+        assert!(!output.contains("le=\"0.25\""));
     }
 }
@@ -0,0 +1,204 @@
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::forwarder::Forwarder;
+use crate::router::RouteTarget;
+use crate::sqs::WebhookMessage;
+
+/// A route's shadow target, as configured in the routes YAML
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    pub url: String,
+    /// Share of webhooks to copy, from 0 to 100
+    #[serde(default = "default_sample_percent")]
+    pub sample_percent: f64,
+    /// Defaults to the route's timeout
+    #[serde(default)]
+    pub timeout_seconds: Option<u64>,
+    /// Copies in flight at once; further copies are dropped
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: usize,
+}
+
+fn default_sample_percent() -> f64 {
+    100.0
+}
+
+fn default_max_in_flight() -> usize {
+    100
+}
+
+/// Sends copies of a route's webhooks to a second target in the background.
+/// The mirror's responses and failures never affect the original delivery.
+#[derive(Debug)]
+pub struct Mirror {
+    /// The route with the mirror's URL in place of its own
+    pub target: RouteTarget,
+    sample_percent: f64,
+    seen: AtomicU64,
+    in_flight: Arc<Semaphore>,
+}
+
+impl Mirror {
+    pub fn from_config(config: MirrorConfig, route: &RouteTarget) -> Result<Self> {
+        match Url::parse(&config.url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+            _ => bail!("invalid URL {}", config.url),
+        }
+        if !(0.0..=100.0).contains(&config.sample_percent) {
+            bail!("sample_percent must be between 0 and 100");
+        }
+        if config.max_in_flight == 0 {
+            bail!("max_in_flight must be at least 1");
+        }
+
+        let target = RouteTarget {
+            url: config.url,
+            service: None,
+            pool: None,
+            timeout_seconds: config.timeout_seconds.unwrap_or(route.timeout_seconds),
+            batch: None,
+            mirror: None,
+            archive: None,
+            ..route.clone()
+        };
+
+        Ok(Mirror {
+            target,
+            sample_percent: config.sample_percent,
+            seen: AtomicU64::new(0),
+            in_flight: Arc::new(Semaphore::new(config.max_in_flight)),
+        })
+    }
+
+    /// Whether to copy the next webhook. Copies are spread evenly rather
+    /// than picked at random, so exactly `sample_percent` of every hundred
+    /// webhooks are copied.
+    fn sampled(&self) -> bool {
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        let share = self.sample_percent / 100.0;
+        ((n + 1.0) * share).floor() > (n * share).floor()
+    }
+
+    /// Copy a webhook to the mirror if it is sampled, without waiting for
+    /// the mirror to answer
+    pub fn send(
+        &self,
+        forwarder: &Arc<Forwarder>,
+        queue: &str,
+        webhook: &WebhookMessage,
+        rest_path: &str,
+    ) {
+        if !self.sampled() {
+            return;
+        }
+
+        let name = &self.target.name;
+        let Ok(permit) = Arc::clone(&self.in_flight).try_acquire_owned() else {
+            debug!("Mirror for {} is busy, dropping copy", name);
+            forwarder
+                .metrics()
+                .mirror_failed
+                .with_label_values(&[queue, name, "overloaded"])
+                .inc();
+            return;
+        };
+
+        let forwarder = Arc::clone(forwarder);
+        let target = self.target.clone();
+        let queue = queue.to_string();
+        let webhook = webhook.clone();
+        let rest_path = rest_path.to_string();
+        tokio::spawn(async move {
+            let metrics = forwarder.metrics();
+            let timer = metrics
+                .mirror_duration
+                .with_label_values(&[&queue, &target.name])
+                .start_timer();
+            match forwarder.forward(&webhook, &target, &rest_path).await {
+                Ok(response) => {
+                    timer.observe_duration();
+                    metrics
+                        .mirror_forwarded
+                        .with_label_values(&[&queue, &target.name, &response.status.to_string()])
+                        .inc();
+                }
+                Err(e) => {
+                    timer.observe_duration();
+                    warn!("Failed to mirror webhook to {}: {:#}", target.url, e);
+                    metrics
+                        .mirror_failed
+                        .with_label_values(&[&queue, &target.name, "forward_error"])
+                        .inc();
+                }
+            }
+            drop(permit);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::WebhookRouter;
+
+    fn mirror(yaml: &str) -> Result<Arc<Mirror>> {
+        let router = WebhookRouter::from_yaml(yaml)?;
+        let (target, _) = router.route("/webhook/n8n/hook", None)?;
+        Ok(Arc::clone(target.mirror.as_ref().unwrap()))
+    }
+
+    #[test]
+    fn test_mirror_config() {
+        let mirror = mirror(
+            r#"
+routes:
+  n8n:
+    url: "https://n8n.example.com/webhook"
+    timeout_seconds: 20
+    headers:
+      deny: ["cookie"]
+    mirror:
+      url: "https://n8n-next.example.com/webhook"
+      sample_percent: 25
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(mirror.target.name, "n8n");
+        assert_eq!(mirror.target.url, "https://n8n-next.example.com/webhook");
+        assert_eq!(mirror.target.timeout_seconds, 20);
+        assert!(mirror.target.mirror.is_none());
+
+        let sampled = (0..200).filter(|_| mirror.sampled()).count();
+        assert_eq!(sampled, 50);
+
+        for bad in [
+            "url: \"ftp://n8n-next.example.com\"",
+            "url: \"https://n8n-next.example.com\"\n      sample_percent: 150",
+            "url: \"https://n8n-next.example.com\"\n      max_in_flight: 0",
+        ] {
+            let yaml = format!(
+                "routes:\n  n8n:\n    url: \"https://n8n.example.com\"\n    mirror:\n      {}\n",
+                bad
+            );
+            assert!(WebhookRouter::from_yaml(&yaml).is_err(), "{}", bad);
+        }
+
+        let yaml = r#"
+routes:
+  n8n:
+    url: "https://n8n.example.com"
+    batch: {}
+    mirror:
+      url: "https://n8n-next.example.com"
+"#;
+        assert!(WebhookRouter::from_yaml(yaml).is_err());
+    }
+}
@@ -0,0 +1,34 @@
+use aws_sdk_s3::config::{Credentials, Region};
+use aws_sdk_s3::Client;
+
+use crate::config::ObjectStoreConfig;
+
+/// Create an S3 client for the configured object store. Static credentials
+/// are used when set, otherwise the default AWS credential chain.
+pub async fn client(config: &ObjectStoreConfig) -> Client {
+    let aws_config = aws_config::from_env()
+        .region(Region::new(config.region.clone()))
+        .load()
+        .await;
+
+    let mut builder =
+        aws_sdk_s3::config::Builder::from(&aws_config).force_path_style(config.force_path_style);
+
+    if let Some(ref endpoint) = config.endpoint {
+        builder = builder.endpoint_url(endpoint);
+    }
+
+    if let (Some(access_key_id), Some(secret_access_key)) =
+        (&config.access_key_id, &config.secret_access_key)
+    {
+        builder = builder.credentials_provider(Credentials::new(
+            access_key_id,
+            secret_access_key,
+            None,
+            None,
+            "webhook-relay",
+        ));
+    }
+
+    Client::from_conf(builder.build())
+}
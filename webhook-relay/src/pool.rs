@@ -0,0 +1,377 @@
+use anyhow::{anyhow, bail, Context, Result};
+use ring::digest;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+use url::Url;
+
+use crate::message_key::MessageKey;
+use crate::sqs::WebhookMessage;
+
+/// How a pool picks the URL for each webhook
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    /// In proportion to each target's `weight`
+    Weighted,
+    /// The first healthy target, in the order listed
+    Failover,
+    /// The same target for the same `hash_key`, weighted
+    ConsistentHash,
+}
+
+/// A route's `pool` section as written in the routes file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    #[serde(default)]
+    strategy: Strategy,
+    /// `header:<name>` or `body:<dotted.json.path>`
+    #[serde(default)]
+    hash_key: Option<String>,
+    targets: Vec<MemberConfig>,
+    /// Consecutive failures before a target is taken out of the pool
+    #[serde(default = "default_max_failures")]
+    max_failures: u32,
+    /// How long a failing target stays out before it is tried again
+    #[serde(default = "default_ejection_seconds")]
+    ejection_seconds: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MemberConfig {
+    url: String,
+    #[serde(default = "default_weight")]
+    weight: u32,
+}
+
+fn default_max_failures() -> u32 {
+    3
+}
+
+fn default_ejection_seconds() -> u64 {
+    30
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    failures: u32,
+    ejected_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Member {
+    url: Url,
+    weight: u32,
+    health: Mutex<Health>,
+}
+
+/// Several URLs serving one route, with passive health tracking: targets
+/// that keep failing are left out until their ejection time has passed
+#[derive(Debug)]
+pub struct TargetPool {
+    strategy: Strategy,
+    hash_key: Option<MessageKey>,
+    members: Vec<Member>,
+    max_failures: u32,
+    ejection: Duration,
+    next: AtomicUsize,
+    /// Smooth weighted round-robin state, one entry per member
+    current_weights: Mutex<Vec<i64>>,
+}
+
+impl TargetPool {
+    pub fn from_config(config: PoolConfig) -> Result<Self> {
+        if config.targets.is_empty() {
+            bail!("pool needs at least one target");
+        }
+
+        let hash_key = match config.hash_key {
+            Some(key) => Some(MessageKey::parse(&key).context("invalid hash_key")?),
+            None => None,
+        };
+        if config.strategy == Strategy::ConsistentHash && hash_key.is_none() {
+            bail!("consistent_hash pools need a hash_key");
+        }
+
+        let mut members = Vec::new();
+        for member in config.targets {
+            let url = Url::parse(&member.url)
+                .ok()
+                .filter(|url| matches!(url.scheme(), "http" | "https"))
+                .ok_or_else(|| anyhow!("invalid URL {}", member.url))?;
+            if member.weight == 0 {
+                bail!("{}: weight must be at least 1", member.url);
+            }
+            members.push(Member {
+                url,
+                weight: member.weight,
+                health: Mutex::default(),
+            });
+        }
+
+        Ok(TargetPool {
+            strategy: config.strategy,
+            hash_key,
+            current_weights: Mutex::new(vec![0; members.len()]),
+            members,
+            max_failures: config.max_failures.max(1),
+            ejection: Duration::from_secs(config.ejection_seconds),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// URL of the first target, used to describe the route
+    pub fn primary_url(&self) -> &Url {
+        &self.members[0].url
+    }
+
+    /// Pick a target for a webhook, or for a batch when `message` is None.
+    /// Returns the target's index, to report the outcome with, and its URL.
+    pub fn select(&self, message: Option<&WebhookMessage>) -> (usize, &Url) {
+        let now = Instant::now();
+        let mut healthy: Vec<usize> = (0..self.members.len())
+            .filter(|&i| {
+                let health = self.members[i].health.lock().unwrap();
+                health.ejected_until.is_none_or(|until| until <= now)
+            })
+            .collect();
+        // With every target out, try them all rather than none
+        if healthy.is_empty() {
+            healthy = (0..self.members.len()).collect();
+        }
+
+        let key = match (&self.hash_key, message) {
+            (Some(hash_key), Some(message)) => hash_key.value(message),
+            _ => None,
+        };
+
+        // Webhooks without a hash key are spread by weight
+        let index = match (self.strategy, key) {
+            (Strategy::Failover, _) => healthy[0],
+            (Strategy::ConsistentHash, Some(key)) => self.rendezvous(&healthy, &key),
+            (Strategy::Weighted | Strategy::ConsistentHash, _) => self.weighted(&healthy),
+            (Strategy::RoundRobin, _) => {
+                healthy[self.next.fetch_add(1, Ordering::Relaxed) % healthy.len()]
+            }
+        };
+
+        (index, &self.members[index].url)
+    }
+
+    /// Record whether a request to a target succeeded
+    pub fn report(&self, index: usize, success: bool) {
+        let member = &self.members[index];
+        let mut health = member.health.lock().unwrap();
+
+        if success {
+            *health = Health::default();
+            return;
+        }
+
+        health.failures += 1;
+        if health.failures >= self.max_failures {
+            if health
+                .ejected_until
+                .is_none_or(|until| until <= Instant::now())
+            {
+                warn!(
+                    "Pool target {} failed {} times, ejecting for {}s",
+                    member.url,
+                    health.failures,
+                    self.ejection.as_secs()
+                );
+            }
+            health.ejected_until = Some(Instant::now() + self.ejection);
+        }
+    }
+
+    /// Smooth weighted round-robin over the candidates
+    fn weighted(&self, candidates: &[usize]) -> usize {
+        let mut current = self.current_weights.lock().unwrap();
+        let total: i64 = candidates
+            .iter()
+            .map(|&i| self.members[i].weight as i64)
+            .sum();
+
+        let mut best = candidates[0];
+        for &i in candidates {
+            current[i] += self.members[i].weight as i64;
+            if current[i] > current[best] {
+                best = i;
+            }
+        }
+        current[best] -= total;
+        best
+    }
+
+    /// Weighted rendezvous hashing: the candidate with the highest score for
+    /// the key wins, so only keys of a removed target move elsewhere
+    fn rendezvous(&self, candidates: &[usize], key: &str) -> usize {
+        let score = |i: usize| {
+            let member = &self.members[i];
+            let mut context = digest::Context::new(&digest::SHA256);
+            context.update(member.url.as_str().as_bytes());
+            context.update(b"\0");
+            context.update(key.as_bytes());
+            let hash = context.finish();
+
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&hash.as_ref()[..8]);
+            // Map the hash into (0, 1) and weight it
+            let unit = ((u64::from_be_bytes(bytes) >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+            -(member.weight as f64) / unit.ln()
+        };
+
+        candidates
+            .iter()
+            .copied()
+            .max_by(|&a, &b| score(a).total_cmp(&score(b)))
+            .unwrap_or(candidates[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(yaml: &str) -> TargetPool {
+        TargetPool::from_config(serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    fn message(headers: serde_json::Value, body: &str) -> WebhookMessage {
+        serde_json::from_value(serde_json::json!({
+            "path": "/webhook/n8n/flow",
+            "method": "POST",
+            "headers": headers,
+            "body": body,
+            "timestamp": "2025-01-15T10:30:00Z",
+        }))
+        .unwrap()
+    }
+
+    fn hosts(pool: &TargetPool, n: usize) -> Vec<String> {
+        (0..n)
+            .map(|_| pool.select(None).1.host_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_weighted() {
+        let pool = pool(
+            r#"
+strategy: weighted
+targets:
+  - url: "https://n8n.example.com"
+    weight: 9
+  - url: "https://n8n-canary.example.com"
+"#,
+        );
+
+        let hosts = hosts(&pool, 100);
+        let canary = hosts.iter().filter(|h| *h == "n8n-canary.example.com");
+        assert_eq!(canary.count(), 10);
+    }
+
+    #[test]
+    fn test_failover() {
+        let mut pool = pool(
+            r#"
+strategy: failover
+max_failures: 2
+targets:
+  - url: "https://dagster.example.com"
+  - url: "https://dagster-standby.example.com"
+"#,
+        );
+        pool.ejection = Duration::from_millis(50);
+
+        assert_eq!(hosts(&pool, 2), vec!["dagster.example.com"; 2]);
+
+        pool.report(0, false);
+        assert_eq!(hosts(&pool, 1), vec!["dagster.example.com"]);
+        pool.report(0, false);
+        assert_eq!(hosts(&pool, 2), vec!["dagster-standby.example.com"; 2]);
+
+        // With both out, the primary is still tried
+        pool.report(1, false);
+        pool.report(1, false);
+        assert_eq!(hosts(&pool, 1), vec!["dagster.example.com"]);
+
+        // Once the ejection has passed, a single failure ejects it again
+        std::thread::sleep(Duration::from_millis(60));
+        pool.report(1, true);
+        pool.report(0, false);
+        assert_eq!(hosts(&pool, 1), vec!["dagster-standby.example.com"]);
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let yaml = r#"
+strategy: consistent_hash
+hash_key: body:repository.id
+targets:
+  - url: "https://a.example.com"
+  - url: "https://b.example.com"
+  - url: "https://c.example.com"
+"#;
+        let hashed = pool(yaml);
+
+        let picks: Vec<usize> = (0..50)
+            .map(|id| {
+                let body = format!(r#"{{"repository": {{"id": {}}}}}"#, id);
+                let first = hashed
+                    .select(Some(&message(serde_json::json!({}), &body)))
+                    .0;
+                let again = hashed
+                    .select(Some(&message(serde_json::json!({}), &body)))
+                    .0;
+                assert_eq!(first, again);
+                first
+            })
+            .collect();
+        for member in 0..3 {
+            assert!(picks.contains(&member));
+        }
+
+        // Taking a target out only moves the keys it had
+        hashed.report(1, false);
+        hashed.report(1, false);
+        hashed.report(1, false);
+        for (id, &before) in picks.iter().enumerate() {
+            let body = format!(r#"{{"repository": {{"id": {}}}}}"#, id);
+            let after = hashed
+                .select(Some(&message(serde_json::json!({}), &body)))
+                .0;
+            if before != 1 {
+                assert_eq!(after, before);
+            } else {
+                assert_ne!(after, 1);
+            }
+        }
+
+        let by_header = pool(&yaml.replace("body:repository.id", "header:X-Tenant"));
+        let tenant = message(serde_json::json!({"x-tenant": "acme"}), "{}");
+        let first = by_header.select(Some(&tenant)).0;
+        assert!((0..10).all(|_| by_header.select(Some(&tenant)).0 == first));
+
+        for invalid in [
+            "strategy: consistent_hash\ntargets: [{url: \"https://a.example.com\"}]",
+            "hash_key: query:id\ntargets: [{url: \"https://a.example.com\"}]",
+            "targets: []",
+            "targets: [{url: \"https://a.example.com\", weight: 0}]",
+        ] {
+            let config = serde_yaml::from_str(invalid).unwrap();
+            assert!(TargetPool::from_config(config).is_err());
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use anyhow::{anyhow, bail, Context, Result};
+use redis::aio::ConnectionManager;
+use redis::{Client, Cmd, IntoConnectionInfo};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+use crate::config::RedisConfig;
+use crate::router::RouteTarget;
+use crate::sqs::WebhookMessage;
+
+/// A redis route's stream or channel, as configured in the routes YAML
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedisTargetConfig {
+    /// Stream to XADD webhooks to
+    #[serde(default)]
+    pub stream: Option<String>,
+    /// Trim the stream to about this many entries
+    #[serde(default)]
+    pub max_len: Option<usize>,
+    /// Trim to exactly `max_len` rather than letting Redis trim lazily
+    #[serde(default)]
+    pub exact: bool,
+    /// Channel to PUBLISH webhooks to
+    #[serde(default)]
+    pub channel: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum RedisTarget {
+    Stream {
+        key: String,
+        max_len: Option<usize>,
+        exact: bool,
+    },
+    Channel(String),
+}
+
+impl RedisTarget {
+    pub fn from_config(config: RedisTargetConfig) -> Result<Self> {
+        match (config.stream, config.channel) {
+            (Some(key), None) if !key.is_empty() => Ok(RedisTarget::Stream {
+                key,
+                max_len: config.max_len,
+                exact: config.exact,
+            }),
+            (None, Some(channel)) if !channel.is_empty() => {
+                if config.max_len.is_some() {
+                    bail!("max_len only applies to streams");
+                }
+                Ok(RedisTarget::Channel(channel))
+            }
+            _ => bail!("set one of stream or channel"),
+        }
+    }
+
+    /// Describes the target in logs
+    pub fn url(&self) -> String {
+        match self {
+            RedisTarget::Stream { key, .. } => format!("redis://stream/{}", key),
+            RedisTarget::Channel(channel) => format!("redis://channel/{}", channel),
+        }
+    }
+
+    /// The command delivering a webhook: the whole message as JSON, with the
+    /// route name alongside it in stream entries
+    fn command(&self, route: &str, message: &WebhookMessage) -> Result<Cmd> {
+        let payload = serde_json::to_vec(message).context("Failed to encode webhook")?;
+
+        let mut cmd;
+        match self {
+            RedisTarget::Stream {
+                key,
+                max_len,
+                exact,
+            } => {
+                cmd = redis::cmd("XADD");
+                cmd.arg(key);
+                if let Some(max_len) = max_len {
+                    cmd.arg("MAXLEN")
+                        .arg(if *exact { "=" } else { "~" })
+                        .arg(max_len);
+                }
+                cmd.arg("*")
+                    .arg("route")
+                    .arg(route)
+                    .arg("message")
+                    .arg(payload);
+            }
+            RedisTarget::Channel(channel) => {
+                cmd = redis::cmd("PUBLISH");
+                cmd.arg(channel).arg(payload);
+            }
+        }
+        Ok(cmd)
+    }
+}
+
+/// Writes webhooks to Redis over a shared connection, opened on first use
+/// and re-established whenever it drops
+pub struct RedisPublisher {
+    client: Client,
+    connection: OnceCell<ConnectionManager>,
+}
+
+impl RedisPublisher {
+    pub fn new(config: &RedisConfig) -> Result<Self> {
+        let Some(ref url) = config.url else {
+            bail!("REDIS_URL is required for redis routes");
+        };
+
+        let mut info = url
+            .as_str()
+            .into_connection_info()
+            .with_context(|| format!("Invalid REDIS_URL {}", url))?;
+        if config.password.is_some() {
+            info.redis.password = config.password.clone();
+        }
+        let client = Client::open(info).context("Failed to create Redis client")?;
+
+        Ok(RedisPublisher {
+            client,
+            connection: OnceCell::new(),
+        })
+    }
+
+    /// Write a webhook to the route's stream or channel within the route's
+    /// timeout. Returns the stream entry ID, or for channels the number of
+    /// subscribers that received it.
+    pub async fn publish(
+        &self,
+        target: &RouteTarget,
+        redis: &RedisTarget,
+        message: &WebhookMessage,
+    ) -> Result<String> {
+        let cmd = redis.command(&target.name, message)?;
+
+        let write = async {
+            let mut connection = self
+                .connection
+                .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+                .await?
+                .clone();
+            match redis {
+                RedisTarget::Stream { .. } => cmd.query_async::<String>(&mut connection).await,
+                RedisTarget::Channel(_) => cmd
+                    .query_async::<u64>(&mut connection)
+                    .await
+                    .map(|receivers| receivers.to_string()),
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(target.timeout_seconds), write)
+            .await
+            .map_err(|_| anyhow!("Timed out writing webhook to {}", redis.url()))?
+            .with_context(|| format!("Failed to write webhook to {}", redis.url()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{TargetKind, WebhookRouter};
+
+    fn packed(cmd: &Cmd) -> String {
+        String::from_utf8_lossy(&cmd.get_packed_command()).into_owned()
+    }
+
+    #[test]
+    fn test_redis_target() {
+        let router = WebhookRouter::from_yaml(
+            r#"
+routes:
+  gitea:
+    redis:
+      stream: webhooks:gitea
+      max_len: 10000
+  notify:
+    redis:
+      channel: webhooks.notify
+"#,
+        )
+        .unwrap();
+
+        let message: WebhookMessage = serde_json::from_value(serde_json::json!({
+            "path": "/webhook/gitea/push",
+            "method": "POST",
+            "headers": {"X-Gitea-Event": "push"},
+            "body": "{\"ref\": \"main\"}",
+            "timestamp": "2025-01-15T10:30:00Z",
+        }))
+        .unwrap();
+
+        let (target, _) = router.route("/webhook/gitea/push", None).unwrap();
+        assert_eq!(target.url, "redis://stream/webhooks:gitea");
+        let TargetKind::Redis(ref redis) = target.kind else {
+            panic!("expected a redis target");
+        };
+        let cmd = packed(&redis.command(&target.name, &message).unwrap());
+        assert!(cmd.contains("XADD\r\n$14\r\nwebhooks:gitea\r\n"));
+        assert!(cmd.contains("$6\r\nMAXLEN\r\n$1\r\n~\r\n$5\r\n10000\r\n$1\r\n*\r\n"));
+        assert!(cmd.contains("$5\r\nroute\r\n$5\r\ngitea\r\n$7\r\nmessage\r\n"));
+        assert!(cmd.contains("\"X-Gitea-Event\":\"push\""));
+
+        let (target, _) = router.route("/webhook/notify/x", None).unwrap();
+        let TargetKind::Redis(ref redis) = target.kind else {
+            panic!("expected a redis target");
+        };
+        let cmd = packed(&redis.command(&target.name, &message).unwrap());
+        assert!(cmd.starts_with("*3\r\n$7\r\nPUBLISH\r\n$15\r\nwebhooks.notify\r\n"));
+
+        for bad in [
+            "redis: {}",
+            "redis:\n      stream: a\n      channel: b",
+            "redis:\n      channel: b\n      max_len: 10",
+            "redis:\n      stream: a\n    batch: {}",
+        ] {
+            let yaml = format!("routes:\n  gitea:\n    {}\n", bad);
+            assert!(WebhookRouter::from_yaml(&yaml).is_err(), "{}", bad);
+        }
+    }
+}
@@ -1,13 +1,104 @@
 use anyhow::{anyhow, Context, Result};
+use reqwest::Method;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs;
+use std::sync::Arc;
+use url::Url;
+
+use crate::archive::{Archive, ArchiveConfig};
+use crate::compression::ContentEncoding;
+use crate::discovery::{ServicePort, ServiceRef};
+use crate::exec::{CommandConfig, ExecTarget};
+use crate::grpc::{GrpcConfig, GrpcTarget};
+use crate::header_policy::{HeaderPolicy, HeaderRules};
+use crate::http_client::ClientSettings;
+use crate::kafka::{KafkaTarget, TopicConfig};
+use crate::mirror::{Mirror, MirrorConfig};
+use crate::pool::{PoolConfig, TargetPool};
+use crate::redis_target::{RedisTarget, RedisTargetConfig};
+use crate::target_url::{TraversalPolicy, UrlRules};
+use crate::validation::{self, MessageError};
 
 #[derive(Debug, Clone)]
 pub struct RouteTarget {
     pub name: String,
+    /// Where webhooks are delivered to
+    pub kind: TargetKind,
+    /// Target URL; for other than plain HTTP routes, a description used in
+    /// logs
     pub url: String,
+    /// Kubernetes Service whose endpoints webhooks are sent to, instead of
+    /// `url`
+    pub service: Option<ServiceRef>,
+    /// URLs webhooks are spread over, instead of `url`
+    pub pool: Option<Arc<TargetPool>>,
     pub timeout_seconds: u64,
+    pub batch: Option<BatchConfig>,
+    /// Encodings the target accepts; compressed bodies in one of these are
+    /// forwarded as-is with a `Content-Encoding` header
+    pub accept_encoding: Vec<ContentEncoding>,
+    pub url_rules: UrlRules,
+    /// Methods the route accepts; any method when unset
+    pub methods: Option<Vec<Method>>,
+    pub headers: HeaderPolicy,
+    /// HTTP client options overriding the global ones
+    pub client: ClientSettings,
+    /// Shadow target receiving copies of the route's webhooks
+    pub mirror: Option<Arc<Mirror>>,
+    /// Bucket the route's webhooks are also written to
+    pub archive: Option<Archive>,
+}
+
+/// How a route delivers webhooks
+#[derive(Debug, Clone)]
+pub enum TargetKind {
+    /// An HTTP request to `url`, a Service endpoint or a pool member
+    Http,
+    /// A record on a Kafka topic
+    Kafka(KafkaTarget),
+    /// An entry on a Redis stream or a message on a Redis channel
+    Redis(RedisTarget),
+    /// A local command run with the webhook on stdin
+    Exec(ExecTarget),
+    /// A unary gRPC call carrying the webhook
+    Grpc(GrpcTarget),
+}
+
+impl RouteTarget {
+    /// Refuse webhooks whose method is not in the route's allow-list
+    pub fn check_method(&self, method: &Method) -> Result<(), MessageError> {
+        match self.methods {
+            Some(ref methods) if !methods.contains(method) => Err(MessageError::MethodNotAllowed {
+                method: method.clone(),
+                route: self.name.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Per-route batching: webhooks for the target are buffered and forwarded
+/// together in a single request once `max_size` is reached or the oldest
+/// buffered webhook has waited `max_wait_ms`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchConfig {
+    #[serde(default = "default_batch_max_size")]
+    pub max_size: usize,
+    #[serde(default = "default_batch_max_wait_ms")]
+    pub max_wait_ms: u64,
+    #[serde(default)]
+    pub format: BatchFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchFormat {
+    /// A single JSON array containing one element per webhook
+    #[default]
+    JsonArray,
+    /// Newline-delimited JSON, one webhook per line
+    Ndjson,
 }
 
 #[derive(Debug, Deserialize)]
@@ -19,9 +110,58 @@ struct RoutesConfig {
 
 #[derive(Debug, Deserialize)]
 struct RouteEntry {
-    url: String,
+    #[serde(default)]
+    url: Option<String>,
+    /// Kubernetes Service to forward to, instead of `url`
+    #[serde(default)]
+    service: Option<String>,
+    #[serde(default = "default_namespace")]
+    namespace: String,
+    #[serde(default)]
+    port: Option<ServicePort>,
+    #[serde(default = "default_scheme")]
+    scheme: String,
+    #[serde(default)]
+    base_path: String,
+    /// Several URLs to spread webhooks over, instead of `url`
+    #[serde(default)]
+    pool: Option<PoolConfig>,
+    /// Kafka topic to publish to, instead of an HTTP target
+    #[serde(default)]
+    kafka: Option<TopicConfig>,
+    /// Redis stream or channel to write to, instead of an HTTP target
+    #[serde(default)]
+    redis: Option<RedisTargetConfig>,
+    /// Local command to run, instead of an HTTP target
+    #[serde(default)]
+    exec: Option<CommandConfig>,
+    /// gRPC method to call, instead of an HTTP target
+    #[serde(default)]
+    grpc: Option<GrpcConfig>,
     #[serde(default = "default_timeout")]
     timeout_seconds: u64,
+    #[serde(default)]
+    batch: Option<BatchConfig>,
+    #[serde(default)]
+    accept_encoding: Vec<ContentEncoding>,
+    /// Append the rest of the webhook path to `url`
+    #[serde(default = "default_true")]
+    append_path: bool,
+    /// Append the webhook's query parameters to `url`
+    #[serde(default = "default_true")]
+    append_query: bool,
+    #[serde(default)]
+    path_traversal: TraversalPolicy,
+    #[serde(default)]
+    methods: Option<Vec<String>>,
+    #[serde(default)]
+    headers: HeaderRules,
+    #[serde(default)]
+    client: ClientSettings,
+    #[serde(default)]
+    mirror: Option<MirrorConfig>,
+    #[serde(default)]
+    archive: Option<ArchiveConfig>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -31,10 +171,30 @@ struct DefaultAction {
     url: Option<String>,
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_namespace() -> String {
+    "default".to_string()
+}
+
+fn default_scheme() -> String {
+    "http".to_string()
+}
+
 fn default_timeout() -> u64 {
     30
 }
 
+fn default_batch_max_size() -> usize {
+    10
+}
+
+fn default_batch_max_wait_ms() -> u64 {
+    5000
+}
+
 pub struct WebhookRouter {
     routes: HashMap<String, RouteTarget>,
     default_action: String,
@@ -53,20 +213,162 @@ impl WebhookRouter {
         let config: RoutesConfig =
             serde_yaml::from_str(yaml).context("Failed to parse routes YAML")?;
 
-        let routes = config
-            .routes
-            .into_iter()
-            .map(|(name, entry)| {
-                (
-                    name.clone(),
-                    RouteTarget {
+        let mut routes = HashMap::new();
+        for (name, entry) in config.routes {
+            if let Some(ref batch) = entry.batch {
+                if batch.max_size == 0 {
+                    return Err(anyhow!("Route {}: batch.max_size must be at least 1", name));
+                }
+            }
+
+            let destinations = [
+                entry.url.is_some(),
+                entry.service.is_some(),
+                entry.pool.is_some(),
+                entry.kafka.is_some(),
+                entry.redis.is_some(),
+                entry.exec.is_some(),
+                entry.grpc.is_some(),
+            ];
+            if destinations.iter().filter(|set| **set).count() != 1 {
+                return Err(anyhow!(
+                    "Route {}: set one of url, service, pool, kafka, redis, exec or grpc",
+                    name
+                ));
+            }
+
+            let (url, service, pool, kind) = if let Some(url) = entry.url {
+                match Url::parse(&url) {
+                    Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+                    _ => return Err(anyhow!("Route {}: invalid URL {}", name, url)),
+                }
+                (url, None, None, TargetKind::Http)
+            } else if let Some(pool) = entry.pool {
+                let pool = TargetPool::from_config(pool)
+                    .with_context(|| format!("Route {}: invalid pool", name))?;
+                let url = pool.primary_url().to_string();
+                (url, None, Some(Arc::new(pool)), TargetKind::Http)
+            } else if let Some(service) = entry.service {
+                let Some(port) = entry.port else {
+                    return Err(anyhow!("Route {}: service routes need a port", name));
+                };
+                // Pods are dialled by IP, which their certificates can't be
+                // verified against, so service routes are plain HTTP only
+                if entry.scheme != "http" {
+                    return Err(anyhow!(
+                        "Route {}: service routes only support the http scheme, not {}",
                         name,
-                        url: entry.url,
-                        timeout_seconds: entry.timeout_seconds,
-                    },
-                )
-            })
-            .collect();
+                        entry.scheme
+                    ));
+                }
+                if !entry.base_path.is_empty() && !entry.base_path.starts_with('/') {
+                    return Err(anyhow!("Route {}: base_path must start with /", name));
+                }
+
+                let service = ServiceRef {
+                    name: service,
+                    namespace: entry.namespace,
+                    port,
+                    scheme: entry.scheme,
+                    base_path: entry.base_path,
+                };
+                let url = format!(
+                    "{}://{}.{}.svc{}",
+                    service.scheme, service.name, service.namespace, service.base_path
+                );
+                (url, Some(service), None, TargetKind::Http)
+            } else if let Some(kafka) = entry.kafka {
+                let kafka = KafkaTarget::from_config(kafka)
+                    .with_context(|| format!("Route {}: invalid kafka target", name))?;
+                let url = format!("kafka://{}", kafka.topic);
+                (url, None, None, TargetKind::Kafka(kafka))
+            } else if let Some(redis) = entry.redis {
+                let redis = RedisTarget::from_config(redis)
+                    .with_context(|| format!("Route {}: invalid redis target", name))?;
+                (redis.url(), None, None, TargetKind::Redis(redis))
+            } else if let Some(exec) = entry.exec {
+                let exec = ExecTarget::from_config(exec)
+                    .with_context(|| format!("Route {}: invalid exec target", name))?;
+                let url = format!("exec:{}", exec.program);
+                (url, None, None, TargetKind::Exec(exec))
+            } else if let Some(grpc) = entry.grpc {
+                let grpc = GrpcTarget::from_config(grpc)
+                    .with_context(|| format!("Route {}: invalid grpc target", name))?;
+                (grpc.describe(), None, None, TargetKind::Grpc(grpc))
+            } else {
+                unreachable!("exactly one destination is set")
+            };
+
+            let methods = match entry.methods {
+                Some(methods) => Some(
+                    methods
+                        .iter()
+                        .map(|m| validation::parse_method(m))
+                        .collect::<Result<Vec<_>, _>>()
+                        .with_context(|| format!("Route {}: invalid method", name))?,
+                ),
+                None => None,
+            };
+
+            let headers = HeaderPolicy::from_rules(entry.headers)
+                .with_context(|| format!("Route {}: invalid header policy", name))?;
+
+            if entry.mirror.is_some() && entry.batch.is_some() {
+                return Err(anyhow!("Route {}: batched routes cannot be mirrored", name));
+            }
+            if !matches!(kind, TargetKind::Http) {
+                if entry.batch.is_some() {
+                    return Err(anyhow!("Route {}: only HTTP routes can be batched", name));
+                }
+                if entry.mirror.is_some() {
+                    return Err(anyhow!("Route {}: only HTTP routes can be mirrored", name));
+                }
+            }
+
+            let archive = match entry.archive {
+                Some(archive) => Some(
+                    Archive::from_config(archive)
+                        .with_context(|| format!("Route {}: invalid archive", name))?,
+                ),
+                None => None,
+            };
+            // Hourly archive batches take webhooks once delivered, which for
+            // batch routes only happens later in the batcher
+            if archive.as_ref().is_some_and(|a| a.format.is_batched()) && entry.batch.is_some() {
+                return Err(anyhow!(
+                    "Route {}: batched routes can only be archived as json",
+                    name
+                ));
+            }
+
+            let mut target = RouteTarget {
+                name: name.clone(),
+                kind,
+                url,
+                service,
+                pool,
+                timeout_seconds: entry.timeout_seconds,
+                batch: entry.batch,
+                accept_encoding: entry.accept_encoding,
+                url_rules: UrlRules {
+                    append_path: entry.append_path,
+                    append_query: entry.append_query,
+                    traversal: entry.path_traversal,
+                },
+                methods,
+                headers,
+                client: entry.client,
+                mirror: None,
+                archive,
+            };
+            if let Some(mirror) = entry.mirror {
+                let mirror = Mirror::from_config(mirror, &target)
+                    .with_context(|| format!("Route {}: invalid mirror", name))?;
+                target.mirror = Some(Arc::new(mirror));
+            }
+
+            routes.insert(name, target);
+        }
 
         let (default_action, default_url) = match config.default {
             Some(d) => (d.action, d.url),
@@ -84,9 +386,40 @@ impl WebhookRouter {
         self.routes.len()
     }
 
-    /// Route a webhook path to a target
+    pub fn has_route(&self, name: &str) -> bool {
+        self.routes.contains_key(name)
+    }
+
+    /// The name of a route that batches webhooks, if any does
+    pub fn batch_route(&self) -> Option<&str> {
+        self.routes
+            .iter()
+            .find(|(_, target)| target.batch.is_some())
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Whether any route publishes to Kafka
+    pub fn has_kafka_routes(&self) -> bool {
+        self.routes
+            .values()
+            .any(|target| matches!(target.kind, TargetKind::Kafka(_)))
+    }
+
+    /// Whether any route writes to Redis
+    pub fn has_redis_routes(&self) -> bool {
+        self.routes
+            .values()
+            .any(|target| matches!(target.kind, TargetKind::Redis(_)))
+    }
+
+    pub fn targets(&self) -> impl Iterator<Item = &RouteTarget> {
+        self.routes.values()
+    }
+
+    /// Route a webhook path to a target, falling back to the named route
+    /// (a queue's default route) before the global default action
     /// Returns (target, remaining_path)
-    pub fn route(&self, path: &str) -> Result<(&RouteTarget, String)> {
+    pub fn route(&self, path: &str, default_route: Option<&str>) -> Result<(&RouteTarget, String)> {
         // Parse path: /webhook/<service>/<rest>
         let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
 
@@ -106,6 +439,10 @@ impl WebhookRouter {
             return Ok((target, rest_path));
         }
 
+        if let Some(target) = default_route.and_then(|name| self.routes.get(name)) {
+            return Ok((target, rest_path));
+        }
+
         // Handle default action
         match self.default_action.as_str() {
             "forward" => {
@@ -120,7 +457,7 @@ impl WebhookRouter {
                     Err(anyhow!("No route found for service: {}", service))
                 }
             }
-            "drop" | _ => Err(anyhow!("No route found for service: {} (dropping)", service)),
+            _ => Err(anyhow!("No route found for service: {} (dropping)", service)),
         }
     }
 }
@@ -146,17 +483,215 @@ default:
         let router = WebhookRouter::from_yaml(yaml).unwrap();
 
         // Test n8n route
-        let (target, rest) = router.route("/webhook/n8n/my-workflow").unwrap();
+        let (target, rest) = router.route("/webhook/n8n/my-workflow", None).unwrap();
         assert_eq!(target.name, "n8n");
         assert_eq!(target.url, "https://n8n.example.com");
         assert_eq!(rest, "/my-workflow");
 
         // Test gitea route
-        let (target, rest) = router.route("/webhook/gitea/push").unwrap();
+        let (target, rest) = router.route("/webhook/gitea/push", None).unwrap();
         assert_eq!(target.name, "gitea");
         assert_eq!(rest, "/push");
 
         // Test unknown route
-        assert!(router.route("/webhook/unknown/test").is_err());
+        assert!(router.route("/webhook/unknown/test", None).is_err());
+    }
+
+    #[test]
+    fn test_batch_config() {
+        let yaml = r#"
+routes:
+  dagster:
+    url: "https://dagster.example.com/ingest"
+    batch:
+      max_size: 50
+      format: ndjson
+  n8n:
+    url: "https://n8n.example.com"
+    batch: {}
+  gitea:
+    url: "https://gitea.example.com"
+"#;
+
+        let router = WebhookRouter::from_yaml(yaml).unwrap();
+
+        let (target, _) = router.route("/webhook/dagster/events", None).unwrap();
+        let batch = target.batch.as_ref().unwrap();
+        assert_eq!(batch.max_size, 50);
+        assert_eq!(batch.max_wait_ms, 5000);
+        assert_eq!(batch.format, BatchFormat::Ndjson);
+
+        let (target, _) = router.route("/webhook/n8n/bulk", None).unwrap();
+        let batch = target.batch.as_ref().unwrap();
+        assert_eq!(batch.max_size, 10);
+        assert_eq!(batch.format, BatchFormat::JsonArray);
+
+        let (target, _) = router.route("/webhook/gitea/push", None).unwrap();
+        assert!(target.batch.is_none());
+        assert!(router.batch_route().is_some());
+
+        let yaml = r#"
+routes:
+  dagster:
+    url: "https://dagster.example.com"
+    batch:
+      max_size: 0
+"#;
+        assert!(WebhookRouter::from_yaml(yaml).is_err());
+    }
+
+    #[test]
+    fn test_url_rules() {
+        let yaml = r#"
+routes:
+  immich:
+    url: "https://immich.example.com/api/hook"
+    append_path: false
+    append_query: false
+  files:
+    url: "https://files.example.com"
+    path_traversal: normalize
+"#;
+
+        let router = WebhookRouter::from_yaml(yaml).unwrap();
+
+        let (target, _) = router.route("/webhook/immich/asset", None).unwrap();
+        assert!(!target.url_rules.append_path);
+        assert!(!target.url_rules.append_query);
+        assert_eq!(target.url_rules.traversal, TraversalPolicy::Reject);
+
+        let (target, _) = router.route("/webhook/files/a", None).unwrap();
+        assert!(target.url_rules.append_path);
+        assert_eq!(target.url_rules.traversal, TraversalPolicy::Normalize);
+
+        let yaml = r#"
+routes:
+  broken:
+    url: "files.example.com/upload"
+"#;
+        assert!(WebhookRouter::from_yaml(yaml).is_err());
+    }
+
+    #[test]
+    fn test_service_routes() {
+        let yaml = r#"
+routes:
+  n8n:
+    service: n8n
+    namespace: n8n
+    port: http
+    base_path: /webhook
+  dagster:
+    service: dagster-webserver
+    port: 3000
+"#;
+
+        let router = WebhookRouter::from_yaml(yaml).unwrap();
+
+        let (target, _) = router.route("/webhook/n8n/flow", None).unwrap();
+        let service = target.service.as_ref().unwrap();
+        assert_eq!(service.namespace, "n8n");
+        assert_eq!(service.port, ServicePort::Name("http".to_string()));
+        assert_eq!(target.url, "http://n8n.n8n.svc/webhook");
+
+        let (target, _) = router.route("/webhook/dagster/run", None).unwrap();
+        let service = target.service.as_ref().unwrap();
+        assert_eq!(service.namespace, "default");
+        assert_eq!(service.port, ServicePort::Number(3000));
+
+        for invalid in [
+            "routes:\n  n8n:\n    service: n8n\n",
+            "routes:\n  n8n:\n    service: n8n\n    port: http\n    url: \"https://n8n.example.com\"\n",
+            "routes:\n  n8n:\n    timeout_seconds: 5\n",
+            "routes:\n  n8n:\n    service: n8n\n    port: https\n    scheme: https\n",
+        ] {
+            assert!(WebhookRouter::from_yaml(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_method_allow_list() {
+        let yaml = r#"
+routes:
+  gitea:
+    url: "https://gitea.example.com"
+    methods: [post, PUT]
+  n8n:
+    url: "https://n8n.example.com"
+"#;
+
+        let router = WebhookRouter::from_yaml(yaml).unwrap();
+
+        let (target, _) = router.route("/webhook/gitea/push", None).unwrap();
+        assert!(target.check_method(&Method::POST).is_ok());
+        assert!(target.check_method(&Method::PUT).is_ok());
+        let err = target.check_method(&Method::DELETE).unwrap_err();
+        assert_eq!(err.reason(), "method_not_allowed");
+
+        let (target, _) = router.route("/webhook/n8n/hook", None).unwrap();
+        assert!(target.check_method(&Method::DELETE).is_ok());
+
+        let yaml = r#"
+routes:
+  gitea:
+    url: "https://gitea.example.com"
+    methods: ["NOT A METHOD"]
+"#;
+        assert!(WebhookRouter::from_yaml(yaml).is_err());
+    }
+
+    #[test]
+    fn test_accept_encoding() {
+        let yaml = r#"
+routes:
+  immich:
+    url: "https://immich.example.com"
+    accept_encoding: [gzip, brotli]
+  gitea:
+    url: "https://gitea.example.com"
+"#;
+
+        let router = WebhookRouter::from_yaml(yaml).unwrap();
+
+        let (target, _) = router.route("/webhook/immich/asset", None).unwrap();
+        assert_eq!(
+            target.accept_encoding,
+            vec![ContentEncoding::Gzip, ContentEncoding::Br]
+        );
+
+        let (target, _) = router.route("/webhook/gitea/push", None).unwrap();
+        assert!(target.accept_encoding.is_empty());
+
+        let yaml = r#"
+routes:
+  immich:
+    url: "https://immich.example.com"
+    accept_encoding: [deflate]
+"#;
+        assert!(WebhookRouter::from_yaml(yaml).is_err());
+    }
+
+    #[test]
+    fn test_queue_default_route() {
+        let yaml = r#"
+routes:
+  n8n:
+    url: "https://n8n.example.com"
+  github:
+    url: "https://github-ingest.example.com"
+"#;
+
+        let router = WebhookRouter::from_yaml(yaml).unwrap();
+
+        // Known services still use their own route
+        let (target, _) = router.route("/webhook/n8n/flow", Some("github")).unwrap();
+        assert_eq!(target.name, "n8n");
+
+        // Unknown services fall back to the queue's default route
+        let (target, rest) = router.route("/webhook/gh/push", Some("github")).unwrap();
+        assert_eq!(target.name, "github");
+        assert_eq!(rest, "/push");
+
+        assert!(router.route("/webhook/gh/push", None).is_err());
     }
 }
@@ -0,0 +1,319 @@
+use aws_sdk_sqs::types::Message;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tracing::{error, info};
+
+use crate::sqs::{self, SqsConsumer};
+
+/// Messages handled in order by a single worker. On a standard queue every
+/// message is its own unit; on a FIFO queue a unit holds all the messages of
+/// one message group from a receive.
+type Unit = Vec<Message>;
+
+/// A queue together with the units received from it awaiting dispatch
+struct Lane {
+    consumer: Arc<SqsConsumer>,
+    pending: Mutex<VecDeque<Unit>>,
+    /// Wakes the poller once every buffered message has been dispatched
+    drained: Notify,
+    /// The queue's own concurrency budget
+    permits: Arc<Semaphore>,
+}
+
+/// Schedulable state of a queue when choosing where the next message comes from
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    priority: u32,
+    weight: u32,
+    ready: bool,
+}
+
+/// Polls several queues and dispatches their messages to workers.
+///
+/// Each unit of work needs a permit from the global budget and from its
+/// queue's budget. When several queues have messages waiting, the highest
+/// priority queue is always served first, and queues of equal priority are
+/// served by smooth weighted round-robin.
+///
+/// Messages from FIFO queues are grouped by message group: groups run in
+/// parallel, but the messages of a group are handled one after another, and
+/// the first failure releases the rest of the group so nothing is delivered
+/// out of order.
+pub struct Scheduler {
+    lanes: Vec<Lane>,
+    global: Arc<Semaphore>,
+    /// Wakes the dispatcher when messages arrive or a worker finishes
+    wake: Notify,
+}
+
+impl Scheduler {
+    pub fn new(consumers: Vec<Arc<SqsConsumer>>, max_concurrency: usize) -> Self {
+        let lanes = consumers
+            .into_iter()
+            .map(|consumer| Lane {
+                permits: Arc::new(Semaphore::new(consumer.config().concurrency)),
+                consumer,
+                pending: Mutex::new(VecDeque::new()),
+                drained: Notify::new(),
+            })
+            .collect();
+
+        Scheduler {
+            lanes,
+            global: Arc::new(Semaphore::new(max_concurrency)),
+            wake: Notify::new(),
+        }
+    }
+
+    /// Start a poller per queue and hand every received message to `handler`,
+    /// which returns whether the message was processed successfully
+    pub async fn run<F, Fut>(self: Arc<Self>, handler: F)
+    where
+        F: Fn(Arc<SqsConsumer>, Message) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        for index in 0..self.lanes.len() {
+            tokio::spawn(Arc::clone(&self).poll(index));
+        }
+
+        let handler = Arc::new(handler);
+
+        let mut current = vec![0; self.lanes.len()];
+
+        loop {
+            let global = Arc::clone(&self.global)
+                .acquire_owned()
+                .await
+                .expect("scheduler semaphore closed");
+
+            let (index, unit, permit) = loop {
+                if let Some(next) = self.next(&mut current) {
+                    break next;
+                }
+                self.wake.notified().await;
+            };
+
+            let consumer = Arc::clone(&self.lanes[index].consumer);
+            let handler = Arc::clone(&handler);
+            let scheduler = Arc::clone(&self);
+
+            tokio::spawn(async move {
+                let mut messages = unit.into_iter();
+                while let Some(message) = messages.next() {
+                    if !handler(Arc::clone(&consumer), message).await {
+                        // Leave the rest of the group for redelivery, after
+                        // the failed message
+                        for rest in messages {
+                            if let Some(ref handle) = rest.receipt_handle {
+                                consumer.release(handle);
+                            }
+                        }
+                        break;
+                    }
+                }
+
+                drop(permit);
+                drop(global);
+                scheduler.wake.notify_one();
+            });
+        }
+    }
+
+    /// Poll a queue, buffering what it returns for the dispatcher. The queue
+    /// is only polled again once the previous messages have been dispatched,
+    /// so a busy relay doesn't hold more messages than it can work on.
+    async fn poll(self: Arc<Self>, index: usize) {
+        let lane = &self.lanes[index];
+        let queue = lane.consumer.name().to_string();
+        let poll_interval = Duration::from_millis(lane.consumer.config().poll_interval_ms);
+
+        loop {
+            while !lane.pending.lock().unwrap().is_empty() {
+                lane.drained.notified().await;
+            }
+
+            match lane.consumer.receive_messages().await {
+                Ok(messages) => {
+                    if !messages.is_empty() {
+                        info!("Received {} messages from {}", messages.len(), queue);
+
+                        let units = if lane.consumer.is_fifo() {
+                            group_messages(messages)
+                        } else {
+                            messages.into_iter().map(|m| vec![m]).collect()
+                        };
+
+                        lane.pending.lock().unwrap().extend(units);
+                        self.wake.notify_one();
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to receive messages from {}: {:?}", queue, e);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+
+            tokio::time::sleep(poll_interval).await;
+        }
+    }
+
+    /// Take the next unit to dispatch, along with its queue's permit
+    fn next(&self, current: &mut [i64]) -> Option<(usize, Unit, OwnedSemaphorePermit)> {
+        let candidates: Vec<Candidate> = self
+            .lanes
+            .iter()
+            .map(|lane| Candidate {
+                priority: lane.consumer.config().priority,
+                weight: lane.consumer.config().weight,
+                ready: lane.permits.available_permits() > 0
+                    && !lane.pending.lock().unwrap().is_empty(),
+            })
+            .collect();
+
+        let index = pick(&candidates, current)?;
+        let lane = &self.lanes[index];
+        let permit = Arc::clone(&lane.permits).try_acquire_owned().ok()?;
+
+        let mut pending = lane.pending.lock().unwrap();
+        let unit = pending.pop_front()?;
+        if pending.is_empty() {
+            lane.drained.notify_one();
+        }
+
+        Some((index, unit, permit))
+    }
+}
+
+/// Split FIFO messages into one unit per message group, keeping the order in
+/// which SQS returned them within each group
+fn group_messages(messages: Vec<Message>) -> Vec<Unit> {
+    let mut units: Vec<Unit> = Vec::new();
+    let mut groups: HashMap<String, usize> = HashMap::new();
+
+    for message in messages {
+        let group = sqs::message_group_id(&message)
+            .unwrap_or_default()
+            .to_string();
+        match groups.get(&group) {
+            Some(&i) => units[i].push(message),
+            None => {
+                groups.insert(group, units.len());
+                units.push(vec![message]);
+            }
+        }
+    }
+
+    units
+}
+
+/// Choose among the ready candidates with the highest priority using smooth
+/// weighted round-robin. `current` carries each candidate's running weight
+/// between calls.
+fn pick(candidates: &[Candidate], current: &mut [i64]) -> Option<usize> {
+    let top = candidates
+        .iter()
+        .filter(|c| c.ready)
+        .map(|c| c.priority)
+        .max()?;
+
+    let mut total = 0;
+    let mut best: Option<usize> = None;
+
+    for (i, candidate) in candidates.iter().enumerate() {
+        if !candidate.ready || candidate.priority != top {
+            continue;
+        }
+
+        current[i] += candidate.weight as i64;
+        total += candidate.weight as i64;
+
+        if best.is_none_or(|b| current[i] > current[b]) {
+            best = Some(i);
+        }
+    }
+
+    let best = best?;
+    current[best] -= total;
+    Some(best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_sqs::types::MessageSystemAttributeName;
+
+    fn candidate(priority: u32, weight: u32, ready: bool) -> Candidate {
+        Candidate {
+            priority,
+            weight,
+            ready,
+        }
+    }
+
+    #[test]
+    fn test_pick_weighted() {
+        let candidates = [candidate(0, 3, true), candidate(0, 1, true)];
+        let mut current = [0; 2];
+
+        let picks: Vec<usize> = (0..8)
+            .map(|_| pick(&candidates, &mut current).unwrap())
+            .collect();
+
+        assert_eq!(picks.iter().filter(|&&i| i == 0).count(), 6);
+        assert_eq!(picks.iter().filter(|&&i| i == 1).count(), 2);
+        // Smooth: the lighter queue is interleaved rather than starved until the end
+        assert_eq!(&picks[..4], &[0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_pick_priority() {
+        let mut current = [0; 3];
+
+        let candidates = [
+            candidate(0, 10, true),
+            candidate(5, 1, true),
+            candidate(5, 1, false),
+        ];
+        for _ in 0..5 {
+            assert_eq!(pick(&candidates, &mut current), Some(1));
+        }
+
+        // Once the high priority queue has nothing ready, lower priorities run
+        let candidates = [
+            candidate(0, 10, true),
+            candidate(5, 1, false),
+            candidate(5, 1, false),
+        ];
+        assert_eq!(pick(&candidates, &mut current), Some(0));
+
+        let candidates = [candidate(0, 1, false)];
+        assert_eq!(pick(&candidates, &mut [0]), None);
+    }
+
+    #[test]
+    fn test_group_messages() {
+        let message = |id: &str, group: &str| {
+            Message::builder()
+                .message_id(id)
+                .attributes(MessageSystemAttributeName::MessageGroupId, group)
+                .build()
+        };
+
+        let units = group_messages(vec![
+            message("1", "orders"),
+            message("2", "refunds"),
+            message("3", "orders"),
+            message("4", "orders"),
+            message("5", "refunds"),
+        ]);
+
+        let ids: Vec<Vec<&str>> = units
+            .iter()
+            .map(|unit| unit.iter().map(|m| m.message_id().unwrap()).collect())
+            .collect();
+        assert_eq!(ids, vec![vec!["1", "3", "4"], vec!["2", "5"]]);
+    }
+}
@@ -1,78 +1,730 @@
-use anyhow::Result;
-use aws_sdk_sqs::{types::Message, Client};
+use anyhow::{anyhow, Result};
+use aws_sdk_sqs::{
+    types::{
+        BatchResultErrorEntry, ChangeMessageVisibilityBatchRequestEntry,
+        DeleteMessageBatchRequestEntry, Message, MessageAttributeValue, MessageSystemAttributeName,
+        QueueAttributeName,
+    },
+    Client,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use indexmap::IndexMap;
+use opentelemetry::trace::SpanContext;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{debug, error, info_span, warn, Instrument};
 
-use crate::config::Config;
+use crate::claim_check::ClaimCheck;
+use crate::compression::ContentEncoding;
+use crate::config::{Config, QueueConfig};
+use crate::delivery_log::DeliveryRecord;
+use crate::metrics::Metrics;
+use crate::telemetry;
+use crate::validation::MessageError;
+
+/// SQS accepts at most ten entries per batch request
+const MAX_BATCH_ENTRIES: usize = 10;
+
+/// How long acknowledged messages may wait for a full delete batch
+const DELETE_FLUSH_INTERVAL: Duration = Duration::from_millis(250);
 
 pub struct SqsConsumer {
     client: Client,
-    queue_url: String,
+    queue: QueueConfig,
+    /// Receipt handles of messages received but not yet deleted or released
+    in_flight: Mutex<HashSet<String>>,
+    /// Receipt handles of processed messages waiting to be deleted, with the
+    /// span each was processed in
+    acknowledged: Mutex<Vec<(String, SpanContext)>>,
+    delete_ready: Notify,
+    metrics: Arc<Metrics>,
+}
+
+/// A failed entry from a batch request, indexed into the submitted chunk
+struct BatchFailure {
+    index: usize,
+    sender_fault: bool,
+    code: String,
+    message: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl From<&BatchResultErrorEntry> for BatchFailure {
+    fn from(entry: &BatchResultErrorEntry) -> Self {
+        BatchFailure {
+            index: entry.id.parse().unwrap_or_default(),
+            sender_fault: entry.sender_fault,
+            code: entry.code.clone(),
+            message: entry.message.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// A webhook as queued by the Lambda transformer. Field names are camelCase
+/// on the wire, and when the relay writes a webhook out to Redis or an
+/// archive; snake_case names are accepted too.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct WebhookMessage {
+    /// Message format version, absent on messages from before versioning
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
     pub path: String,
     pub method: String,
-    pub headers: HashMap<String, String>,
-    pub body: String,
+    pub headers: IndexMap<String, String>,
+    /// Every value of each header, for headers that may repeat. Takes
+    /// precedence over `headers`, which only holds one value per name.
+    #[serde(
+        default,
+        alias = "multi_value_headers",
+        skip_serializing_if = "IndexMap::is_empty"
+    )]
+    pub multi_value_headers: IndexMap<String, Vec<String>>,
     #[serde(default)]
+    pub body: String,
+    #[serde(default, alias = "is_base64_encoded")]
     pub is_base64_encoded: bool,
-    #[serde(default)]
-    pub query_string_parameters: HashMap<String, String>,
+    #[serde(default, alias = "query_string_parameters")]
+    pub query_string_parameters: IndexMap<String, String>,
+    /// Every value of each query parameter, as with `multi_value_headers`
+    #[serde(
+        default,
+        alias = "multi_value_query_string_parameters",
+        skip_serializing_if = "IndexMap::is_empty"
+    )]
+    pub multi_value_query_string_parameters: IndexMap<String, Vec<String>>,
+    /// RFC 3339 time the webhook was received
     pub timestamp: String,
-    #[serde(default)]
+    #[serde(default, alias = "source_ip")]
     pub source_ip: String,
+    /// Compression applied to the body, which is then base64 encoded
+    #[serde(
+        default,
+        alias = "content_encoding",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub content_encoding: Option<ContentEncoding>,
+    /// Reference to a body too large for SQS, stored in an object store
+    #[serde(
+        default,
+        alias = "claim_check",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub claim_check: Option<ClaimCheck>,
+    /// FIFO message group the webhook was received in. Taken from the SQS
+    /// message attributes rather than the message body.
+    #[serde(skip)]
+    pub message_group_id: Option<String>,
+    /// Set by the ingestion side when the sender is waiting for the target's
+    /// response, which is then published to the reply queue under this ID
+    #[serde(
+        default,
+        alias = "correlation_id",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub correlation_id: Option<String>,
 }
 
-impl SqsConsumer {
-    pub async fn new(config: &Config) -> Result<Self> {
-        let aws_config = aws_config::from_env()
-            .region(aws_config::Region::new(config.aws_region.clone()))
-            .load()
-            .await;
+/// The FIFO message group of a received message, if it has one
+pub fn message_group_id(message: &Message) -> Option<&str> {
+    message
+        .attributes()?
+        .get(&MessageSystemAttributeName::MessageGroupId)
+        .map(String::as_str)
+}
 
-        let client = Client::new(&aws_config);
+impl WebhookMessage {
+    /// Every header in order, with repeated headers once per value. Header
+    /// names match case-insensitively.
+    pub fn header_values(&self) -> Vec<(&str, &str)> {
+        merge_values(&self.headers, &self.multi_value_headers, |a, b| {
+            a.eq_ignore_ascii_case(b)
+        })
+    }
+
+    /// Every query parameter in order, with repeated keys once per value
+    pub fn query_values(&self) -> Vec<(&str, &str)> {
+        merge_values(
+            &self.query_string_parameters,
+            &self.multi_value_query_string_parameters,
+            |a, b| a == b,
+        )
+    }
+
+    /// Remove a header from both the single and multi-value headers
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers
+            .retain(|key, _| !key.eq_ignore_ascii_case(name));
+        self.multi_value_headers
+            .retain(|key, _| !key.eq_ignore_ascii_case(name));
+    }
+
+    /// Returns the webhook body, decoding it first if it is base64 encoded
+    pub fn decoded_body(&self) -> Result<Vec<u8>, MessageError> {
+        if self.is_base64_encoded {
+            BASE64
+                .decode(&self.body)
+                .map_err(MessageError::InvalidBase64)
+        } else {
+            Ok(self.body.as_bytes().to_vec())
+        }
+    }
+
+    /// Size of the body once decoded, without decoding it
+    pub fn body_len(&self) -> usize {
+        if self.is_base64_encoded {
+            self.body.trim_end_matches('=').len() * 3 / 4
+        } else {
+            self.body.len()
+        }
+    }
+}
+
+/// Combine API Gateway's single and multi-value maps. Keys in the single map
+/// keep their position, taking all their values from the multi-value map
+/// when it has them; keys only in the multi-value map follow.
+fn merge_values<'a>(
+    single: &'a IndexMap<String, String>,
+    multi: &'a IndexMap<String, Vec<String>>,
+    same_key: impl Fn(&str, &str) -> bool,
+) -> Vec<(&'a str, &'a str)> {
+    let multi_values = |key: &str| {
+        multi
+            .iter()
+            .filter(|(k, _)| same_key(k, key))
+            .flat_map(|(k, values)| values.iter().map(move |v| (k.as_str(), v.as_str())))
+            .collect::<Vec<_>>()
+    };
+
+    let mut values = Vec::new();
+    for (key, value) in single {
+        let repeated = multi_values(key);
+        if repeated.is_empty() {
+            values.push((key.as_str(), value.as_str()));
+        } else {
+            values.extend(repeated);
+        }
+    }
+
+    for (key, repeated) in multi {
+        if !single.keys().any(|k| same_key(k, key)) {
+            values.extend(repeated.iter().map(|v| (key.as_str(), v.as_str())));
+        }
+    }
 
-        Ok(SqsConsumer {
+    values
+}
+
+/// Create an SQS client shared by all queue consumers
+pub async fn client(config: &Config) -> Client {
+    let aws_config = aws_config::from_env()
+        .region(aws_config::Region::new(config.aws_region.clone()))
+        .load()
+        .await;
+
+    Client::new(&aws_config)
+}
+
+impl SqsConsumer {
+    pub fn new(client: Client, queue: QueueConfig, metrics: Arc<Metrics>) -> Self {
+        SqsConsumer {
             client,
-            queue_url: config.sqs_queue_url.clone(),
-        })
+            queue,
+            in_flight: Mutex::new(HashSet::new()),
+            acknowledged: Mutex::new(Vec::new()),
+            delete_ready: Notify::new(),
+            metrics,
+        }
     }
 
-    pub async fn receive_messages(&self, max_messages: i32) -> Result<Vec<Message>> {
-        let response = self
+    /// Name of the queue, used in logs and metric labels
+    pub fn name(&self) -> &str {
+        &self.queue.name
+    }
+
+    pub fn config(&self) -> &QueueConfig {
+        &self.queue
+    }
+
+    /// FIFO queue names always end in `.fifo`
+    pub fn is_fifo(&self) -> bool {
+        self.queue.url.ends_with(".fifo")
+    }
+
+    /// Receive messages from the queue. Every returned message is tracked as
+    /// in flight, and kept invisible by the heartbeat until it is deleted or
+    /// released.
+    pub async fn receive_messages(&self) -> Result<Vec<Message>> {
+        let mut request = self
             .client
             .receive_message()
-            .queue_url(&self.queue_url)
-            .max_number_of_messages(max_messages)
-            .wait_time_seconds(20) // Long polling
-            .visibility_timeout(60)
-            .send()
-            .await?;
+            .queue_url(&self.queue.url)
+            .max_number_of_messages(self.queue.max_messages)
+            .wait_time_seconds(self.queue.wait_time_seconds) // Long polling
+            .visibility_timeout(self.queue.visibility_timeout_seconds)
+            .set_message_attribute_names(Some(telemetry::TRACE_FIELDS.map(String::from).to_vec()));
+
+        if self.is_fifo() {
+            request =
+                request.message_system_attribute_names(MessageSystemAttributeName::MessageGroupId);
+        }
 
-        Ok(response.messages.unwrap_or_default())
+        let response = self.call("ReceiveMessage", request.send()).await?;
+
+        let messages = response.messages.unwrap_or_default();
+        self.metrics
+            .messages_received
+            .with_label_values(&[&self.queue.name])
+            .inc_by(messages.len() as f64);
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        for handle in messages.iter().filter_map(|m| m.receipt_handle.as_ref()) {
+            in_flight.insert(handle.clone());
+        }
+
+        Ok(messages)
+    }
+
+    /// Stop extending the visibility of a message that could not be
+    /// processed, so that it returns to the queue after the visibility timeout
+    pub fn release(&self, receipt_handle: &str) {
+        self.in_flight.lock().unwrap().remove(receipt_handle);
+    }
+
+    /// Mark a message as processed. Acknowledged messages are deleted in
+    /// batches by [`SqsConsumer::run_deleter`], in a span linked to `trace`,
+    /// the span the message was processed in.
+    pub fn acknowledge(&self, receipt_handle: String, trace: SpanContext) {
+        let mut acknowledged = self.acknowledged.lock().unwrap();
+        acknowledged.push((receipt_handle, trace));
+        if acknowledged.len() >= MAX_BATCH_ENTRIES {
+            self.delete_ready.notify_one();
+        }
     }
 
-    pub async fn delete_message(&self, receipt_handle: &str) -> Result<()> {
-        self.client
-            .delete_message()
-            .queue_url(&self.queue_url)
-            .receipt_handle(receipt_handle)
-            .send()
-            .await?;
+    /// Delete acknowledged messages whenever a full batch is ready, or
+    /// periodically for whatever has accumulated
+    pub async fn run_deleter(self: Arc<Self>) {
+        loop {
+            tokio::select! {
+                _ = self.delete_ready.notified() => {}
+                _ = tokio::time::sleep(DELETE_FLUSH_INTERVAL) => {}
+            }
+
+            let acknowledged = std::mem::take(&mut *self.acknowledged.lock().unwrap());
+            if acknowledged.is_empty() {
+                continue;
+            }
+
+            let (handles, traces): (Vec<String>, Vec<SpanContext>) =
+                acknowledged.into_iter().unzip();
+            let span = info_span!("delete", queue = %self.queue.name, messages = handles.len());
+            telemetry::link(&span, traces);
+            if let Err(e) = self.delete_message_batch(&handles).instrument(span).await {
+                error!("Failed to delete messages from {}: {}", self.queue.name, e);
+            }
+        }
+    }
+
+    /// Delete several messages using `DeleteMessageBatch`, ten at a time.
+    ///
+    /// Entries that fail through no fault of the request (throttling, internal
+    /// errors) are retried once; anything still failing is reported in the
+    /// returned error.
+    pub async fn delete_message_batch(&self, receipt_handles: &[String]) -> Result<()> {
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            for handle in receipt_handles {
+                in_flight.remove(handle);
+            }
+        }
+
+        let mut failures = Vec::new();
+
+        for chunk in receipt_handles.chunks(MAX_BATCH_ENTRIES) {
+            let failed = self.delete_chunk(chunk).await?;
+
+            let (retryable, permanent): (Vec<_>, Vec<_>) =
+                failed.into_iter().partition(|f| !f.sender_fault);
+            failures.extend(permanent);
+
+            if !retryable.is_empty() {
+                let retry: Vec<String> = retryable.iter().map(|f| chunk[f.index].clone()).collect();
+                failures.extend(self.delete_chunk(&retry).await?);
+            }
+        }
+
+        if !failures.is_empty() {
+            let reasons: Vec<String> = failures
+                .iter()
+                .map(|f| format!("{}: {}", f.code, f.message))
+                .collect();
+            return Err(anyhow!(
+                "{} of {} messages could not be deleted ({})",
+                failures.len(),
+                receipt_handles.len(),
+                reasons.join(", ")
+            ));
+        }
 
         Ok(())
     }
 
+    async fn delete_chunk(&self, receipt_handles: &[String]) -> Result<Vec<BatchFailure>> {
+        let entries = receipt_handles
+            .iter()
+            .enumerate()
+            .map(|(i, handle)| {
+                DeleteMessageBatchRequestEntry::builder()
+                    .id(i.to_string())
+                    .receipt_handle(handle)
+                    .build()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let request = self
+            .client
+            .delete_message_batch()
+            .queue_url(&self.queue.url)
+            .set_entries(Some(entries))
+            .send();
+        let response = self.call("DeleteMessageBatch", request).await?;
+
+        Ok(response.failed.iter().map(BatchFailure::from).collect())
+    }
+
+    /// Extend the visibility timeout of every in-flight message every half
+    /// timeout, so that slow forwards and buffered batches are not redelivered
+    pub async fn run_heartbeat(self: Arc<Self>) {
+        let period = Duration::from_secs((self.queue.visibility_timeout_seconds as u64 / 2).max(1));
+        let mut interval = tokio::time::interval(period);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let handles: Vec<String> = self.in_flight.lock().unwrap().iter().cloned().collect();
+            if handles.is_empty() {
+                continue;
+            }
+
+            debug!(
+                "Extending visibility of {} in-flight messages",
+                handles.len()
+            );
+
+            for chunk in handles.chunks(MAX_BATCH_ENTRIES) {
+                match self.extend_visibility(chunk).await {
+                    Ok(failed) => {
+                        // Usually the message was deleted between the snapshot
+                        // and the request; either way the handle is no longer
+                        // worth extending
+                        for failure in failed {
+                            debug!(
+                                "Could not extend visibility: {}: {}",
+                                failure.code, failure.message
+                            );
+                            self.release(&chunk[failure.index]);
+                        }
+                    }
+                    Err(e) => warn!("Failed to extend message visibility: {}", e),
+                }
+            }
+        }
+    }
+
+    /// Move a message that can never be delivered to the queue's dead-letter
+    /// queue, with the reason attached as message attributes. Returns false
+    /// when no dead-letter queue is configured.
+    pub async fn dead_letter(
+        &self,
+        body: &str,
+        error: &MessageError,
+        group_id: Option<&str>,
+        message_id: Option<&str>,
+    ) -> Result<bool> {
+        let Some(ref url) = self.queue.dead_letter_url else {
+            return Ok(false);
+        };
+
+        let attribute = |value: &str| {
+            MessageAttributeValue::builder()
+                .data_type("String")
+                .string_value(value)
+                .build()
+        };
+
+        let mut request = self
+            .client
+            .send_message()
+            .queue_url(url)
+            .message_body(body)
+            .message_attributes("DeadLetterReason", attribute(error.reason())?)
+            .message_attributes("DeadLetterError", attribute(&error.to_string())?)
+            .message_attributes("SourceQueue", attribute(&self.queue.name)?);
+
+        if url.ends_with(".fifo") {
+            request = request.message_group_id(group_id.unwrap_or(&self.queue.name));
+            if let Some(id) = message_id {
+                request = request.message_deduplication_id(id);
+            }
+        }
+
+        self.call("SendMessage", request.send()).await?;
+        Ok(true)
+    }
+
+    /// Publish a target's response to the queue's reply queue. Returns false
+    /// when no reply queue is configured.
+    pub async fn reply(&self, record: &DeliveryRecord, correlation_id: &str) -> Result<bool> {
+        let Some(ref url) = self.queue.reply_url else {
+            return Ok(false);
+        };
+
+        let correlation = MessageAttributeValue::builder()
+            .data_type("String")
+            .string_value(correlation_id)
+            .build()?;
+
+        let mut request = self
+            .client
+            .send_message()
+            .queue_url(url)
+            .message_body(serde_json::to_string(record)?)
+            .message_attributes("CorrelationId", correlation);
+
+        if url.ends_with(".fifo") {
+            request = request
+                .message_group_id(correlation_id)
+                .message_deduplication_id(correlation_id);
+        }
+
+        self.call("SendMessage", request.send()).await?;
+        Ok(true)
+    }
+
+    async fn extend_visibility(&self, receipt_handles: &[String]) -> Result<Vec<BatchFailure>> {
+        let entries = receipt_handles
+            .iter()
+            .enumerate()
+            .map(|(i, handle)| {
+                ChangeMessageVisibilityBatchRequestEntry::builder()
+                    .id(i.to_string())
+                    .receipt_handle(handle)
+                    .visibility_timeout(self.queue.visibility_timeout_seconds)
+                    .build()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let request = self
+            .client
+            .change_message_visibility_batch()
+            .queue_url(&self.queue.url)
+            .set_entries(Some(entries))
+            .send();
+        let response = self.call("ChangeMessageVisibilityBatch", request).await?;
+
+        Ok(response.failed.iter().map(BatchFailure::from).collect())
+    }
+
     pub async fn check_connectivity(&self) -> Result<()> {
-        self.client
+        let request = self
+            .client
             .get_queue_attributes()
-            .queue_url(&self.queue_url)
-            .attribute_names(aws_sdk_sqs::types::QueueAttributeName::ApproximateNumberOfMessages)
-            .send()
-            .await?;
+            .queue_url(&self.queue.url)
+            .attribute_names(QueueAttributeName::ApproximateNumberOfMessages)
+            .send();
+        self.call("GetQueueAttributes", request).await?;
 
         Ok(())
     }
+
+    /// Publish the queue's approximate message counts every `period`
+    pub async fn run_depth_poller(self: Arc<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+
+        loop {
+            interval.tick().await;
+
+            let request = self
+                .client
+                .get_queue_attributes()
+                .queue_url(&self.queue.url)
+                .attribute_names(QueueAttributeName::ApproximateNumberOfMessages)
+                .attribute_names(QueueAttributeName::ApproximateNumberOfMessagesNotVisible)
+                .attribute_names(QueueAttributeName::ApproximateNumberOfMessagesDelayed)
+                .send();
+            let attributes = match self.call("GetQueueAttributes", request).await {
+                Ok(response) => response.attributes.unwrap_or_default(),
+                Err(e) => {
+                    warn!(
+                        "Failed to get message counts for {}: {}",
+                        self.queue.name, e
+                    );
+                    continue;
+                }
+            };
+
+            for (attribute, state) in [
+                (QueueAttributeName::ApproximateNumberOfMessages, "visible"),
+                (
+                    QueueAttributeName::ApproximateNumberOfMessagesNotVisible,
+                    "not_visible",
+                ),
+                (
+                    QueueAttributeName::ApproximateNumberOfMessagesDelayed,
+                    "delayed",
+                ),
+            ] {
+                if let Some(count) = attributes.get(&attribute).and_then(|v| v.parse().ok()) {
+                    self.metrics
+                        .queue_messages
+                        .with_label_values(&[&self.queue.name, state])
+                        .set(count);
+                }
+            }
+        }
+    }
+
+    /// Time an SQS API call and count it if it fails
+    async fn call<T, E>(
+        &self,
+        operation: &str,
+        request: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let timer = self
+            .metrics
+            .sqs_request_duration
+            .with_label_values(&[&self.queue.name, operation])
+            .start_timer();
+        let result = request.await;
+        timer.observe_duration();
+
+        if result.is_err() {
+            self.metrics
+                .sqs_request_errors
+                .with_label_values(&[&self.queue.name, operation])
+                .inc();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+impl SqsConsumer {
+    /// A consumer for the queue at `url` whose SQS calls fail straight away
+    pub fn for_tests(url: &str) -> Arc<Self> {
+        use aws_sdk_sqs::config::{retry::RetryConfig, Credentials, Region};
+
+        let config = aws_sdk_sqs::Config::builder()
+            .behavior_version_latest()
+            .region(Region::new("eu-west-2"))
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .endpoint_url("http://127.0.0.1:1")
+            .retry_config(RetryConfig::disabled())
+            .build();
+        let queue = QueueConfig {
+            name: "webhooks".to_string(),
+            url: url.to_string(),
+            priority: 0,
+            weight: 1,
+            concurrency: 1,
+            poll_interval_ms: 1000,
+            max_messages: 10,
+            visibility_timeout_seconds: 60,
+            wait_time_seconds: 20,
+            default_route: None,
+            dead_letter_url: None,
+            reply_url: None,
+        };
+        Arc::new(SqsConsumer::new(
+            Client::from_conf(config),
+            queue,
+            Default::default(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multi_value_headers() {
+        let message: WebhookMessage = serde_json::from_value(serde_json::json!({
+            "path": "/webhook/n8n/test",
+            "method": "POST",
+            "headers": {"content-type": "text/plain", "set-cookie": "b=2", "x-one": "1"},
+            "multiValueHeaders": {"Set-Cookie": ["a=1", "b=2"], "via": ["1.1 proxy"]},
+            "queryStringParameters": {"tag": "b"},
+            "multiValueQueryStringParameters": {"tag": ["a", "b"], "page": ["2"]},
+            "timestamp": "2025-01-15T10:30:00Z"
+        }))
+        .unwrap();
+
+        assert_eq!(
+            message.header_values(),
+            vec![
+                ("content-type", "text/plain"),
+                ("Set-Cookie", "a=1"),
+                ("Set-Cookie", "b=2"),
+                ("x-one", "1"),
+                ("via", "1.1 proxy"),
+            ]
+        );
+        assert_eq!(
+            message.query_values(),
+            vec![("tag", "a"), ("tag", "b"), ("page", "2")]
+        );
+    }
+
+    #[test]
+    fn test_serialized_fields() {
+        let message: WebhookMessage = serde_json::from_value(serde_json::json!({
+            "path": "/webhook/n8n/test",
+            "method": "POST",
+            "headers": {"content-type": "text/plain"},
+            "body": "aGk=",
+            "is_base64_encoded": true,
+            "query_string_parameters": {"a": "1"},
+            "timestamp": "2025-01-15T10:30:00Z",
+            "source_ip": "192.0.2.1",
+            "correlation_id": "abc"
+        }))
+        .unwrap();
+
+        // Written out camelCase, whichever names the message came with
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            serde_json::json!({
+                "path": "/webhook/n8n/test",
+                "method": "POST",
+                "headers": {"content-type": "text/plain"},
+                "body": "aGk=",
+                "isBase64Encoded": true,
+                "queryStringParameters": {"a": "1"},
+                "timestamp": "2025-01-15T10:30:00Z",
+                "sourceIp": "192.0.2.1",
+                "correlationId": "abc"
+            })
+        );
+    }
+
+    #[test]
+    fn test_body_len() {
+        let mut message = WebhookMessage {
+            body: "{\"a\": 1}".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(message.body_len(), 8);
+
+        for body in ["", "a", "ab", "abc", "abcd"] {
+            message.body = BASE64.encode(body);
+            message.is_base64_encoded = true;
+            assert_eq!(message.body_len(), body.len());
+        }
+    }
 }
@@ -0,0 +1,290 @@
+use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, CONTROLS};
+use serde::Deserialize;
+use url::Url;
+
+use crate::validation::MessageError;
+
+/// Bytes encoded in a path segment: everything that isn't allowed there
+/// literally, plus `%`, `/` and `\` so that a segment stays one segment
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// What to do with `.`, `..` and encoded slashes in the forwarded path
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraversalPolicy {
+    /// Refuse to forward the webhook
+    #[default]
+    Reject,
+    /// Resolve `.` and `..` without ever leaving the target's base path, and
+    /// keep encoded slashes inside their segment
+    Normalize,
+}
+
+/// How a route combines its base URL with the incoming request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UrlRules {
+    pub append_path: bool,
+    pub append_query: bool,
+    pub traversal: TraversalPolicy,
+}
+
+impl Default for UrlRules {
+    fn default() -> Self {
+        UrlRules {
+            append_path: true,
+            append_query: true,
+            traversal: TraversalPolicy::default(),
+        }
+    }
+}
+
+/// Build the URL a webhook is forwarded to.
+///
+/// Each segment of `rest_path` is percent-decoded and re-encoded as a single
+/// segment below the base path, so nothing in it can change the host or
+/// climb above the base. Query parameters are form-encoded and appended to
+/// any query the base URL already has.
+pub fn build(
+    base: &Url,
+    rest_path: &str,
+    query: &[(&str, &str)],
+    rules: UrlRules,
+) -> Result<Url, MessageError> {
+    let mut url = base.clone();
+
+    if rules.append_path {
+        if base.cannot_be_a_base() {
+            return Err(MessageError::Malformed(format!(
+                "{} cannot be a base URL",
+                base
+            )));
+        }
+
+        let mut path = base.path().trim_end_matches('/').to_string();
+        for segment in path_segments(rest_path, rules.traversal)? {
+            path.push('/');
+            path.extend(percent_encode(&segment, SEGMENT));
+        }
+        url.set_path(&path);
+    }
+
+    if rules.append_query && !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query);
+    }
+
+    Ok(url)
+}
+
+/// Decoded path segments, with empty segments dropped other than a trailing
+/// one (which keeps a trailing slash)
+fn path_segments(
+    rest_path: &str,
+    traversal: TraversalPolicy,
+) -> Result<Vec<Vec<u8>>, MessageError> {
+    let raw: Vec<&str> = rest_path
+        .strip_prefix('/')
+        .unwrap_or(rest_path)
+        .split('/')
+        .collect();
+    let last = raw.len() - 1;
+
+    let mut segments: Vec<Vec<u8>> = Vec::new();
+    let mut trailing_slash = false;
+    for (i, raw_segment) in raw.iter().enumerate() {
+        let segment: Vec<u8> = percent_decode_str(raw_segment).collect();
+
+        let dots = matches!(segment.as_slice(), b"." | b"..");
+        let traverses = dots || segment.contains(&b'/') || segment.contains(&b'\\');
+
+        if traverses && traversal == TraversalPolicy::Reject {
+            return Err(MessageError::PathTraversal(rest_path.to_string()));
+        }
+
+        // A trailing `.` or `..` leaves a directory, which keeps its slash
+        trailing_slash = i == last && (dots || segment.is_empty());
+
+        match segment.as_slice() {
+            b"." | b"" => {}
+            b".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    if trailing_slash {
+        segments.push(Vec::new());
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn base() -> Url {
+        Url::parse("https://n8n.example.com/webhook?token=abc").unwrap()
+    }
+
+    fn normalize() -> UrlRules {
+        UrlRules {
+            traversal: TraversalPolicy::Normalize,
+            ..UrlRules::default()
+        }
+    }
+
+    fn built(rest_path: &str, query: &[(&str, &str)], rules: UrlRules) -> String {
+        build(&base(), rest_path, query, rules).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_build() {
+        let rules = UrlRules::default();
+
+        assert_eq!(
+            built("/my-workflow", &[], rules),
+            "https://n8n.example.com/webhook/my-workflow?token=abc"
+        );
+        assert_eq!(
+            built("/", &[], rules),
+            "https://n8n.example.com/webhook/?token=abc"
+        );
+        assert_eq!(
+            built("/a b/ü/", &[("q", "x&y=z"), ("q", "#1")], rules),
+            "https://n8n.example.com/webhook/a%20b/%C3%BC/?token=abc&q=x%26y%3Dz&q=%231"
+        );
+
+        let fixed = UrlRules {
+            append_path: false,
+            append_query: false,
+            ..rules
+        };
+        assert_eq!(
+            built("/ignored", &[("q", "1")], fixed),
+            "https://n8n.example.com/webhook?token=abc"
+        );
+    }
+
+    #[test]
+    fn test_traversal() {
+        for path in [
+            "/../admin",
+            "/a/./b",
+            "/a/%2e%2E/b",
+            "/a%2Fb",
+            "/a%5c..%5cb",
+        ] {
+            assert!(matches!(
+                build(&base(), path, &[], UrlRules::default()),
+                Err(MessageError::PathTraversal(_))
+            ));
+        }
+
+        assert_eq!(
+            built("/../../admin", &[], normalize()),
+            "https://n8n.example.com/webhook/admin?token=abc"
+        );
+        assert_eq!(
+            built("/a/./b/../c", &[], normalize()),
+            "https://n8n.example.com/webhook/a/c?token=abc"
+        );
+        assert_eq!(
+            built("/a/..", &[], normalize()),
+            "https://n8n.example.com/webhook/?token=abc"
+        );
+        assert_eq!(
+            built("/a%2Fb", &[], normalize()),
+            "https://n8n.example.com/webhook/a%2Fb?token=abc"
+        );
+    }
+
+    fn segment() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just(".".to_string()),
+            Just("..".to_string()),
+            Just("%2e%2e".to_string()),
+            Just("%2F".to_string()),
+            Just(String::new()),
+            any::<String>(),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_stays_below_base(
+            segments in prop::collection::vec(segment(), 0..8),
+            policy in prop_oneof![Just(TraversalPolicy::Reject), Just(TraversalPolicy::Normalize)],
+        ) {
+            let rest_path = format!("/{}", segments.join("/"));
+            let rules = UrlRules { traversal: policy, ..UrlRules::default() };
+
+            match build(&base(), &rest_path, &[], rules) {
+                Ok(url) => {
+                    prop_assert_eq!(url.host_str(), Some("n8n.example.com"));
+                    prop_assert_eq!(url.query(), Some("token=abc"));
+
+                    let mut path = url.path_segments().unwrap();
+                    prop_assert_eq!(path.next(), Some("webhook"));
+                    for segment in path {
+                        let decoded: Vec<u8> = percent_decode_str(segment).collect();
+                        prop_assert!(decoded != b"." && decoded != b"..");
+                    }
+                }
+                Err(e) => {
+                    prop_assert_eq!(policy, TraversalPolicy::Reject);
+                    prop_assert!(matches!(e, MessageError::PathTraversal(_)));
+                }
+            }
+        }
+
+        #[test]
+        fn prop_plain_segments_round_trip(
+            segments in prop::collection::vec("[^/\\\\%]+", 1..6)
+                .prop_filter("no dot segments", |s| s.iter().all(|s| s != "." && s != "..")),
+        ) {
+            let rest_path = format!("/{}", segments.join("/"));
+            let url = build(&base(), &rest_path, &[], UrlRules::default()).unwrap();
+
+            let decoded: Vec<String> = url
+                .path_segments()
+                .unwrap()
+                .skip(1)
+                .map(|s| percent_decode_str(s).decode_utf8().unwrap().into_owned())
+                .collect();
+            prop_assert_eq!(decoded, segments);
+        }
+
+        #[test]
+        fn prop_query_round_trip(
+            pairs in prop::collection::vec((any::<String>(), any::<String>()), 0..6),
+        ) {
+            let query: Vec<(&str, &str)> =
+                pairs.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+            let url = build(&base(), "/", &query, UrlRules::default()).unwrap();
+
+            let mut expected = vec![("token".to_string(), "abc".to_string())];
+            expected.extend(pairs.iter().cloned());
+
+            let actual: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+            prop_assert_eq!(actual, expected);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{Context as _, Result};
+use aws_sdk_sqs::types::Message;
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::{SpanContext, TraceContextExt, TraceId, TracerProvider as _};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::collections::HashMap;
+use std::env;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+use crate::sqs::WebhookMessage;
+
+/// SQS message attributes and webhook headers a trace is continued from
+pub const TRACE_FIELDS: [&str; 2] = ["traceparent", "tracestate"];
+
+/// Start logging, and export spans over OTLP when an endpoint is set with
+/// OTEL_EXPORTER_OTLP_ENDPOINT or OTEL_EXPORTER_OTLP_TRACES_ENDPOINT. The
+/// returned provider flushes the last spans when shut down.
+pub fn init() -> Result<Option<TracerProvider>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let provider = if otlp_enabled() {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .build()
+            .context("Failed to create OTLP span exporter")?;
+        let service_name =
+            env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "webhook-relay".to_string());
+        Some(
+            TracerProvider::builder()
+                .with_batch_exporter(exporter, runtime::Tokio)
+                .with_resource(Resource::new_with_defaults([KeyValue::new(
+                    "service.name",
+                    service_name,
+                )]))
+                .build(),
+        )
+    } else {
+        None
+    };
+
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_target(true))
+        .with(
+            provider
+                .as_ref()
+                .map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer("webhook-relay"))),
+        )
+        .with(filter)
+        .init();
+
+    Ok(provider)
+}
+
+fn otlp_enabled() -> bool {
+    [
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+    ]
+    .iter()
+    .any(|name| env::var(name).is_ok_and(|v| !v.is_empty()))
+}
+
+/// The trace context carried in a received message's attributes, if any
+pub fn message_context(message: &Message) -> Option<Context> {
+    let attributes: HashMap<String, String> = message
+        .message_attributes()?
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_lowercase(), value.string_value()?.to_string())))
+        .collect();
+    valid(TraceContextPropagator::new().extract(&attributes))
+}
+
+/// Make the current span part of the trace the webhook was sent in: the one
+/// in its SQS message attributes if there was one, else the one in its
+/// headers. Records the trace ID on the span so log lines can be matched
+/// to the trace.
+pub fn continue_trace(parent: Option<Context>, webhook: &WebhookMessage) {
+    let span = Span::current();
+    let parent = parent.or_else(|| valid(TraceContextPropagator::new().extract(webhook)));
+    if let Some(parent) = parent {
+        span.set_parent(parent);
+    }
+
+    let trace_id = span.context().span().span_context().trace_id();
+    if trace_id != TraceId::INVALID {
+        span.record("trace_id", trace_id.to_string());
+    }
+}
+
+/// Add the current span's traceparent and tracestate headers, replacing any
+/// the webhook arrived with
+pub fn inject(headers: &mut HeaderMap) {
+    let context = Span::current().context();
+    if context.span().span_context().is_valid() {
+        TraceContextPropagator::new().inject_context(&context, &mut HeaderInjector(headers));
+    }
+}
+
+/// The current span's context, kept to link the spans of work done for a
+/// message after its own span has ended
+pub fn span_context() -> SpanContext {
+    Span::current().context().span().span_context().clone()
+}
+
+/// Link a span that acts on several messages, such as a batch delivery or
+/// delete, to each of their spans
+pub fn link(span: &Span, contexts: impl IntoIterator<Item = SpanContext>) {
+    for context in contexts {
+        span.add_link(context);
+    }
+}
+
+fn valid(context: Context) -> Option<Context> {
+    context.span().span_context().is_valid().then_some(context)
+}
+
+impl Extractor for WebhookMessage {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.header_values()
+            .into_iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.header_values()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(key), HeaderValue::try_from(value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_sqs::types::MessageAttributeValue;
+    use tracing::info_span;
+
+    const PARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_trace_propagation() {
+        let provider = TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        let webhook: WebhookMessage = serde_json::from_value(serde_json::json!({
+            "path": "/webhook/n8n/flow",
+            "method": "POST",
+            "headers": {"Traceparent": PARENT, "Content-Type": "application/json"},
+            "timestamp": "2025-01-15T10:30:00Z",
+        }))
+        .unwrap();
+        let attribute = |value: &str| {
+            MessageAttributeValue::builder()
+                .data_type("String")
+                .string_value(value)
+                .build()
+                .unwrap()
+        };
+        let message = Message::builder()
+            .message_attributes(
+                "traceparent",
+                attribute("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+            )
+            .build();
+
+        tracing::subscriber::with_default(subscriber, || {
+            // Continued from the webhook's headers, with the trace ID on the span
+            let span = info_span!("message", trace_id = tracing::field::Empty);
+            let entered = span.enter();
+            continue_trace(None, &webhook);
+            let mut headers = HeaderMap::new();
+            headers.insert("traceparent", HeaderValue::from_static(PARENT));
+            inject(&mut headers);
+
+            let traceparent = headers["traceparent"].to_str().unwrap();
+            assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+            assert_ne!(traceparent, PARENT);
+            drop(entered);
+
+            // SQS message attributes take precedence over the headers
+            let span = info_span!("message", trace_id = tracing::field::Empty);
+            let _entered = span.enter();
+            continue_trace(message_context(&message), &webhook);
+            let mut headers = HeaderMap::new();
+            inject(&mut headers);
+            assert!(headers["traceparent"]
+                .to_str()
+                .unwrap()
+                .starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+        });
+
+        assert!(message_context(&Message::builder().build()).is_none());
+    }
+}
@@ -0,0 +1,249 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::DateTime;
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::Method;
+use thiserror::Error;
+
+use crate::sqs::WebhookMessage;
+
+/// Message format versions this relay understands. Messages without a
+/// `version` field predate versioning and are treated as version 1.
+pub const SUPPORTED_VERSIONS: &[u32] = &[1];
+
+const METHODS: &[Method] = &[
+    Method::GET,
+    Method::HEAD,
+    Method::POST,
+    Method::PUT,
+    Method::DELETE,
+    Method::OPTIONS,
+    Method::PATCH,
+];
+
+/// Methods that are never relayed: TRACE echoes the request, headers and
+/// all, back to whoever reads the response, and CONNECT asks the target to
+/// open a tunnel
+const REJECTED_METHODS: &[Method] = &[Method::TRACE, Method::CONNECT];
+
+/// Why a message can never be delivered. Redelivering these won't help, so
+/// they are sent to the dead-letter queue rather than retried.
+#[derive(Debug, Error)]
+pub enum MessageError {
+    #[error("message body is not valid JSON: {0}")]
+    NotJson(#[source] serde_json::Error),
+
+    #[error("malformed message: {0}")]
+    Malformed(String),
+
+    #[error("unsupported message version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("invalid path {0:?}: must start with /")]
+    InvalidPath(String),
+
+    #[error("invalid timestamp {value:?}: {source}")]
+    InvalidTimestamp {
+        value: String,
+        #[source]
+        source: chrono::ParseError,
+    },
+
+    #[error("invalid HTTP method {0:?}")]
+    InvalidMethod(String),
+
+    #[error("invalid header name {0:?}")]
+    InvalidHeaderName(String),
+
+    #[error("invalid value for header {0:?}")]
+    InvalidHeaderValue(String),
+
+    #[error("body is not valid base64: {0}")]
+    InvalidBase64(#[source] base64::DecodeError),
+
+    #[error("body could not be decompressed as {encoding}: {source}")]
+    InvalidCompression {
+        encoding: &'static str,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("body is larger than {0} bytes")]
+    BodyTooLarge(usize),
+
+    #[error("checksum mismatch: expected {expected}, got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+
+    #[error("SNS signature verification failed: {0}")]
+    InvalidSignature(String),
+
+    #[error("path {0:?} escapes the target's base path")]
+    PathTraversal(String),
+
+    #[error("method {method} is not allowed for route {route}")]
+    MethodNotAllowed { method: Method, route: String },
+}
+
+impl MessageError {
+    /// Short machine-readable reason, used as a metric label and as the
+    /// dead-letter reason attribute
+    pub fn reason(&self) -> &'static str {
+        match self {
+            MessageError::NotJson(_) => "not_json",
+            MessageError::Malformed(_) => "malformed",
+            MessageError::UnsupportedVersion(_) => "unsupported_version",
+            MessageError::InvalidPath(_) => "invalid_path",
+            MessageError::InvalidTimestamp { .. } => "invalid_timestamp",
+            MessageError::InvalidMethod(_) => "invalid_method",
+            MessageError::InvalidHeaderName(_) => "invalid_header_name",
+            MessageError::InvalidHeaderValue(_) => "invalid_header_value",
+            MessageError::InvalidBase64(_) => "invalid_base64",
+            MessageError::InvalidCompression { .. } => "invalid_compression",
+            MessageError::BodyTooLarge(_) => "body_too_large",
+            MessageError::ChecksumMismatch { .. } => "checksum_mismatch",
+            MessageError::InvalidSignature(_) => "invalid_signature",
+            MessageError::PathTraversal(_) => "path_traversal",
+            MessageError::MethodNotAllowed { .. } => "method_not_allowed",
+        }
+    }
+}
+
+/// Parse an HTTP method. Standard methods are matched case-insensitively;
+/// anything else must be a valid HTTP token and is kept as given. TRACE and
+/// CONNECT are rejected.
+pub fn parse_method(method: &str) -> Result<Method, MessageError> {
+    if REJECTED_METHODS
+        .iter()
+        .any(|m| m.as_str().eq_ignore_ascii_case(method))
+    {
+        return Err(MessageError::InvalidMethod(method.to_string()));
+    }
+
+    if let Some(standard) = METHODS
+        .iter()
+        .find(|m| m.as_str().eq_ignore_ascii_case(method))
+    {
+        return Ok(standard.clone());
+    }
+
+    Method::from_bytes(method.as_bytes())
+        .map_err(|_| MessageError::InvalidMethod(method.to_string()))
+}
+
+/// Check a decoded message against the format before anything is delivered
+pub fn validate(message: &WebhookMessage) -> Result<(), MessageError> {
+    if let Some(version) = message.version {
+        if !SUPPORTED_VERSIONS.contains(&version) {
+            return Err(MessageError::UnsupportedVersion(version));
+        }
+    }
+
+    if !message.path.starts_with('/') {
+        return Err(MessageError::InvalidPath(message.path.clone()));
+    }
+
+    DateTime::parse_from_rfc3339(&message.timestamp).map_err(|source| {
+        MessageError::InvalidTimestamp {
+            value: message.timestamp.clone(),
+            source,
+        }
+    })?;
+
+    parse_method(&message.method)?;
+
+    for (name, value) in message.header_values() {
+        if HeaderName::try_from(name).is_err() {
+            return Err(MessageError::InvalidHeaderName(name.to_string()));
+        }
+        if HeaderValue::try_from(value).is_err() {
+            return Err(MessageError::InvalidHeaderValue(name.to_string()));
+        }
+    }
+
+    if message.is_base64_encoded {
+        BASE64
+            .decode(&message.body)
+            .map_err(MessageError::InvalidBase64)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(overrides: serde_json::Value) -> WebhookMessage {
+        let mut value = serde_json::json!({
+            "version": 1,
+            "path": "/webhook/gitea/push",
+            "method": "POST",
+            "headers": {"content-type": "application/json"},
+            "body": "{}",
+            "timestamp": "2025-01-15T10:30:00.123456Z",
+        });
+        for (key, field) in overrides.as_object().unwrap() {
+            value[key] = field.clone();
+        }
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn reason(overrides: serde_json::Value) -> &'static str {
+        validate(&message(overrides)).unwrap_err().reason()
+    }
+
+    #[test]
+    fn test_valid_message() {
+        validate(&message(serde_json::json!({}))).unwrap();
+        validate(&message(serde_json::json!({"method": "patch"}))).unwrap();
+        validate(&message(serde_json::json!({"method": "PROPFIND"}))).unwrap();
+
+        // Messages from before versioning carry no version
+        let mut unversioned = message(serde_json::json!({}));
+        unversioned.version = None;
+        validate(&unversioned).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_message() {
+        assert_eq!(
+            reason(serde_json::json!({"version": 2})),
+            "unsupported_version"
+        );
+        assert_eq!(
+            reason(serde_json::json!({"path": "webhook/gitea"})),
+            "invalid_path"
+        );
+        assert_eq!(
+            reason(serde_json::json!({"timestamp": "15/01/2025 10:30"})),
+            "invalid_timestamp"
+        );
+        assert_eq!(
+            reason(serde_json::json!({"method": "GET /"})),
+            "invalid_method"
+        );
+        assert_eq!(
+            reason(serde_json::json!({"method": "TRACE"})),
+            "invalid_method"
+        );
+        assert_eq!(
+            reason(serde_json::json!({"method": "connect"})),
+            "invalid_method"
+        );
+        assert_eq!(
+            reason(serde_json::json!({"headers": {"bad header": "x"}})),
+            "invalid_header_name"
+        );
+        assert_eq!(
+            reason(serde_json::json!({"headers": {"x-test": "line\nbreak"}})),
+            "invalid_header_value"
+        );
+        assert_eq!(
+            reason(serde_json::json!({"multiValueHeaders": {"via": ["1.1 a", "bad\r\n"]}})),
+            "invalid_header_value"
+        );
+        assert_eq!(
+            reason(serde_json::json!({"body": "not base64!", "isBase64Encoded": true})),
+            "invalid_base64"
+        );
+    }
+}